@@ -0,0 +1,37 @@
+#!/bin/bash
+# Runs an N-node cluster on localhost without Kubernetes.
+# Node i serves clients on 8090 + 2i and peers on 8091 + 2i.
+#
+#   ./dev_cluster.sh [nodes] [extra whitewater flags...]
+
+set -e
+
+NODES=${1:-3}
+shift || true
+
+cargo build
+BIN=target/debug/whitewater
+
+trap 'kill $(jobs -p) 2>/dev/null' EXIT INT TERM
+
+for ((i = 0; i < NODES; i++)); do
+  peers=()
+  for ((j = 0; j < NODES; j++)); do
+    if [ "$j" -ne "$i" ]; then
+      peers+=("127.0.0.1:$((8091 + 2 * j))")
+    fi
+  done
+  peer_list=$(IFS=,; echo "${peers[*]}")
+
+  "$BIN" \
+    --node-name "whitewater-$i" \
+    --node-ip 127.0.0.1 \
+    --client-addr "127.0.0.1:$((8090 + 2 * i))" \
+    --peer-addr "127.0.0.1:$((8091 + 2 * i))" \
+    --peers "$peer_list" \
+    --discovery-delay-ms 1000 \
+    --cluster-size "$NODES" \
+    "$@" 2>&1 | sed -u "s/^/[whitewater-$i] /" &
+done
+
+wait
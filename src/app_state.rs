@@ -1,59 +1,929 @@
+pub mod admission;
+mod cluster;
+pub mod coordination;
+pub mod dedup;
 pub mod log;
-mod raft_state;
+pub mod raft_state;
 pub mod shared;
 pub mod state_machine;
+pub mod watch;
 
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{Duration, Instant};
 
-use axum::{extract::Json, http::StatusCode};
-use raft_state::RaftState;
-use shared::{Peer, StatusInfo};
+use super::config::{Config, HealthConfig};
+use super::handler::Handler;
+use super::health::Loops;
+use super::leases::LeaseKeeper;
+use super::membership::{Member, Membership};
+use super::status::StatusRequests;
+use admission::Admission;
+use axum::{
+    extract::Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use cluster::Cluster;
+use coordination::{AcquireRequest, ELECTION_PREFIX, Holder, LOCK_PREFIX, ReleaseRequest};
+use dedup::Dedup;
+use log::{Command, ToCommand};
+use raft_state::{ProposeError, RaftState};
+use shared::{NodeId, StatusInfo};
 use state_machine::{
-    StateMachine,
-    user::{CreateUserRequest, User},
+    kv::{DeleteKvQuery, KvCommand, KvResponse, PutKvRequest},
+    lease::{GrantLeaseRequest, Lease, LeaseCommand, LeaseResponse},
+    session::{RegisterSessionRequest, SessionCommand, SessionResponse},
+    store::{Store, StoreCommand, StoreResponse},
+    txn::{Txn, TxnResponse},
+    user::{
+        CreateUserRequest, ListUsersQuery, PatchUserRequest, UpdateUserRequest, UserCommand,
+        UserResponse,
+    },
 };
+use watch::WatchQuery;
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+pub fn error_response(status: StatusCode, error: impl Into<String>) -> Response {
+    (
+        status,
+        Json(ErrorBody {
+            error: error.into(),
+        }),
+    )
+        .into_response()
+}
+
+/// How long a client turned away by admission control is asked to wait before retrying.
+const RETRY_AFTER_SECS: u32 = 1;
+
+/// 504 if the entry might yet be applied, since the wait was what ran out; 429 if this node has
+/// too many requests in flight; 503 otherwise, as retrying against the current leader should
+/// work. Requests refused for load carry a `Retry-After`.
+fn proposal_failed(e: ProposeError) -> Response {
+    let status = match e {
+        ProposeError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ProposeError::TooManyInFlight => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let overloaded = matches!(e, ProposeError::TooManyInFlight | ProposeError::Backlogged);
+    let mut response = error_response(status, e.to_string());
+    if overloaded {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    }
+    response
+}
+
+/// 500 for a command answered with a response of the wrong kind, which is a bug. Logs the
+/// response rather than sending it to the client.
+fn unexpected_response(what: &str, response: &impl std::fmt::Debug) -> Response {
+    eprintln!("{what} answered with {response:?}");
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "unexpected response to command",
+    )
+}
+
+/// Why a client's command wasn't applied.
+#[derive(Debug)]
+pub enum RequestError {
+    Propose(ProposeError),
+    /// The command named a client session that has ended or never existed.
+    SessionNotFound,
+    /// The command's sequence number was acknowledged, so its response is gone.
+    SessionStale {
+        acked: u64,
+    },
+    /// The command's sequence number was already used for a different command.
+    SessionSequenceReused {
+        sequence: u64,
+    },
+    /// The `Idempotency-Key` was already used for a different request.
+    IdempotencyKeyReused,
+    /// The command was answered with a response of the wrong kind.
+    Unexpected(Box<StoreResponse>),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::Propose(e) => write!(f, "{e}"),
+            RequestError::SessionNotFound => write!(f, "no such session; it may have expired"),
+            RequestError::SessionStale { acked } => {
+                write!(f, "sequence number already acknowledged (up to {acked})")
+            }
+            RequestError::SessionSequenceReused { sequence } => {
+                write!(
+                    f,
+                    "sequence number {sequence} was already used for a different request"
+                )
+            }
+            RequestError::IdempotencyKeyReused => {
+                write!(
+                    f,
+                    "Idempotency-Key was already used for a different request"
+                )
+            }
+            RequestError::Unexpected(response) => write!(f, "unexpected response {response:?}"),
+        }
+    }
+}
+
+impl From<ProposeError> for RequestError {
+    fn from(e: ProposeError) -> Self {
+        RequestError::Propose(e)
+    }
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        match self {
+            RequestError::Propose(e) => proposal_failed(e),
+            RequestError::SessionNotFound => error_response(StatusCode::GONE, self.to_string()),
+            RequestError::SessionStale { .. } => {
+                error_response(StatusCode::CONFLICT, self.to_string())
+            }
+            RequestError::SessionSequenceReused { .. } | RequestError::IdempotencyKeyReused => {
+                error_response(StatusCode::UNPROCESSABLE_ENTITY, self.to_string())
+            }
+            RequestError::Unexpected(response) => unexpected_response("command", &response),
+        }
+    }
+}
+
+/// How up to date a read has to be, shared by every read endpoint.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadConsistency {
+    /// Served from this node's state machine, which may lag the leader.
+    #[default]
+    Local,
+    /// Reflects every write that completed before the read began. Only the leader can serve it.
+    Linearizable,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ReadQuery {
+    #[serde(default)]
+    pub consistency: ReadConsistency,
+}
+
+/// A lease as `GET /leases/{id}` reports it. Only the leader knows how long it has left.
+#[derive(Serialize)]
+pub struct LeaseStatus {
+    #[serde(flatten)]
+    pub lease: Lease,
+    pub remaining_ms: Option<u64>,
+}
 
 #[derive(Clone)]
 pub struct AppState {
-    pub raft_state: Arc<Mutex<RaftState>>,
-    pub state_machine: Arc<Mutex<StateMachine>>,
+    pub raft_state: Arc<Mutex<RaftState<Store>>>,
+    pub membership: Arc<Mutex<Membership>>,
+    pub leases: Arc<Mutex<LeaseKeeper>>,
+    /// Wakes the replication loop so new entries don't wait for the next heartbeat.
+    pub replicate_now: Arc<Notify>,
+    pub admission: Arc<Admission>,
+    pub loops: Arc<Loops>,
+    pub handler: Handler,
+    pub health: HealthConfig,
+    pub status_requests: Arc<StatusRequests>,
+    /// Set once the node starts shutting down.
+    stopping: Arc<tokio::sync::watch::Sender<bool>>,
+    lease_min_ttl_ms: u64,
+    idempotency_window_ms: u64,
+    proposal_timeout: Duration,
 }
 
 impl AppState {
-    pub fn new(status_info: StatusInfo) -> Self {
+    pub fn new(
+        status_info: StatusInfo,
+        membership: Membership,
+        handler: Handler,
+        config: &Config,
+    ) -> Self {
+        let cluster = Cluster::new(status_info, config.raft.initial_voters());
         AppState {
-            raft_state: Arc::new(Mutex::new(RaftState::new())),
-            state_machine: Arc::new(Mutex::new(StateMachine::new(status_info.clone()))),
+            raft_state: Arc::new(Mutex::new(RaftState::new(
+                cluster,
+                Store::new(),
+                config.raft.clone(),
+                config.channels.watch,
+            ))),
+            membership: Arc::new(Mutex::new(membership)),
+            leases: Arc::new(Mutex::new(LeaseKeeper::new())),
+            replicate_now: Arc::new(Notify::new()),
+            admission: Arc::new(Admission::new(config.raft.max_in_flight_proposals)),
+            loops: Arc::new(Loops::default()),
+            handler,
+            health: config.health.clone(),
+            status_requests: Arc::new(StatusRequests::default()),
+            stopping: Arc::new(tokio::sync::watch::Sender::new(false)),
+            lease_min_ttl_ms: config.leases.min_ttl_ms,
+            idempotency_window_ms: config.idempotency.window_ms,
+            proposal_timeout: config.raft.proposal_timeout(),
         }
     }
 
-    async fn modify_state_machine<A>(&self, func: impl FnOnce(&mut StateMachine) -> A) -> A {
-        let mut state_machine = self.state_machine.lock().await;
-        func(&mut state_machine)
+    /// Marks the node as shutting down, which ends every client event stream.
+    pub fn stop_serving(&self) {
+        self.stopping.send_replace(true);
     }
 
-    pub async fn add_peer(&self, peer: Peer) {
-        self.modify_state_machine(|state| state.add_peer(peer))
-            .await;
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
     }
 
-    pub async fn create_user(&self, req: CreateUserRequest) -> (StatusCode, Json<Option<User>>) {
-        let user = self
-            .modify_state_machine(|state| state.create_user(req))
-            .await;
-        (StatusCode::CREATED, Json(Some(user.clone())))
+    /// Resolves once the node starts shutting down.
+    fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopping = self.stopping.subscribe();
+        async move {
+            let _ = stopping.wait_for(|stopping| *stopping).await;
+        }
+    }
+
+    /// Records where a node can currently be reached. This is local knowledge, not replicated.
+    pub async fn update_address(&self, id: NodeId, addr: String) {
+        let changed = self
+            .raft_state
+            .lock()
+            .await
+            .cluster
+            .addresses
+            .insert(id, addr.clone());
+        if changed {
+            println!("Node {id} is at {addr}");
+        }
+    }
+
+    /// Replicates a command and waits, up to the proposal timeout, for the state machine's
+    /// response to it. Fails straight away if the node is already waiting on as many requests
+    /// as it admits, or the log has no room.
+    pub async fn propose(&self, command: StoreCommand) -> Result<StoreResponse, ProposeError> {
+        let _permit = self.admission.admit()?;
+        let proposal = self
+            .raft_state
+            .lock()
+            .await
+            .propose(command)
+            .inspect_err(|e| self.admission.note(e))?;
+        self.replicate_now.notify_one();
+        tokio::time::timeout(self.proposal_timeout, proposal)
+            .await
+            .map_err(|_| ProposeError::Timeout)?
+    }
+
+    /// Proposes a client's command, applying it at most once if the client gave a session or
+    /// an idempotency key. A retry gets the response the command first had.
+    async fn propose_deduped(
+        &self,
+        command: StoreCommand,
+        dedup: &Dedup,
+    ) -> Result<StoreResponse, RequestError> {
+        let command = dedup.wrap(command, self.idempotency_window_ms);
+        // Without a session of its own, a session response is the command's answer.
+        let tracked = dedup.has_session();
+        match self.propose(command).await? {
+            StoreResponse::Replayed(response) => Ok(*response),
+            StoreResponse::Session(SessionResponse::NotFound) if tracked => {
+                Err(RequestError::SessionNotFound)
+            }
+            StoreResponse::Session(SessionResponse::Stale { acked }) if tracked => {
+                Err(RequestError::SessionStale { acked })
+            }
+            StoreResponse::Session(SessionResponse::SequenceReused { sequence }) if tracked => {
+                Err(RequestError::SessionSequenceReused { sequence })
+            }
+            StoreResponse::IdempotencyKeyReused => Err(RequestError::IdempotencyKeyReused),
+            response => Ok(response),
+        }
     }
 
-    pub async fn get_user(&self, id: u32) -> (StatusCode, Json<Option<User>>) {
-        match self.state_machine.lock().await.get_user(id) {
-            Some(user) => (StatusCode::OK, Json(Some(user.clone()))),
-            None => (StatusCode::NOT_FOUND, Json(None::<User>)),
+    async fn propose_user(
+        &self,
+        command: UserCommand,
+        dedup: &Dedup,
+    ) -> Result<UserResponse, RequestError> {
+        match self
+            .propose_deduped(StoreCommand::User(command), dedup)
+            .await?
+        {
+            StoreResponse::User(response) => Ok(response),
+            other => Err(RequestError::Unexpected(Box::new(other))),
         }
     }
 
-    pub async fn list_users(&self) -> (StatusCode, Json<Option<Vec<User>>>) {
-        let users = self.state_machine.lock().await.list_users();
-        (StatusCode::OK, Json(Some(users)))
+    async fn propose_kv(
+        &self,
+        command: KvCommand,
+        dedup: &Dedup,
+    ) -> Result<KvResponse, RequestError> {
+        match self
+            .propose_deduped(StoreCommand::Kv(command), dedup)
+            .await?
+        {
+            StoreResponse::Kv(response) => Ok(response),
+            other => Err(RequestError::Unexpected(Box::new(other))),
+        }
+    }
+
+    async fn propose_lease(
+        &self,
+        command: LeaseCommand,
+        dedup: &Dedup,
+    ) -> Result<LeaseResponse, RequestError> {
+        match self
+            .propose_deduped(StoreCommand::Lease(command), dedup)
+            .await?
+        {
+            StoreResponse::Lease(response) => Ok(response),
+            other => Err(RequestError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn propose_membership_change(
+        &self,
+        command: Command<StoreCommand>,
+    ) -> Result<(), ProposeError> {
+        self.raft_state
+            .lock()
+            .await
+            .propose_membership_change(command)?;
+        self.replicate_now.notify_one();
+        Ok(())
+    }
+
+    /// Runs `read` against the state machine once it's as up to date as `consistency` asks.
+    async fn read<T>(
+        &self,
+        consistency: ReadConsistency,
+        read: impl FnOnce(&Store) -> T,
+    ) -> Result<T, ProposeError> {
+        if consistency == ReadConsistency::Linearizable {
+            let _permit = self.admission.admit()?;
+            let barrier = self
+                .raft_state
+                .lock()
+                .await
+                .read_barrier()
+                .inspect_err(|e| self.admission.note(e))?;
+            self.replicate_now.notify_one();
+            tokio::time::timeout(self.proposal_timeout, barrier)
+                .await
+                .map_err(|_| ProposeError::Timeout)?
+                .map_err(|_| ProposeError::LeadershipLost)?;
+        }
+        Ok(read(&self.raft_state.lock().await.state_machine))
+    }
+
+    /// Maps the outcome of applying a user command to an HTTP response. Since it comes from
+    /// the apply result, every replica would have answered the same way.
+    fn user_response(result: Result<UserResponse, RequestError>) -> Response {
+        match result {
+            Ok(UserResponse::Created(user)) => (StatusCode::CREATED, Json(user)).into_response(),
+            Ok(UserResponse::Updated(user)) | Ok(UserResponse::Deleted(user)) => {
+                (StatusCode::OK, Json(user)).into_response()
+            }
+            Ok(UserResponse::NotFound) => error_response(StatusCode::NOT_FOUND, "no such user"),
+            Ok(UserResponse::EmailTaken) => {
+                error_response(StatusCode::CONFLICT, "email is already in use")
+            }
+            Err(e) => {
+                eprintln!("Couldn't apply user command: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    pub async fn create_user(&self, req: CreateUserRequest, dedup: Dedup) -> Response {
+        if let Err(e) = req.validate() {
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+        Self::user_response(self.propose_user(req.to_command(), &dedup).await)
+    }
+
+    pub async fn update_user(&self, id: u32, req: UpdateUserRequest, dedup: Dedup) -> Response {
+        if let Err(e) = req.validate() {
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+        Self::user_response(self.propose_user(req.to_command(id), &dedup).await)
+    }
+
+    pub async fn patch_user(&self, id: u32, req: PatchUserRequest, dedup: Dedup) -> Response {
+        if let Err(e) = req.validate() {
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+        Self::user_response(self.propose_user(req.to_command(id), &dedup).await)
+    }
+
+    pub async fn delete_user(&self, id: u32, dedup: Dedup) -> Response {
+        Self::user_response(self.propose_user(UserCommand::Delete { id }, &dedup).await)
+    }
+
+    pub async fn get_user(&self, id: u32, consistency: ReadConsistency) -> Response {
+        match self
+            .read(consistency, |store| store.users.get_user(id))
+            .await
+        {
+            Ok(Some(user)) => (StatusCode::OK, Json(user)).into_response(),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, "no such user"),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    pub async fn get_user_by_email(&self, email: String, consistency: ReadConsistency) -> Response {
+        match self
+            .read(consistency, |store| store.users.get_user_by_email(&email))
+            .await
+        {
+            Ok(Some(user)) => (StatusCode::OK, Json(user)).into_response(),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, "no such user"),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    pub async fn list_users(
+        &self,
+        query: ListUsersQuery,
+        consistency: ReadConsistency,
+    ) -> Response {
+        match self
+            .read(consistency, |store| store.users.list_users(&query))
+            .await
+        {
+            Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    fn kv_response(result: Result<KvResponse, RequestError>) -> Response {
+        match result {
+            Ok(KvResponse::Put(entry)) | Ok(KvResponse::Deleted(entry)) => {
+                (StatusCode::OK, Json(entry)).into_response()
+            }
+            Ok(KvResponse::NotFound) => error_response(StatusCode::NOT_FOUND, "no such key"),
+            Ok(KvResponse::RevisionMismatch(actual)) => error_response(
+                StatusCode::CONFLICT,
+                format!("revision mismatch; the key is at revision {actual}"),
+            ),
+            Ok(KvResponse::LeaseNotFound) => error_response(StatusCode::NOT_FOUND, "no such lease"),
+            Err(e) => {
+                eprintln!("Couldn't apply kv command: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    pub async fn put_kv(&self, key: String, req: PutKvRequest, dedup: Dedup) -> Response {
+        Self::kv_response(self.propose_kv(req.to_command(key), &dedup).await)
+    }
+
+    pub async fn delete_kv(&self, key: String, query: DeleteKvQuery, dedup: Dedup) -> Response {
+        Self::kv_response(self.propose_kv(query.to_command(key), &dedup).await)
+    }
+
+    pub async fn get_kv(&self, key: String, consistency: ReadConsistency) -> Response {
+        match self.read(consistency, |store| store.kv.get(&key)).await {
+            Ok(Some(entry)) => (StatusCode::OK, Json(entry)).into_response(),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, "no such key"),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    /// Runs a transaction; an aborted one is a 409, reporting the op that failed.
+    pub async fn txn(&self, txn: Txn, dedup: Dedup) -> Response {
+        if let Err(e) = txn.validate() {
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+        match self.propose_deduped(StoreCommand::Txn(txn), &dedup).await {
+            Ok(StoreResponse::Txn(response @ TxnResponse::Committed { .. })) => {
+                (StatusCode::OK, Json(response)).into_response()
+            }
+            Ok(StoreResponse::Txn(response @ TxnResponse::Aborted { .. })) => {
+                (StatusCode::CONFLICT, Json(response)).into_response()
+            }
+            Ok(other) => unexpected_response("txn", &other),
+            Err(e) => {
+                eprintln!("Couldn't apply txn: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    pub async fn grant_lease(&self, req: GrantLeaseRequest, dedup: Dedup) -> Response {
+        if req.ttl_ms < self.lease_min_ttl_ms {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("ttl_ms must be at least {}", self.lease_min_ttl_ms),
+            );
+        }
+        match self
+            .propose_lease(LeaseCommand::Grant { ttl_ms: req.ttl_ms }, &dedup)
+            .await
+        {
+            Ok(LeaseResponse::Granted(lease)) => (StatusCode::CREATED, Json(lease)).into_response(),
+            Ok(other) => unexpected_response("grant", &other),
+            Err(e) => {
+                eprintln!("Couldn't grant lease: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    /// Ends a lease early, deleting its keys. Responds with the lease as it was.
+    pub async fn revoke_lease(&self, id: u32, dedup: Dedup) -> Response {
+        match self
+            .propose_lease(LeaseCommand::Revoke { id }, &dedup)
+            .await
+        {
+            Ok(LeaseResponse::Revoked { lease, .. }) => {
+                (StatusCode::OK, Json(lease)).into_response()
+            }
+            Ok(LeaseResponse::NotFound) => error_response(StatusCode::NOT_FOUND, "no such lease"),
+            Ok(other) => unexpected_response("revoke", &other),
+            Err(e) => {
+                eprintln!("Couldn't revoke lease {id}: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    /// Renews a lease for another full TTL. Deadlines are kept by the leader, so only it can.
+    pub async fn keepalive_lease(&self, id: u32) -> Response {
+        let raft_state = self.raft_state.lock().await;
+        if let Err(e) = raft_state.ensure_leader() {
+            return proposal_failed(e);
+        }
+        let Some(lease) = raft_state.state_machine.leases.get(id) else {
+            return error_response(StatusCode::NOT_FOUND, "no such lease");
+        };
+        self.leases.lock().await.keepalive(id, lease.ttl_ms);
+        let status = LeaseStatus {
+            remaining_ms: Some(lease.ttl_ms),
+            lease,
+        };
+        (StatusCode::OK, Json(status)).into_response()
+    }
+
+    pub async fn get_lease(&self, id: u32, consistency: ReadConsistency) -> Response {
+        match self.read(consistency, |store| store.leases.get(id)).await {
+            Ok(Some(lease)) => {
+                let remaining = self.leases.lock().await.remaining(id);
+                let status = LeaseStatus {
+                    remaining_ms: remaining.map(|d| d.as_millis() as u64),
+                    lease,
+                };
+                (StatusCode::OK, Json(status)).into_response()
+            }
+            Ok(None) => error_response(StatusCode::NOT_FOUND, "no such lease"),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    /// Starts a client session on a lease. Its id goes in the `Client-Id` header of the
+    /// requests that should be applied exactly once.
+    pub async fn register_session(&self, req: RegisterSessionRequest, dedup: Dedup) -> Response {
+        let command = SessionCommand::Register { lease: req.lease };
+        match self
+            .propose_deduped(StoreCommand::Session(command), &dedup)
+            .await
+        {
+            Ok(StoreResponse::Session(SessionResponse::Registered(session))) => (
+                StatusCode::CREATED,
+                Json(serde_json::json!({ "id": session.id, "lease": session.lease })),
+            )
+                .into_response(),
+            Ok(StoreResponse::Session(SessionResponse::LeaseNotFound)) => {
+                error_response(StatusCode::NOT_FOUND, "no such lease")
+            }
+            Ok(other) => unexpected_response("session registration", &other),
+            Err(e) => {
+                eprintln!("Couldn't register session: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    pub async fn close_session(&self, id: u32, dedup: Dedup) -> Response {
+        let command = SessionCommand::Close { id };
+        match self
+            .propose_deduped(StoreCommand::Session(command), &dedup)
+            .await
+        {
+            Ok(StoreResponse::Session(SessionResponse::Closed(session))) => (
+                StatusCode::OK,
+                Json(serde_json::json!({ "id": session.id, "lease": session.lease })),
+            )
+                .into_response(),
+            Ok(StoreResponse::Session(SessionResponse::NotFound)) => {
+                error_response(StatusCode::NOT_FOUND, "no such session")
+            }
+            Ok(other) => unexpected_response("session close", &other),
+            Err(e) => {
+                eprintln!("Couldn't close session {id}: {e}");
+                e.into_response()
+            }
+        }
+    }
+
+    /// Puts `key` on the request's lease if nobody holds it, waiting up to `wait_ms` for the
+    /// holder to let go. Acquiring again on the holder's own lease returns the same token, which
+    /// is what makes a retry safe: each attempt has to run, so a recorded outcome can't stand in
+    /// for one and the request is refused if it asks to be deduplicated.
+    async fn acquire(
+        &self,
+        name: &str,
+        key: String,
+        req: AcquireRequest,
+        dedup: Dedup,
+    ) -> Response {
+        if dedup.is_set() {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "acquiring can't be deduplicated; retry on the same lease to get the same token",
+            );
+        }
+        let deadline = Instant::now() + Duration::from_millis(req.wait_ms.unwrap_or(0));
+        loop {
+            // Subscribe before trying, so a release right after a failed attempt isn't missed.
+            let applied = self.raft_state.lock().await.subscribe();
+            let command = KvCommand::Put {
+                key: key.clone(),
+                value: req.value.clone(),
+                expected_revision: Some(0),
+                lease: Some(req.lease),
+            };
+            match self.propose_kv(command, &dedup).await {
+                Ok(KvResponse::Put(entry)) => {
+                    return (StatusCode::OK, Json(Holder::new(name, entry))).into_response();
+                }
+                Ok(KvResponse::RevisionMismatch(_)) => {}
+                Ok(KvResponse::LeaseNotFound) => {
+                    return error_response(StatusCode::NOT_FOUND, "no such lease");
+                }
+                Ok(other) => return unexpected_response("acquire", &other),
+                Err(e) => return e.into_response(),
+            }
+            let holder = self.raft_state.lock().await.state_machine.kv.get(&key);
+            let Some(holder) = holder else {
+                continue;
+            };
+            if holder.lease == Some(req.lease) {
+                return (StatusCode::OK, Json(Holder::new(name, holder))).into_response();
+            }
+            if !coordination::released(applied, &key, deadline).await {
+                let body = serde_json::json!({
+                    "error": format!("{name} is held"),
+                    "holder": Holder::new(name, holder),
+                });
+                return (StatusCode::CONFLICT, Json(body)).into_response();
+            }
+        }
+    }
+
+    /// Deletes `key` if it's still held under `token`.
+    async fn release(&self, name: &str, key: String, token: u32, dedup: Dedup) -> Response {
+        let command = KvCommand::Delete {
+            key,
+            expected_revision: Some(token),
+        };
+        match self.propose_kv(command, &dedup).await {
+            Ok(KvResponse::Deleted(entry)) => {
+                (StatusCode::OK, Json(Holder::new(name, entry))).into_response()
+            }
+            Ok(KvResponse::NotFound) | Ok(KvResponse::RevisionMismatch(0)) => {
+                error_response(StatusCode::NOT_FOUND, format!("{name} isn't held"))
+            }
+            Ok(KvResponse::RevisionMismatch(actual)) => error_response(
+                StatusCode::CONFLICT,
+                format!("token {token} is stale; {name} is held under {actual}"),
+            ),
+            Ok(other) => unexpected_response("release", &other),
+            Err(e) => e.into_response(),
+        }
+    }
+
+    async fn holder(&self, name: &str, key: String, consistency: ReadConsistency) -> Response {
+        match self.read(consistency, |store| store.kv.get(&key)).await {
+            Ok(Some(entry)) => (StatusCode::OK, Json(Holder::new(name, entry))).into_response(),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, format!("{name} isn't held")),
+            Err(e) => proposal_failed(e),
+        }
+    }
+
+    pub async fn lock(&self, name: String, req: AcquireRequest, dedup: Dedup) -> Response {
+        self.acquire(&name, format!("{LOCK_PREFIX}{name}"), req, dedup)
+            .await
+    }
+
+    pub async fn unlock(&self, name: String, req: ReleaseRequest, dedup: Dedup) -> Response {
+        self.release(&name, format!("{LOCK_PREFIX}{name}"), req.token, dedup)
+            .await
+    }
+
+    pub async fn lock_holder(&self, name: String, consistency: ReadConsistency) -> Response {
+        self.holder(&name, format!("{LOCK_PREFIX}{name}"), consistency)
+            .await
+    }
+
+    /// Becomes the election's leader, waiting as a candidate for up to `wait_ms`.
+    pub async fn campaign(&self, name: String, req: AcquireRequest, dedup: Dedup) -> Response {
+        self.acquire(&name, format!("{ELECTION_PREFIX}{name}"), req, dedup)
+            .await
+    }
+
+    pub async fn resign(&self, name: String, req: ReleaseRequest, dedup: Dedup) -> Response {
+        self.release(&name, format!("{ELECTION_PREFIX}{name}"), req.token, dedup)
+            .await
+    }
+
+    pub async fn election_leader(&self, name: String, consistency: ReadConsistency) -> Response {
+        self.holder(&name, format!("{ELECTION_PREFIX}{name}"), consistency)
+            .await
+    }
+
+    pub async fn observe_election(&self, name: String) -> Response {
+        let key = format!("{ELECTION_PREFIX}{name}");
+        let (current, applied) = {
+            let raft_state = self.raft_state.lock().await;
+            let current = raft_state.state_machine.kv.get(&key);
+            (current, raft_state.subscribe())
+        };
+        let current = current.map(|entry| Holder::new(&name, entry));
+        coordination::observe(name, key, current, applied, self.stopped())
+    }
+
+    /// Streams applied changes after `after`, or from now on if it isn't given.
+    pub async fn watch(&self, after: Option<u32>, query: WatchQuery) -> Response {
+        let (after, watched) = {
+            let raft_state = self.raft_state.lock().await;
+            let after = after.unwrap_or(raft_state.last_applied());
+            (after, raft_state.watch(after))
+        };
+        match watched {
+            Ok(watcher) => watch::stream(query, after, watcher, self.stopped()),
+            Err(compacted_through) => (
+                StatusCode::GONE,
+                Json(serde_json::json!({
+                    "error": "compacted",
+                    "compacted_through": compacted_through,
+                })),
+            )
+                .into_response(),
+        }
+    }
+
+    pub async fn list_members(&self) -> (StatusCode, Json<Vec<Member>>) {
+        let members = self.membership.lock().await.members();
+        (StatusCode::OK, Json(members))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::FromRequestParts;
+    use axum::http::Request;
+
+    use super::super::config::Config;
+    use super::*;
+
+    /// A single-node cluster that has elected itself.
+    async fn leader() -> AppState {
+        let config = Config::default();
+        let (handler, _) = Handler::new(&config, NodeId(0));
+        let status_info = StatusInfo {
+            id: NodeId(0),
+            ..StatusInfo::default()
+        };
+        let membership = Membership::new(NodeId(0), String::new(), config.swim.clone());
+        let state = AppState::new(status_info, membership, handler, &config);
+        state.raft_state.lock().await.handle_missed_heartbeat();
+        state
+    }
+
+    async fn dedup(idempotency_key: Option<&str>) -> Dedup {
+        let mut request = Request::builder();
+        if let Some(key) = idempotency_key {
+            request = request.header("idempotency-key", key);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        Dedup::from_request_parts(&mut parts, &()).await.unwrap()
+    }
+
+    async fn json(response: Response) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn a_taken_email_is_a_conflict() {
+        let response = AppState::user_response(Ok(UserResponse::EmailTaken));
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = AppState::user_response(Ok(UserResponse::NotFound));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn session_routes_honor_an_idempotency_key() {
+        let state = leader().await;
+        let grant = GrantLeaseRequest { ttl_ms: 60_000 };
+        let (_, lease) = json(state.grant_lease(grant, dedup(None).await).await).await;
+        let lease = lease["id"].as_u64().unwrap() as u32;
+
+        let register = |key| {
+            let state = state.clone();
+            async move {
+                let req = RegisterSessionRequest { lease };
+                json(state.register_session(req, dedup(key).await).await).await
+            }
+        };
+        let (status, first) = register(Some("register")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(register(Some("register")).await, (status, first.clone()));
+        let (_, other) = register(None).await;
+        assert_ne!(other["id"], first["id"]);
+
+        let session = first["id"].as_u64().unwrap() as u32;
+        let close = || async {
+            json(
+                state
+                    .close_session(session, dedup(Some("close")).await)
+                    .await,
+            )
+            .await
+        };
+        let (status, closed) = close().await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(close().await, (status, closed));
+        let (status, _) = json(state.close_session(session, dedup(None).await).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn acquiring_refuses_to_be_deduplicated() {
+        let state = leader().await;
+        let grant = GrantLeaseRequest { ttl_ms: 60_000 };
+        let (_, lease) = json(state.grant_lease(grant, dedup(None).await).await).await;
+        let acquire = || AcquireRequest {
+            lease: lease["id"].as_u64().unwrap() as u32,
+            value: String::new(),
+            wait_ms: None,
+        };
+
+        let (status, _) = json(
+            state
+                .lock("a".to_string(), acquire(), dedup(Some("k")).await)
+                .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = json(
+            state
+                .campaign("a".to_string(), acquire(), dedup(Some("k")).await)
+                .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // A retry is safe without a key: it gets back the same token.
+        let (status, held) = json(
+            state
+                .lock("a".to_string(), acquire(), dedup(None).await)
+                .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            json(
+                state
+                    .lock("a".to_string(), acquire(), dedup(None).await)
+                    .await
+            )
+            .await,
+            (status, held.clone())
+        );
+        let token = held["token"].as_u64().unwrap() as u32;
+        let release = || async {
+            let req = ReleaseRequest { token };
+            json(
+                state
+                    .unlock("a".to_string(), req, dedup(Some("unlock")).await)
+                    .await,
+            )
+            .await
+        };
+        let (status, released) = release().await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(release().await, (status, released));
     }
 }
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::raft_state::ProposeError;
+
+/// Caps the proposals and linearizable reads a node waits on at once, and counts the requests
+/// turned away by that cap or by the leader's uncommitted-entry limit.
+pub struct Admission {
+    permits: Semaphore,
+    max_in_flight: usize,
+    rejected_in_flight: AtomicU64,
+    rejected_backlogged: AtomicU64,
+}
+
+impl Admission {
+    pub fn new(max_in_flight: usize) -> Self {
+        Admission {
+            permits: Semaphore::new(max_in_flight),
+            max_in_flight,
+            rejected_in_flight: AtomicU64::new(0),
+            rejected_backlogged: AtomicU64::new(0),
+        }
+    }
+
+    /// A slot for one request, held until it's answered.
+    pub fn admit(&self) -> Result<SemaphorePermit<'_>, ProposeError> {
+        self.permits.try_acquire().map_err(|_| {
+            self.rejected_in_flight.fetch_add(1, Ordering::Relaxed);
+            ProposeError::TooManyInFlight
+        })
+    }
+
+    /// Counts a proposal the log refused for want of room.
+    pub fn note(&self, e: &ProposeError) {
+        if let ProposeError::Backlogged = e {
+            self.rejected_backlogged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.permits.available_permits()
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    pub fn rejected_in_flight(&self) -> u64 {
+        self.rejected_in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_backlogged(&self) -> u64 {
+        self.rejected_backlogged.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::http::header::RETRY_AFTER;
+
+    use super::super::proposal_failed;
+    use super::*;
+
+    #[test]
+    fn turns_requests_away_once_every_slot_is_taken() {
+        let admission = Admission::new(2);
+        let first = admission.admit().unwrap();
+        let _second = admission.admit().unwrap();
+        assert_eq!(admission.in_flight(), 2);
+        let Err(e) = admission.admit() else {
+            panic!("admitted a third request");
+        };
+        assert_eq!(admission.rejected_in_flight(), 1);
+
+        let response = proposal_failed(e);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        drop(first);
+        assert!(admission.admit().is_ok());
+    }
+
+    #[test]
+    fn a_backlogged_leader_answers_503_with_retry_after() {
+        let admission = Admission::new(1);
+        let e = ProposeError::Backlogged;
+        admission.note(&e);
+        assert_eq!(admission.rejected_backlogged(), 1);
+
+        let response = proposal_failed(e);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+    }
+}
@@ -0,0 +1,85 @@
+use super::shared::{AddressBook, NodeId, Peer, StatusInfo};
+
+/// Cluster bookkeeping kept next to the replicated state machine: who this node is, the voter
+/// set, and where each voter can be reached.
+#[derive(Clone)]
+pub struct Cluster {
+    pub status_info: StatusInfo,
+    /// The other voters.
+    pub peers: Vec<NodeId>,
+    /// Whether this node is a voter itself. Only voters stand for election or count towards a
+    /// quorum; anyone else just follows the log until an `AddPeer` names it.
+    pub voter: bool,
+    pub addresses: AddressBook,
+}
+
+impl Cluster {
+    pub fn new(status_info: StatusInfo, voters: Vec<NodeId>) -> Self {
+        let voter = voters.contains(&status_info.id);
+        let peers = voters
+            .into_iter()
+            .filter(|id| *id != status_info.id)
+            .collect();
+        Cluster {
+            status_info,
+            peers,
+            voter,
+            addresses: AddressBook::default(),
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.status_info.id
+    }
+
+    /// Votes needed for a majority of the voters.
+    pub fn quorum(&self) -> usize {
+        let voters = self.peers.len() + usize::from(self.voter);
+        voters / 2 + 1
+    }
+
+    pub fn add_peer(&mut self, peer: Peer) {
+        if peer.id == self.node_id() {
+            self.voter = true;
+            return;
+        }
+        if !self.peers.contains(&peer.id) {
+            self.peers.push(peer.id);
+        }
+        if !peer.addr.is_empty() {
+            self.addresses.insert(peer.id, peer.addr);
+        }
+    }
+
+    pub fn remove_peer(&mut self, id: &NodeId) {
+        if *id == self.node_id() {
+            self.voter = false;
+            return;
+        }
+        self.peers.retain(|p| p != id);
+        self.addresses.remove(id);
+    }
+
+    /// The voter set, this node included if it's a voter, with whatever addresses are known,
+    /// for snapshots.
+    pub fn known_peers(&self) -> Vec<Peer> {
+        let own = self.voter.then_some(self.node_id());
+        self.peers
+            .iter()
+            .copied()
+            .chain(own)
+            .map(|id| Peer {
+                id,
+                addr: self.addresses.resolve(&id).cloned().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    pub fn restore_peers(&mut self, peers: Vec<Peer>) {
+        self.peers.clear();
+        self.voter = false;
+        for peer in peers {
+            self.add_peer(peer);
+        }
+    }
+}
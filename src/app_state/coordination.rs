@@ -0,0 +1,261 @@
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+
+use super::raft_state::Applied;
+use super::state_machine::kv::KvEntry;
+use super::state_machine::store::{ChangeKind, Resource, Store};
+
+/// Locks and elections are plain keys under these prefixes, attached to the holder's lease.
+pub const LOCK_PREFIX: &str = "_locks/";
+pub const ELECTION_PREFIX: &str = "_elections/";
+
+/// `POST /locks/{name}` and `POST /elections/{name}/campaign`.
+#[derive(Deserialize)]
+pub struct AcquireRequest {
+    /// The session lease. If it expires the lock or leadership goes with it.
+    pub lease: u32,
+    /// What the holder wants others to see, such as an election candidate's address.
+    #[serde(default)]
+    pub value: String,
+    /// How long to wait for a held lock to be released; by default it isn't waited for.
+    pub wait_ms: Option<u64>,
+}
+
+/// `DELETE /locks/{name}` and `POST /elections/{name}/resign`.
+#[derive(Deserialize)]
+pub struct ReleaseRequest {
+    pub token: u32,
+}
+
+/// Whoever holds a lock or leads an election.
+#[derive(Serialize, Clone, Debug)]
+pub struct Holder {
+    pub name: String,
+    pub lease: Option<u32>,
+    pub value: String,
+    /// The commit index at which it was acquired. It only grows from one holder to the next,
+    /// so other services can reject writes carrying an older token than they've already seen.
+    pub token: u32,
+}
+
+impl Holder {
+    pub fn new(name: &str, entry: KvEntry) -> Self {
+        Holder {
+            name: name.to_string(),
+            lease: entry.lease,
+            value: entry.value,
+            token: entry.revision,
+        }
+    }
+}
+
+/// What an applied command did to `key`, if anything: the new entry for a put, None for a
+/// delete.
+fn change_to(applied: &Applied<Store>, key: &str) -> Option<Option<KvEntry>> {
+    let change = applied
+        .1
+        .changes()
+        .into_iter()
+        .find(|change| change.resource == Resource::Kv && change.key == key)?;
+    match change.kind {
+        ChangeKind::Put => Some(serde_json::from_value(change.value).ok()),
+        ChangeKind::Delete => Some(None),
+    }
+}
+
+/// Waits until `key` is deleted, returning false if `deadline` passes first. Falling behind
+/// counts as a release, since the caller just tries again.
+pub async fn released(
+    mut applied: broadcast::Receiver<Applied<Store>>,
+    key: &str,
+    deadline: Instant,
+) -> bool {
+    loop {
+        match tokio::time::timeout_at(deadline, applied.recv()).await {
+            Err(_) | Ok(Err(RecvError::Closed)) => return false,
+            Ok(Err(RecvError::Lagged(_))) => return true,
+            Ok(Ok(applied)) => {
+                if let Some(None) = change_to(&applied, key) {
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+struct Observer {
+    name: String,
+    key: String,
+    current: Option<Option<Holder>>,
+    applied: broadcast::Receiver<Applied<Store>>,
+    done: bool,
+}
+
+impl Observer {
+    async fn next_event(&mut self) -> Option<Event> {
+        if self.done {
+            return None;
+        }
+        loop {
+            if let Some(holder) = self.current.take() {
+                return Event::default().event("leader").json_data(holder).ok();
+            }
+            match self.applied.recv().await {
+                Ok(applied) => {
+                    self.current = change_to(&applied, &self.key)
+                        .map(|entry| entry.map(|entry| Holder::new(&self.name, entry)));
+                }
+                Err(RecvError::Lagged(_)) => {
+                    self.done = true;
+                    return Some(Event::default().event("error").data("lagged"));
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Streams an election's leader as server-sent `leader` events: the current one first, then
+/// each change, with `null` while there's none. The stream ends once `stopped` resolves.
+pub fn observe(
+    name: String,
+    key: String,
+    current: Option<Holder>,
+    applied: broadcast::Receiver<Applied<Store>>,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> Response {
+    let observer = Observer {
+        name,
+        key,
+        current: Some(current),
+        applied,
+        done: false,
+    };
+    let events = stream::unfold(observer, |mut observer| async move {
+        let event = observer.next_event().await?;
+        Some((Ok::<_, Infallible>(event), observer))
+    })
+    .take_until(stopped);
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::state_machine::StateMachine;
+    use super::super::state_machine::kv::{KvCommand, KvResponse};
+    use super::super::state_machine::lease::LeaseCommand;
+    use super::super::state_machine::store::{StoreCommand, StoreResponse};
+    use super::*;
+
+    const KEY: &str = "_locks/l";
+
+    /// A store holding leases 1 and 2, and a channel to feed what it applies to `released`.
+    fn store() -> (Store, broadcast::Sender<Applied<Store>>) {
+        let mut store = Store::new();
+        for index in 1..=2 {
+            store.apply(
+                index,
+                &StoreCommand::Lease(LeaseCommand::Grant { ttl_ms: 1000 }),
+            );
+        }
+        (store, broadcast::channel(16).0)
+    }
+
+    /// Applies the put an acquire proposes.
+    fn acquire(store: &mut Store, index: u32, lease: u32) -> StoreResponse {
+        store.apply(
+            index,
+            &StoreCommand::Kv(KvCommand::Put {
+                key: KEY.to_string(),
+                value: String::new(),
+                expected_revision: Some(0),
+                lease: Some(lease),
+            }),
+        )
+    }
+
+    fn token(response: StoreResponse) -> u32 {
+        match response {
+            StoreResponse::Kv(KvResponse::Put(entry)) => entry.revision,
+            other => panic!("lock not acquired: {other:?}"),
+        }
+    }
+
+    fn soon(ms: u64) -> Instant {
+        Instant::now() + Duration::from_millis(ms)
+    }
+
+    #[tokio::test]
+    async fn a_second_acquire_waits_while_the_lock_is_held() {
+        let (mut store, applied) = store();
+        let first = token(acquire(&mut store, 3, 1));
+        assert!(matches!(
+            acquire(&mut store, 4, 2),
+            StoreResponse::Kv(KvResponse::RevisionMismatch(revision)) if revision == first
+        ));
+        assert!(!released(applied.subscribe(), KEY, soon(50)).await);
+
+        let waiting = tokio::spawn(released(applied.subscribe(), KEY, soon(5000)));
+        let command = KvCommand::Delete {
+            key: KEY.to_string(),
+            expected_revision: Some(first),
+        };
+        let response = store.apply(5, &StoreCommand::Kv(command));
+        applied.send((5, response)).unwrap();
+        assert!(waiting.await.unwrap());
+        assert!(token(acquire(&mut store, 6, 2)) > first);
+    }
+
+    #[test]
+    fn fencing_tokens_grow_from_one_holder_to_the_next() {
+        let (mut store, _) = store();
+        let mut previous = 0;
+        for index in (3..20).step_by(3) {
+            let lease = index % 2 + 1;
+            let token = token(acquire(&mut store, index, lease));
+            assert!(token > previous);
+            // A filler write in between, as other keys move the log on too.
+            store.apply(
+                index + 1,
+                &StoreCommand::Kv(KvCommand::Put {
+                    key: "other".to_string(),
+                    value: String::new(),
+                    expected_revision: None,
+                    lease: None,
+                }),
+            );
+            let command = KvCommand::Delete {
+                key: KEY.to_string(),
+                expected_revision: Some(token),
+            };
+            store.apply(index + 2, &StoreCommand::Kv(command));
+            previous = token;
+        }
+    }
+
+    #[tokio::test]
+    async fn expiring_or_revoking_the_lease_releases_the_lock() {
+        for end in [
+            LeaseCommand::Expire { id: 1 },
+            LeaseCommand::Revoke { id: 1 },
+        ] {
+            let (mut store, applied) = store();
+            token(acquire(&mut store, 3, 1));
+            let waiting = tokio::spawn(released(applied.subscribe(), KEY, soon(5000)));
+            let response = store.apply(4, &StoreCommand::Lease(end));
+            applied.send((4, response)).unwrap();
+            assert!(waiting.await.unwrap());
+            assert!(store.kv.get(KEY).is_none());
+            token(acquire(&mut store, 5, 2));
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{HeaderMap, StatusCode, request::Parts},
+    response::Response,
+};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::error_response;
+use super::state_machine::{idempotency::Idempotent, session::Tracked, store::StoreCommand};
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// How a mutating request is deduplicated, read from its headers: where it sits in its
+/// client's session (`Client-Id`, `Client-Sequence` and optional `Client-Acked`), and its
+/// `Idempotency-Key`. Requests with neither are applied every time they're sent.
+#[derive(Default)]
+pub struct Dedup {
+    session: Option<(u32, u64, Option<u64>)>,
+    idempotency_key: Option<String>,
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Result<Option<T>, String> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Some)
+        .ok_or_else(|| format!("invalid {name} header"))
+}
+
+impl Dedup {
+    fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+        let client_id = header(headers, "client-id")?;
+        let sequence = header(headers, "client-sequence")?;
+        let acked = header(headers, "client-acked")?;
+        let session = match (client_id, sequence) {
+            (Some(client_id), Some(sequence)) => Some((client_id, sequence, acked)),
+            (None, None) => None,
+            _ => return Err("Client-Id and Client-Sequence must be given together".to_string()),
+        };
+        let idempotency_key: Option<String> = header(headers, "idempotency-key")?;
+        if let Some(key) = &idempotency_key
+            && (key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN)
+        {
+            return Err(format!(
+                "Idempotency-Key must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} characters"
+            ));
+        }
+        Ok(Dedup {
+            session,
+            idempotency_key,
+        })
+    }
+
+    /// Whether the request gave a session or an idempotency key.
+    pub fn is_set(&self) -> bool {
+        self.session.is_some() || self.idempotency_key.is_some()
+    }
+
+    pub fn has_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Wraps `command` so that it's applied at most once, remembering an idempotent outcome
+    /// for `window_ms` from now.
+    pub fn wrap(&self, command: StoreCommand, window_ms: u64) -> StoreCommand {
+        let command = match self.session {
+            Some((client_id, sequence, acked)) => StoreCommand::Tracked(Tracked {
+                client_id,
+                sequence,
+                acked,
+                command: Box::new(command),
+            }),
+            None => command,
+        };
+        match &self.idempotency_key {
+            Some(key) => StoreCommand::Idempotent(Idempotent {
+                key: key.clone(),
+                proposed_at_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+                window_ms,
+                command: Box::new(command),
+            }),
+            None => command,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Dedup {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Response> {
+        Self::from_headers(&parts.headers).map_err(|e| error_response(StatusCode::BAD_REQUEST, e))
+    }
+}
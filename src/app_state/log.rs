@@ -1,54 +1,271 @@
-use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use anyhow::bail;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+use super::shared::{NodeId, Peer};
+
+/// The newest feature version this build understands.
+///
+/// Nodes of different builds share a log during a rolling upgrade, so what goes into it has to
+/// stay readable by every node that might replay it:
+///
+/// - Every state machine command states the feature version it needs. A leader only proposes a
+///   command once the cluster's feature version has reached that, and the cluster's version is
+///   only raised, through a `SetFeatureVersion` entry, once every node the leader replicates to
+///   reports supporting it. So a node is never handed an entry it can't decode.
+/// - A node joining after a raise is sent the entries that came with it, so it has to run a
+///   build that supports the cluster's version already.
+/// - Anything added to a command, a log entry or a peer message that older builds would
+///   reject needs a new feature version. Fields that older builds can ignore or default, marked
+///   `#[serde(default)]`, don't.
+/// - Variants and fields are never removed or renamed; a snapshot or a lagging node may still
+///   hold them.
+/// - The cluster's feature version never goes down, so a rollback has to happen before the new
+///   version is raised.
+/// - A node that can't decode an entry stops accepting entries there rather than skip it.
+/// - A new peer message is only sent once the cluster's feature version has reached the version
+///   that added it.
+///
+/// Builds from before feature versions aren't wire-compatible with this one: their log entries
+/// and peer messages have a different shape altogether. A cluster running one has to be replaced
+/// as a whole rather than upgraded node by node. The log is only held in memory, so there's no
+/// stored log of theirs to read back either.
+pub const FEATURE_VERSION: u32 = 4;
+
+/// The feature version a cluster starts at, and that a peer is taken to support when its
+/// messages don't say.
+pub const BASE_FEATURE_VERSION: u32 = 1;
+
+/// The feature version that added `TimeoutNow`, for handing leadership over.
+pub const TIMEOUT_NOW_VERSION: u32 = 2;
+
+/// The feature version that added `StatusRequest` and `StatusResponse`, for `/cluster`.
+pub const STATUS_VERSION: u32 = 3;
+
+/// The feature version that added the kv, txn, lease, session and idempotency commands.
+pub const SERVICES_VERSION: u32 = 4;
+
+pub fn base_feature_version() -> u32 {
+    BASE_FEATURE_VERSION
+}
+
+/// A log command: either one for the replicated state machine, or a change to the cluster
+/// itself.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum Command {
-    AddUser { name: String, email: String },
+pub enum Command<C> {
+    App(C),
+    AddPeer {
+        peer: Peer,
+    },
+    RemovePeer {
+        id: NodeId,
+    },
+    /// Appended by a new leader so entries from earlier terms can commit.
+    Noop,
+    /// Raises the cluster's feature version, letting commands that need it be proposed.
+    SetFeatureVersion {
+        version: u32,
+    },
 }
 
-pub trait ToCommand {
-    fn to_command(&self) -> Command;
+pub trait ToCommand<C> {
+    fn to_command(&self) -> C;
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct LogEntry {
+pub struct LogEntry<C> {
     pub index: u32,
     pub term: u32,
-    pub command: Command,
+    pub command: Command<C>,
+}
+
+/// A state machine command as it travels between peers: left encoded, and labelled with the
+/// feature version needed to decode it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub command: serde_json::Value,
+}
+
+/// Log entries as they travel between peers.
+pub type WireEntry = LogEntry<Envelope>;
+
+impl<C: Serialize> LogEntry<C> {
+    /// Encodes the entry, labelling its command with the version `version_of` says it needs.
+    pub fn to_wire(&self, version_of: impl Fn(&C) -> u32) -> WireEntry {
+        let command = match &self.command {
+            Command::App(command) => Command::App(Envelope {
+                version: version_of(command),
+                command: serde_json::to_value(command).expect("commands always serialize to JSON"),
+            }),
+            Command::AddPeer { peer } => Command::AddPeer { peer: peer.clone() },
+            Command::RemovePeer { id } => Command::RemovePeer { id: *id },
+            Command::Noop => Command::Noop,
+            Command::SetFeatureVersion { version } => {
+                Command::SetFeatureVersion { version: *version }
+            }
+        };
+        LogEntry {
+            index: self.index,
+            term: self.term,
+            command,
+        }
+    }
 }
 
+impl WireEntry {
+    /// Decodes the entry, failing if its command needs a newer feature version than this build
+    /// has.
+    pub fn decode<C: DeserializeOwned>(self) -> anyhow::Result<LogEntry<C>> {
+        let command = match self.command {
+            Command::App(envelope) => {
+                if envelope.version > FEATURE_VERSION {
+                    bail!(
+                        "entry {} needs feature version {}; this node supports up to {FEATURE_VERSION}",
+                        self.index,
+                        envelope.version
+                    );
+                }
+                Command::App(serde_json::from_value(envelope.command)?)
+            }
+            Command::AddPeer { peer } => Command::AddPeer { peer },
+            Command::RemovePeer { id } => Command::RemovePeer { id },
+            Command::Noop => Command::Noop,
+            Command::SetFeatureVersion { version } => Command::SetFeatureVersion { version },
+        };
+        Ok(LogEntry {
+            index: self.index,
+            term: self.term,
+            command,
+        })
+    }
+}
+
+/// The entries after the latest snapshot. Everything up to and including `snapshot_index` has
+/// been compacted away.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Log {
-    pub latest_seen: u32,
-    pub latest_applied: u32,
-    pub entries: Vec<LogEntry>,
+pub struct Log<C> {
+    pub snapshot_index: u32,
+    pub snapshot_term: u32,
+    pub entries: Vec<LogEntry<C>>,
 }
 
-impl Log {
-    pub fn new() -> Log {
+impl<C: Clone> Log<C> {
+    pub fn new() -> Log<C> {
         Log {
-            latest_seen: 0,
-            latest_applied: 0,
+            snapshot_index: 0,
+            snapshot_term: 0,
             entries: Vec::new(),
         }
     }
 
-    fn update_log(&mut self, term: u32, command: Command) {
-        let latest_seen = self.latest_seen + 1;
+    /// The oldest entry still held, or the last compacted one if there are none.
+    pub fn first_index(&self) -> u32 {
+        self.entries
+            .first()
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    pub fn first_term(&self) -> u32 {
+        self.entries
+            .first()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    pub fn last_index(&self) -> u32 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    pub fn last_term(&self) -> u32 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    fn position(&self, index: u32) -> Option<usize> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        let position = (index - self.snapshot_index - 1) as usize;
+        (position < self.entries.len()).then_some(position)
+    }
+
+    pub fn entry(&self, index: u32) -> Option<&LogEntry<C>> {
+        self.position(index).map(|position| &self.entries[position])
+    }
+
+    /// The term of the entry at `index`, which is still known for the last compacted entry.
+    pub fn term_at(&self, index: u32) -> Option<u32> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub fn entries_from(&self, index: u32, max: usize) -> Vec<LogEntry<C>> {
+        match self.position(index) {
+            Some(position) => self.entries[position..].iter().take(max).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn update_log(&mut self, term: u32, command: Command<C>) -> u32 {
+        let index = self.last_index() + 1;
         self.entries.push(LogEntry {
-            index: latest_seen,
+            index,
             term,
             command,
         });
-        self.latest_seen = latest_seen;
+        index
+    }
+
+    pub fn push(&mut self, entry: LogEntry<C>) {
+        self.entries.push(entry);
+    }
+
+    /// Drops the entry at `index` and everything after it.
+    pub fn truncate_from(&mut self, index: u32) {
+        if let Some(position) = self.position(index) {
+            self.entries.truncate(position);
+        }
+    }
+
+    /// Discards entries up to and including `index`, which must already be applied.
+    pub fn compact_through(&mut self, index: u32) {
+        let Some(term) = self.term_at(index) else {
+            return;
+        };
+        let keep = self.position(index).map_or(0, |position| position + 1);
+        self.entries.drain(..keep);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// Moves the log onto an installed snapshot, keeping any entries that follow it.
+    pub fn reset_to_snapshot(&mut self, index: u32, term: u32) {
+        if self.term_at(index) == Some(term) {
+            self.compact_through(index);
+        } else {
+            self.entries.clear();
+            self.snapshot_index = index;
+            self.snapshot_term = term;
+        }
     }
 }
 
-pub async fn add_to_log<T>(log: Arc<Mutex<Log>>, term: u32, entry: &T)
-where
-    T: ToCommand,
-{
-    let mut log = log.lock().await;
-    log.update_log(term, entry.to_command());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_commands_needing_a_newer_version() {
+        let entry = LogEntry {
+            index: 1,
+            term: 1,
+            command: Command::App(0u32),
+        };
+        let wire = entry.to_wire(|_| FEATURE_VERSION + 1);
+        assert!(wire.decode::<u32>().is_err());
+    }
 }
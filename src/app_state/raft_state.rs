@@ -1,39 +1,282 @@
-use std::collections::HashMap;
-use tokio::sync::broadcast;
-use tokio::sync::mpsc::Sender;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::{Duration, Instant};
 
-use super::super::websocket::shared::WSMessage;
-use super::log::Log;
-use super::shared::{Peer, ServerState};
+use super::super::config::RaftConfig;
+use super::super::websocket::shared::{Outbound, WSMessage};
+use super::cluster::Cluster;
+use super::log::{
+    BASE_FEATURE_VERSION, Command, FEATURE_VERSION, Log, LogEntry, TIMEOUT_NOW_VERSION, WireEntry,
+};
+use super::shared::{NodeId, Peer, ServerState};
 use super::state_machine::StateMachine;
 
+#[derive(Debug)]
+pub enum ProposeError {
+    /// Only the leader accepts proposals; carries the leader if one is known.
+    NotLeader(Option<NodeId>),
+    /// This node stopped leading before the entry was applied here. The next leader may still
+    /// commit it, so whether it took effect is unknown.
+    LeadershipLost,
+    /// A new leader replaced the entry with its own, so it will never take effect.
+    Overwritten,
+    /// The entry wasn't applied in time. It may still be.
+    Timeout,
+    /// Too many proposals are already waiting on this node.
+    TooManyInFlight,
+    /// The leader holds as many uncommitted entries as it's allowed; a quorum isn't keeping up.
+    Backlogged,
+    /// The command needs a newer feature version than the cluster has raised so far.
+    FeatureNotEnabled { required: u32, active: u32 },
+    /// Voters change one at a time, and the previous change isn't committed yet.
+    MembershipChangePending,
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader(Some(leader)) => write!(f, "not the leader; node {leader} is"),
+            ProposeError::NotLeader(None) => write!(f, "not the leader; no leader is known"),
+            ProposeError::LeadershipLost => {
+                write!(f, "leadership was lost before the entry was applied")
+            }
+            ProposeError::Overwritten => write!(f, "entry was overwritten by a new leader"),
+            ProposeError::Timeout => write!(f, "timed out waiting for the entry to be applied"),
+            ProposeError::TooManyInFlight => write!(f, "too many requests in flight"),
+            ProposeError::Backlogged => {
+                write!(
+                    f,
+                    "too many uncommitted entries; replication is falling behind"
+                )
+            }
+            ProposeError::FeatureNotEnabled { required, active } => write!(
+                f,
+                "needs cluster feature version {required}; the cluster is at {active}"
+            ),
+            ProposeError::MembershipChangePending => write!(
+                f,
+                "an earlier membership change, or this leader's first entry, isn't committed yet"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProposeError {}
+
+type ProposalResult<R> = Result<R, ProposeError>;
+
+/// Resolves with the state machine's response once the proposed entry is applied, or with why
+/// it won't be. Dropping it only stops the waiting; the entry stays in the log.
+pub struct Proposal<R> {
+    rx: oneshot::Receiver<ProposalResult<R>>,
+}
+
+impl<R> Future for Proposal<R> {
+    type Output = ProposalResult<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The sender is only dropped unanswered along with the node itself.
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(ProposeError::LeadershipLost)))
+    }
+}
+
+/// Someone waiting on the entry proposed at an index, in a term.
+struct Waiter<R> {
+    term: u32,
+    tx: oneshot::Sender<ProposalResult<R>>,
+}
+
+/// A state machine response along with the index of the entry that produced it.
+pub type Applied<S> = (u32, <S as StateMachine>::Response);
+
+/// Applied responses for a watcher: what it missed, then what's applied from now on.
+pub struct Watcher<S: StateMachine> {
+    pub backlog: Vec<Applied<S>>,
+    pub applied: broadcast::Receiver<Applied<S>>,
+    /// Changes to the snapshot index when one installed from the leader skips over entries
+    /// the watcher hasn't been sent.
+    pub compacted: watch::Receiver<u32>,
+}
+
+/// The state machine as of `last_index`, with the cluster configuration that went with it.
 #[derive(Clone)]
-pub struct RaftState {
-    pub log: Log,
-    voted_for: Option<Peer>,
+pub struct Snapshot {
+    pub last_index: u32,
+    pub last_term: u32,
+    pub peers: Vec<Peer>,
+    pub feature_version: u32,
+    pub data: Vec<u8>,
+}
+
+/// A Raft node replicating the state machine `S`.
+///
+/// Every method takes the node from one state to the next and returns the messages that should
+/// go out as a result; sending them, and running the timers, is left to the `Handler`.
+pub struct RaftState<S: StateMachine> {
+    pub log: Log<S::Command>,
+    pub cluster: Cluster,
+    pub state_machine: S,
+    /// The voter set as of the snapshot index. The membership changes in the log after it
+    /// build on this.
+    base_peers: Vec<Peer>,
+    voted_for: Option<NodeId>,
     commit_index: u32,
     last_applied: u32,
     current_term: u32,
     current_state: ServerState,
+    leader_id: Option<NodeId>,
+    last_leader_contact: Option<Instant>,
+    snapshot: Option<Snapshot>,
+    /// Why the last snapshot taken or installed failed, until one succeeds.
+    snapshot_error: Option<String>,
+    /// Responses to the state machine commands applied since the last snapshot, for watchers.
+    history: VecDeque<Applied<S>>,
+    applied_tx: broadcast::Sender<Applied<S>>,
+    installed_tx: watch::Sender<u32>,
+    waiters: HashMap<u32, Waiter<S::Response>>,
+    /// Reads waiting on each barrier `Noop`, by its index.
+    barriers: HashMap<u32, Vec<oneshot::Sender<()>>>,
+    /// The cluster's feature version as of the last applied entry.
+    feature_version: u32,
+    /// The voter this leader is handing leadership to, and since when.
+    transferring_to: Option<(NodeId, Instant)>,
+    /// Set when the node is shutting down, so it doesn't stand for election again.
+    retiring: bool,
+    /// The highest commit index a leader has told this node about.
+    known_commit: u32,
+    /// Set once this node has caught up with a leader, or become one. Until then its state
+    /// machine is still being rebuilt from the cluster.
+    recovered: bool,
+    /// The newest feature version each peer has said it supports. Local knowledge, kept across
+    /// terms.
+    supported_versions: HashMap<NodeId, u32>,
+    config: RaftConfig,
 }
 
-impl RaftState {
-    pub fn new() -> Self {
+impl<S: StateMachine> RaftState<S> {
+    pub fn new(
+        cluster: Cluster,
+        state_machine: S,
+        config: RaftConfig,
+        watch_capacity: usize,
+    ) -> Self {
+        let (applied_tx, _) = broadcast::channel(watch_capacity);
         RaftState {
             log: Log::new(),
+            base_peers: cluster.known_peers(),
+            cluster,
+            state_machine,
             voted_for: None,
             commit_index: 0,
             last_applied: 0,
             current_term: 0,
-            current_state: ServerState::Follower,
+            current_state: ServerState::follower(),
+            leader_id: None,
+            last_leader_contact: None,
+            snapshot: None,
+            snapshot_error: None,
+            history: VecDeque::new(),
+            applied_tx,
+            installed_tx: watch::Sender::new(0),
+            waiters: HashMap::new(),
+            barriers: HashMap::new(),
+            feature_version: BASE_FEATURE_VERSION,
+            transferring_to: None,
+            retiring: false,
+            known_commit: 0,
+            recovered: false,
+            supported_versions: HashMap::new(),
+            config,
+        }
+    }
+
+    fn node_id(&self) -> NodeId {
+        self.cluster.node_id()
+    }
+
+    pub fn last_applied(&self) -> u32 {
+        self.last_applied
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader_id
+    }
+
+    pub fn current_term(&self) -> u32 {
+        self.current_term
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.voted_for
+    }
+
+    pub fn commit_index(&self) -> u32 {
+        self.commit_index
+    }
+
+    pub fn role(&self) -> &'static str {
+        match self.current_state {
+            ServerState::Leader { .. } => "leader",
+            ServerState::Follower => "follower",
+            ServerState::Candidate { .. } => "candidate",
         }
     }
 
+    /// Each follower's `next_index` and `match_index`, if this node leads.
+    pub fn progress(&self) -> HashMap<NodeId, (u32, u32)> {
+        let ServerState::Leader {
+            next_index,
+            match_index,
+        } = &self.current_state
+        else {
+            return HashMap::new();
+        };
+        next_index
+            .iter()
+            .map(|(peer, next)| (*peer, (*next, match_index.get(peer).copied().unwrap_or(0))))
+            .collect()
+    }
+
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+
+    /// Entries known to be committed that this node hasn't applied yet.
+    pub fn apply_lag(&self) -> u32 {
+        self.known_commit
+            .max(self.commit_index)
+            .saturating_sub(self.last_applied)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.current_state, ServerState::Leader { .. })
+    }
+
+    /// Fails with the leader we know of unless it's this node.
+    pub fn ensure_leader(&self) -> Result<(), ProposeError> {
+        if self.is_leader() {
+            Ok(())
+        } else {
+            Err(ProposeError::NotLeader(self.leader_id))
+        }
+    }
+
+    /// The term this node is leading, if it's the leader.
+    pub fn leader_term(&self) -> Option<u32> {
+        self.is_leader().then_some(self.current_term)
+    }
+
     fn inc_term(&mut self) {
         self.current_term += 1;
     }
 
-    fn set_voted_for(&mut self, vote: Peer) {
+    fn set_voted_for(&mut self, vote: NodeId) {
         self.voted_for = Some(vote);
     }
 
@@ -41,71 +284,1140 @@ impl RaftState {
         self.voted_for = None;
     }
 
-    fn append_entries(&self) -> WSMessage {
+    fn append_entries(&self, next_index: u32) -> WSMessage {
+        let prev_log_index = next_index - 1;
         WSMessage::AppendEntries {
             term: self.current_term,
-            leader_id: todo!(),
-            prev_log_index: todo!(),
-            prev_log_term: todo!(),
-            entries: todo!(),
+            leader_id: self.node_id(),
+            prev_log_index,
+            prev_log_term: self.log.term_at(prev_log_index).unwrap_or_default(),
+            entries: self
+                .log
+                .entries_from(next_index, self.config.max_append_entries)
+                .iter()
+                .map(|entry| entry.to_wire(S::required_version))
+                .collect(),
+            leader_commit: self.commit_index,
+        }
+    }
+
+    fn install_snapshot(&self, snapshot: &Snapshot) -> WSMessage {
+        WSMessage::InstallSnapshot {
+            term: self.current_term,
+            leader_id: self.node_id(),
+            last_included_index: snapshot.last_index,
+            last_included_term: snapshot.last_term,
+            peers: snapshot.peers.clone(),
+            feature_version: snapshot.feature_version,
+            data: snapshot.data.clone(),
         }
     }
 
-    fn request_vote(&self, state_machine: &StateMachine) -> WSMessage {
+    /// What a follower whose next entry is `next_index` should be sent.
+    fn message_for(&self, next_index: u32) -> Option<WSMessage> {
+        if next_index <= self.log.snapshot_index {
+            self.snapshot.as_ref().map(|s| self.install_snapshot(s))
+        } else {
+            Some(self.append_entries(next_index))
+        }
+    }
+
+    fn append_entries_response(&self, to: NodeId, success: bool, match_index: u32) -> Outbound {
+        Outbound::to(
+            to,
+            WSMessage::AppendEntriesResponse {
+                from: self.node_id(),
+                term: self.current_term,
+                success,
+                match_index,
+                supported_version: FEATURE_VERSION,
+            },
+        )
+    }
+
+    fn request_vote(&self, transfer: bool) -> WSMessage {
         WSMessage::RequestVote {
             term: self.current_term,
-            candidate_id: state_machine.status_info.to_peer(),
-            last_log_index: todo!(),
-            last_log_term: todo!(),
+            candidate_id: self.node_id(),
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+            transfer,
         }
     }
 
-    async fn initiate_election(
-        &mut self,
-        response_tx: broadcast::Sender<WSMessage>,
-        state_machine: &StateMachine,
-    ) {
-        self.current_state = ServerState::candidate(state_machine.status_info.clone());
+    /// Stands for election in the next term. `transfer` is set when the leader asked for it.
+    fn initiate_election(&mut self, transfer: bool) -> Vec<Outbound> {
         self.inc_term();
-        let request_vote = self.request_vote(state_machine);
-        let _ = response_tx.send(request_vote);
-        let peer_ip = state_machine.status_info.ip.clone();
-        self.set_voted_for(Peer { ip: peer_ip });
+        self.current_state = ServerState::candidate(&self.cluster.status_info);
+        self.set_voted_for(self.node_id());
+        self.leader_id = None;
+        println!("Starting election for term {}", self.current_term);
+        if self.cluster.quorum() <= 1 {
+            return self.convert_to_leader();
+        }
+        vec![Outbound::broadcast(self.request_vote(transfer))]
     }
 
-    fn convert_to_leader(&mut self, new_term: u32, state_machine: &StateMachine) {
-        let latest_applied = self.log.latest_applied.clone();
-        let peers = state_machine.peers.clone();
-        self.current_state = ServerState::leader(peers, latest_applied);
-        self.current_term = new_term;
+    fn convert_to_leader(&mut self) -> Vec<Outbound> {
+        println!("Became leader for term {}", self.current_term);
+        self.current_state = ServerState::leader(&self.cluster.peers, self.log.last_index());
+        self.leader_id = Some(self.node_id());
+        self.recovered = true;
+        self.log.update_log(self.current_term, Command::Noop);
+        self.raise_feature_version();
+        self.advance_commit_index();
+        self.send_messages()
     }
 
     fn convert_to_follower(&mut self, new_term: u32) {
-        self.current_state = ServerState::Follower;
-        self.current_term = new_term;
+        if new_term > self.current_term {
+            self.current_term = new_term;
+            self.clear_voted_for();
+            self.leader_id = None;
+        }
+        if self.is_leader() {
+            println!("Stepping down in term {}", self.current_term);
+            self.transferring_to = None;
+            // Proposals stay waiting: the next leader either commits their entries, and they're
+            // applied here as usual, or replaces them. Reads can't be linearized any more.
+            self.barriers.clear();
+        }
+        self.current_state = ServerState::follower();
     }
 
-    pub async fn handle_missed_heartbeat(
-        &mut self,
-        response_tx: broadcast::Sender<WSMessage>,
-        state_machine: &StateMachine,
-    ) {
-        match self.current_state {
-            ServerState::Follower => self.initiate_election(response_tx, state_machine).await,
-            ServerState::Candidate { .. } => todo!(),
-            ServerState::Leader { .. } => {}
+    /// Acknowledges `leader_id` as leader for `term`.
+    fn follow(&mut self, term: u32, leader_id: NodeId) {
+        if term > self.current_term || !matches!(self.current_state, ServerState::Follower) {
+            self.convert_to_follower(term);
         }
+        self.leader_id = Some(leader_id);
+        self.last_leader_contact = Some(Instant::now());
+    }
+
+    fn heard_from_leader_recently(&self) -> bool {
+        let min_timeout = Duration::from_millis(self.config.election_timeout_min_ms);
+        self.is_leader()
+            || self
+                .last_leader_contact
+                .is_some_and(|contact| contact.elapsed() < min_timeout)
     }
 
-    pub async fn send_messages(
-        &self,
-        response_tx: Sender<WSMessage>,
-        state_machine: &StateMachine,
-    ) {
+    pub fn handle_missed_heartbeat(&mut self) -> Vec<Outbound> {
         match self.current_state {
-            ServerState::Follower => todo!(),
-            ServerState::Candidate { .. } => todo!(),
-            ServerState::Leader { .. } => todo!(),
+            ServerState::Leader { .. } => Vec::new(),
+            _ if self.retiring || !self.cluster.voter => Vec::new(),
+            _ => self.initiate_election(false),
+        }
+    }
+
+    /// Keeps this node from standing for election from now on, as it's on its way out.
+    pub fn retire(&mut self) {
+        self.retiring = true;
+    }
+
+    /// Starts handing leadership to the voter with the most of the log: once it has every entry,
+    /// it's sent a `TimeoutNow` so it stands for election straight away. Until then, it catches
+    /// up through normal replication, and new proposals are refused so that it can. A target
+    /// that hasn't taken over within an election timeout is given up on for another. Returns
+    /// `None` once there's no leadership to hand off, because this node isn't leading or it's
+    /// the only voter, or when the cluster's feature version is too old for `TimeoutNow`, in
+    /// which case the followers elect a leader once they time out.
+    pub fn transfer_leadership(&mut self) -> Option<Vec<Outbound>> {
+        let ServerState::Leader { match_index, .. } = &self.current_state else {
+            return None;
+        };
+        if self.feature_version < TIMEOUT_NOW_VERSION {
+            return None;
+        }
+        let most_caught_up = |skip: Option<NodeId>| {
+            match_index
+                .iter()
+                .filter(|(peer, _)| self.cluster.peers.contains(peer) && Some(**peer) != skip)
+                .max_by_key(|(peer, matched)| (**matched, std::cmp::Reverse(**peer)))
+                .map(|(peer, _)| *peer)
+        };
+        let patience = Duration::from_millis(self.config.election_timeout_max_ms);
+        let target = match self.transferring_to {
+            Some((target, since)) if since.elapsed() < patience => target,
+            previous => {
+                let previous = previous.map(|(target, _)| target);
+                let target = most_caught_up(previous).or_else(|| most_caught_up(None))?;
+                println!("Handing leadership to node {target}");
+                self.transferring_to = Some((target, Instant::now()));
+                target
+            }
+        };
+        if match_index.get(&target).copied().unwrap_or_default() < self.log.last_index() {
+            return Some(Vec::new());
+        }
+        let timeout_now = WSMessage::TimeoutNow {
+            term: self.current_term,
+            leader_id: self.node_id(),
+        };
+        Some(vec![Outbound::to(target, timeout_now)])
+    }
+
+    /// The leader is handing over, so stands for election now rather than wait to time out.
+    pub fn handle_timeout_now(&mut self, term: u32, leader_id: NodeId) -> Vec<Outbound> {
+        if term != self.current_term
+            || self.leader_id != Some(leader_id)
+            || self.is_leader()
+            || self.retiring
+            || !self.cluster.voter
+        {
+            return Vec::new();
+        }
+        println!("Node {leader_id} is handing over leadership");
+        self.initiate_election(true)
+    }
+
+    /// Sends each follower whatever it's missing, which is just a heartbeat when it's caught up.
+    pub fn send_messages(&self) -> Vec<Outbound> {
+        let ServerState::Leader { next_index, .. } = &self.current_state else {
+            return Vec::new();
+        };
+        next_index
+            .iter()
+            .filter_map(|(peer, next)| self.message_for(*next).map(|m| Outbound::to(*peer, m)))
+            .collect()
+    }
+
+    /// Returns the replies and whether the vote was granted.
+    pub fn handle_request_vote(
+        &mut self,
+        term: u32,
+        candidate_id: NodeId,
+        last_log_index: u32,
+        last_log_term: u32,
+        transfer: bool,
+    ) -> (Vec<Outbound>, bool) {
+        // While a leader is known to be alive, a node that was partitioned or removed shouldn't
+        // be able to force an election just by showing up with a higher term. The leader itself
+        // may have asked for it, though.
+        if term > self.current_term && self.heard_from_leader_recently() && !transfer {
+            return (Vec::new(), false);
+        }
+        if term > self.current_term {
+            self.convert_to_follower(term);
+        }
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let vote_granted = term == self.current_term
+            && self.voted_for.is_none_or(|v| v == candidate_id)
+            && up_to_date;
+        if vote_granted {
+            self.set_voted_for(candidate_id);
+        }
+        let reply = WSMessage::RequestVoteResponse {
+            from: self.node_id(),
+            term: self.current_term,
+            vote_granted,
+        };
+        (vec![Outbound::to(candidate_id, reply)], vote_granted)
+    }
+
+    pub fn handle_request_vote_response(
+        &mut self,
+        from: NodeId,
+        term: u32,
+        vote_granted: bool,
+    ) -> Vec<Outbound> {
+        if term > self.current_term {
+            self.convert_to_follower(term);
+            return Vec::new();
+        }
+        if term != self.current_term || !vote_granted || !self.cluster.peers.contains(&from) {
+            return Vec::new();
+        }
+        let quorum = self.cluster.quorum();
+        let ServerState::Candidate { voted_for } = &mut self.current_state else {
+            return Vec::new();
+        };
+        voted_for.insert(from);
+        if voted_for.len() >= quorum {
+            self.convert_to_leader()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Returns the replies and whether the message came from a current leader.
+    pub fn handle_append_entries(
+        &mut self,
+        term: u32,
+        leader_id: NodeId,
+        prev_log_index: u32,
+        prev_log_term: u32,
+        entries: Vec<WireEntry>,
+        leader_commit: u32,
+    ) -> (Vec<Outbound>, bool) {
+        if term < self.current_term {
+            let reply = self.append_entries_response(leader_id, false, self.log.last_index());
+            return (vec![reply], false);
+        }
+        self.follow(term, leader_id);
+
+        // Everything up to the snapshot is committed, so it's known to match the leader.
+        if prev_log_index > self.log.snapshot_index {
+            match self.log.term_at(prev_log_index) {
+                Some(t) if t == prev_log_term => {}
+                Some(_) => {
+                    let reply = self.append_entries_response(leader_id, false, prev_log_index - 1);
+                    return (vec![reply], true);
+                }
+                None => {
+                    let reply =
+                        self.append_entries_response(leader_id, false, self.log.last_index());
+                    return (vec![reply], true);
+                }
+            }
+        }
+
+        let mut match_index = prev_log_index;
+        for wire in entries {
+            let entry = match wire.decode() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Couldn't decode log entry: {e}");
+                    break;
+                }
+            };
+            let index = entry.index;
+            if index > self.log.snapshot_index {
+                match self.log.term_at(index) {
+                    Some(t) if t == entry.term => {}
+                    Some(_) => {
+                        self.truncate_from(index);
+                        self.append(entry);
+                    }
+                    None => self.append(entry),
+                }
+            }
+            match_index = index;
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(leader_commit.min(match_index));
+            self.apply_committed();
+        }
+        self.known_commit = self.known_commit.max(leader_commit);
+        if !self.recovered && self.last_applied >= leader_commit {
+            println!("Caught up with the leader at index {}", self.last_applied);
+            self.recovered = true;
+        }
+        let reply = self.append_entries_response(leader_id, true, match_index);
+        (vec![reply], true)
+    }
+
+    /// Returns the replies and whether the message came from a current leader.
+    pub fn handle_install_snapshot(
+        &mut self,
+        term: u32,
+        leader_id: NodeId,
+        snapshot: Snapshot,
+    ) -> (Vec<Outbound>, bool) {
+        if term < self.current_term {
+            let reply = self.append_entries_response(leader_id, false, self.log.last_index());
+            return (vec![reply], false);
+        }
+        self.follow(term, leader_id);
+
+        let last_index = snapshot.last_index;
+        if last_index > self.commit_index {
+            if let Err(e) = self.state_machine.restore(&snapshot.data) {
+                eprintln!("Couldn't restore snapshot: {e}");
+                self.snapshot_error = Some(format!("couldn't restore snapshot: {e}"));
+                let reply = self.append_entries_response(leader_id, false, self.log.last_index());
+                return (vec![reply], true);
+            }
+            println!("Installed snapshot through index {last_index}");
+            self.snapshot_error = None;
+            self.feature_version = snapshot.feature_version;
+            self.log.reset_to_snapshot(last_index, snapshot.last_term);
+            self.base_peers = snapshot.peers.clone();
+            let peers = self.peers_through(self.log.last_index());
+            self.cluster.restore_peers(peers);
+            self.commit_index = last_index;
+            self.last_applied = last_index;
+            self.history.clear();
+            self.installed_tx.send_replace(last_index);
+            // The snapshot doesn't say which entries made it in.
+            for (_, waiter) in self.waiters.drain() {
+                let _ = waiter.tx.send(Err(ProposeError::LeadershipLost));
+            }
+            self.snapshot = Some(snapshot);
+        }
+        let reply = self.append_entries_response(leader_id, true, last_index);
+        (vec![reply], true)
+    }
+
+    pub fn handle_append_entries_response(
+        &mut self,
+        from: NodeId,
+        term: u32,
+        success: bool,
+        match_index: u32,
+        supported_version: u32,
+    ) -> Vec<Outbound> {
+        self.supported_versions.insert(from, supported_version);
+        if term > self.current_term {
+            self.convert_to_follower(term);
+            return Vec::new();
+        }
+        if term != self.current_term {
+            return Vec::new();
+        }
+        let last_index = self.log.last_index();
+        let ServerState::Leader {
+            next_index,
+            match_index: matched,
+        } = &mut self.current_state
+        else {
+            return Vec::new();
+        };
+        let Some(next) = next_index.get_mut(&from) else {
+            return Vec::new();
+        };
+
+        if success {
+            let known = matched.entry(from).or_insert(0);
+            *known = (*known).max(match_index);
+            *next = (*next).max(match_index + 1);
+            let next = *next;
+            self.raise_feature_version();
+            self.advance_commit_index();
+            if next > last_index {
+                return Vec::new();
+            }
+            self.message_for(next)
+                .map(|m| vec![Outbound::to(from, m)])
+                .unwrap_or_default()
+        } else {
+            // The follower's hint lets us skip straight past everything it's missing.
+            *next = (*next - 1).min(match_index + 1).max(1);
+            let next = *next;
+            self.message_for(next)
+                .map(|m| vec![Outbound::to(from, m)])
+                .unwrap_or_default()
+        }
+    }
+
+    fn advance_commit_index(&mut self) {
+        let ServerState::Leader { match_index, .. } = &self.current_state else {
+            return;
+        };
+        let quorum = self.cluster.quorum();
+        let mut n = self.log.last_index();
+        // Only entries from the current term are committed by counting replicas; earlier ones
+        // commit along with them.
+        while n > self.commit_index && self.log.term_at(n) == Some(self.current_term) {
+            let replicas =
+                usize::from(self.cluster.voter) + match_index.values().filter(|m| **m >= n).count();
+            if replicas >= quorum {
+                self.commit_index = n;
+                break;
+            }
+            n -= 1;
+        }
+        self.apply_committed();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let Some(entry) = self.log.entry(index).cloned() else {
+                break;
+            };
+            let mut response = None;
+            match entry.command {
+                Command::App(command) => {
+                    let applied = self.state_machine.apply(index, &command);
+                    self.history.push_back((index, applied.clone()));
+                    let _ = self.applied_tx.send((index, applied.clone()));
+                    response = Some(applied);
+                }
+                // The voter set changed when these were appended.
+                Command::AddPeer { .. } => {}
+                Command::RemovePeer { id } => {
+                    // A leader removed from the voter set keeps leading until that's committed,
+                    // then leaves the rest to elect a leader among themselves.
+                    if id == self.node_id() && self.is_leader() {
+                        println!("No longer a voter; stepping down");
+                        self.convert_to_follower(self.current_term);
+                        self.leader_id = None;
+                    }
+                }
+                Command::Noop => {
+                    for barrier in self.barriers.remove(&index).unwrap_or_default() {
+                        let _ = barrier.send(());
+                    }
+                }
+                Command::SetFeatureVersion { version } => self.set_feature_version(version),
+            }
+            // The entry applied here is the one proposed only if it's from the same term.
+            if let Some(waiter) = self.waiters.remove(&index) {
+                let result = match response {
+                    Some(response) if waiter.term == entry.term => Ok(response),
+                    _ => Err(ProposeError::Overwritten),
+                };
+                let _ = waiter.tx.send(result);
+            }
+            self.last_applied = index;
+        }
+        self.maybe_snapshot();
+    }
+
+    fn set_feature_version(&mut self, version: u32) {
+        if version <= self.feature_version {
+            return;
+        }
+        if version > FEATURE_VERSION {
+            eprintln!(
+                "Cluster feature version raised to {version}, above the {FEATURE_VERSION} this node supports"
+            );
+        }
+        println!("Cluster feature version is now {version}");
+        self.feature_version = version;
+    }
+
+    /// While leader, appends a `SetFeatureVersion` once every node it replicates to supports a
+    /// version above the cluster's, unless one is already on its way.
+    fn raise_feature_version(&mut self) {
+        let ServerState::Leader { next_index, .. } = &self.current_state else {
+            return;
+        };
+        let supported = next_index
+            .keys()
+            .map(|peer| self.supported_versions.get(peer).copied())
+            .try_fold(FEATURE_VERSION, |lowest, version| {
+                version.map(|version| lowest.min(version))
+            });
+        let Some(supported) = supported else {
+            return;
+        };
+        let pending = (self.last_applied + 1..=self.log.last_index()).any(|index| {
+            self.log
+                .entry(index)
+                .is_some_and(|entry| matches!(entry.command, Command::SetFeatureVersion { .. }))
+        });
+        if supported > self.feature_version && !pending {
+            println!("Every node supports feature version {supported}; raising it");
+            self.log.update_log(
+                self.current_term,
+                Command::SetFeatureVersion { version: supported },
+            );
+        }
+    }
+
+    fn add_peer(&mut self, peer: Peer) {
+        let id = peer.id;
+        self.cluster.add_peer(peer);
+        let last_index = self.log.last_index();
+        if id != self.node_id()
+            && let ServerState::Leader {
+                next_index,
+                match_index,
+            } = &mut self.current_state
+        {
+            next_index.entry(id).or_insert(last_index + 1);
+            match_index.entry(id).or_insert(0);
+        }
+    }
+
+    fn remove_peer(&mut self, id: &NodeId) {
+        self.cluster.remove_peer(id);
+        if let ServerState::Leader {
+            next_index,
+            match_index,
+        } = &mut self.current_state
+        {
+            next_index.remove(id);
+            match_index.remove(id);
+        }
+    }
+
+    /// Appends an entry from the leader. A membership change takes effect straight away.
+    fn append(&mut self, entry: LogEntry<S::Command>) {
+        let command = entry.command.clone();
+        self.log.push(entry);
+        self.change_membership(command);
+    }
+
+    fn change_membership(&mut self, command: Command<S::Command>) {
+        match command {
+            Command::AddPeer { peer } => self.add_peer(peer),
+            Command::RemovePeer { id } => self.remove_peer(&id),
+            _ => {}
+        }
+    }
+
+    /// The voter set once the entries up to and including `index` are in effect.
+    fn peers_through(&self, index: u32) -> Vec<Peer> {
+        let mut cluster = self.cluster.clone();
+        cluster.restore_peers(self.base_peers.clone());
+        for entry in self
+            .log
+            .entries
+            .iter()
+            .take_while(|entry| entry.index <= index)
+        {
+            match &entry.command {
+                Command::AddPeer { peer } => cluster.add_peer(peer.clone()),
+                Command::RemovePeer { id } => cluster.remove_peer(id),
+                _ => {}
+            }
+        }
+        cluster.known_peers()
+    }
+
+    /// Drops the entry at `index` and everything after it, undoing any membership change among
+    /// them.
+    fn truncate_from(&mut self, index: u32) {
+        self.log.truncate_from(index);
+        let peers = self.peers_through(self.log.last_index());
+        self.cluster.restore_peers(peers);
+        let overwritten: Vec<u32> = self
+            .waiters
+            .keys()
+            .filter(|waiting| **waiting >= index)
+            .copied()
+            .collect();
+        for waiting in overwritten {
+            if let Some(waiter) = self.waiters.remove(&waiting) {
+                let _ = waiter.tx.send(Err(ProposeError::Overwritten));
+            }
+        }
+        self.barriers.retain(|waiting, _| *waiting < index);
+    }
+
+    fn maybe_snapshot(&mut self) {
+        if self.last_applied - self.log.snapshot_index < self.config.snapshot_threshold {
+            return;
+        }
+        match self.state_machine.snapshot() {
+            Ok(data) => {
+                let last_term = self.log.term_at(self.last_applied).unwrap_or_default();
+                let peers = self.peers_through(self.last_applied);
+                self.base_peers = peers.clone();
+                self.snapshot = Some(Snapshot {
+                    last_index: self.last_applied,
+                    last_term,
+                    peers,
+                    feature_version: self.feature_version,
+                    data,
+                });
+                self.log.compact_through(self.last_applied);
+                let compacted = self.log.snapshot_index;
+                self.history.retain(|(index, _)| *index > compacted);
+                self.snapshot_error = None;
+            }
+            Err(e) => {
+                eprintln!("Couldn't snapshot state machine: {e}");
+                self.snapshot_error = Some(format!("couldn't snapshot state machine: {e}"));
+            }
+        }
+    }
+
+    /// Entries appended but not yet known to be committed.
+    pub fn uncommitted(&self) -> u32 {
+        self.log.last_index().saturating_sub(self.commit_index)
+    }
+
+    /// The cluster's feature version as this node has applied it.
+    pub fn feature_version(&self) -> u32 {
+        self.feature_version
+    }
+
+    pub fn max_uncommitted(&self) -> u32 {
+        self.config.max_uncommitted_entries
+    }
+
+    /// Proposals and read barriers waiting to be applied.
+    pub fn waiting(&self) -> usize {
+        self.waiters.len() + self.barriers.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// How many entries each follower is missing, while leader.
+    pub fn follower_lag(&self) -> Vec<(NodeId, u32)> {
+        let ServerState::Leader { match_index, .. } = &self.current_state else {
+            return Vec::new();
+        };
+        let last_index = self.log.last_index();
+        let mut lag: Vec<_> = match_index
+            .iter()
+            .map(|(peer, matched)| (*peer, last_index.saturating_sub(*matched)))
+            .collect();
+        lag.sort();
+        lag
+    }
+
+    /// Fails unless this node leads and has room for another uncommitted entry. Entries only
+    /// leave the uncommitted tail once a quorum stores them, so the limit keeps a slow quorum
+    /// from growing the leader's log without bound.
+    fn ensure_room(&self) -> Result<(), ProposeError> {
+        self.ensure_leader()?;
+        if let Some((target, _)) = self.transferring_to {
+            return Err(ProposeError::NotLeader(Some(target)));
+        }
+        if self.uncommitted() >= self.config.max_uncommitted_entries {
+            return Err(ProposeError::Backlogged);
+        }
+        Ok(())
+    }
+
+    /// Appends a state machine command, returning a future for its response once applied.
+    pub fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Response>, ProposeError> {
+        self.ensure_room()?;
+        let required = S::required_version(&command);
+        if required > self.feature_version {
+            return Err(ProposeError::FeatureNotEnabled {
+                required,
+                active: self.feature_version,
+            });
         }
+        let index = self
+            .log
+            .update_log(self.current_term, Command::App(command));
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter {
+            term: self.current_term,
+            tx,
+        };
+        self.waiters.insert(index, waiter);
+        self.advance_commit_index();
+        Ok(Proposal { rx })
+    }
+
+    /// Runs the state machine's `flush` hook. The log itself is only held in memory.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.state_machine.flush()
+    }
+
+    /// Why the last snapshot taken or installed failed, unless one has succeeded since. With the
+    /// log only held in memory, snapshots are the only storage there is to fail.
+    pub fn snapshot_error(&self) -> Option<&str> {
+        self.snapshot_error.as_deref()
+    }
+
+    /// The responses applied after `after`, and a receiver for those applied from now on. Fails
+    /// with the snapshot index if history from `after` onwards has been compacted away.
+    pub fn watch(&self, after: u32) -> Result<Watcher<S>, u32> {
+        if after < self.log.snapshot_index {
+            return Err(self.log.snapshot_index);
+        }
+        let backlog = self
+            .history
+            .iter()
+            .filter(|(index, _)| *index > after)
+            .cloned()
+            .collect();
+        Ok(Watcher {
+            backlog,
+            applied: self.applied_tx.subscribe(),
+            compacted: self.installed_tx.subscribe(),
+        })
+    }
+
+    /// A receiver for the responses applied from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Applied<S>> {
+        self.applied_tx.subscribe()
+    }
+
+    /// Appends a `Noop` whose receiver fires once it's applied. Everything committed before the
+    /// barrier was appended has been applied by then, so a read made afterwards is linearizable;
+    /// if leadership is lost first, the sender is dropped instead.
+    ///
+    /// Reads that arrive while a barrier is still uncommitted share it rather than append their
+    /// own. Nothing can commit ahead of it, so it covers them just as well, and a burst of reads
+    /// takes up one slot of `max_uncommitted_entries` rather than one each.
+    pub fn read_barrier(&mut self) -> Result<oneshot::Receiver<()>, ProposeError> {
+        self.ensure_leader()?;
+        let pending = self
+            .barriers
+            .keys()
+            .copied()
+            .max()
+            .filter(|index| *index > self.commit_index);
+        let index = match pending {
+            Some(index) => index,
+            None => {
+                self.ensure_room()?;
+                self.log.update_log(self.current_term, Command::Noop)
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        self.barriers.entry(index).or_default().push(tx);
+        self.advance_commit_index();
+        Ok(rx)
+    }
+
+    /// Appends an `AddPeer` or `RemovePeer` command, which takes effect as soon as it's in the
+    /// log. Changing one voter at a time keeps any two majorities overlapping, but only if each
+    /// change is committed before the next starts, and only once this leader has committed an
+    /// entry of its own term, so it isn't building on a change an earlier leader left behind.
+    pub fn propose_membership_change(
+        &mut self,
+        command: Command<S::Command>,
+    ) -> Result<u32, ProposeError> {
+        self.ensure_leader()?;
+        let pending = (self.commit_index + 1..=self.log.last_index()).any(|index| {
+            self.log.entry(index).is_some_and(|entry| {
+                matches!(
+                    entry.command,
+                    Command::AddPeer { .. } | Command::RemovePeer { .. }
+                )
+            })
+        });
+        if pending || self.log.term_at(self.commit_index) != Some(self.current_term) {
+            return Err(ProposeError::MembershipChangePending);
+        }
+        let index = self.log.update_log(self.current_term, command.clone());
+        self.change_membership(command);
+        self.advance_commit_index();
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+pub mod sim;
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    /// Adds each command to a running total.
+    #[derive(Default, Serialize, Deserialize)]
+    struct Counter {
+        total: u32,
+    }
+
+    impl StateMachine for Counter {
+        type Command = u32;
+        type Response = u32;
+
+        fn required_version(_: &u32) -> u32 {
+            BASE_FEATURE_VERSION
+        }
+
+        fn apply(&mut self, _: u32, command: &u32) -> u32 {
+            self.total += command;
+            self.total
+        }
+
+        fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(serde_json::to_vec(self)?)
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+            *self = serde_json::from_slice(snapshot)?;
+            Ok(())
+        }
+    }
+
+    type Node = RaftState<Counter>;
+    type Net = sim::Net<Counter>;
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            // Lets a node vote for a candidate right after hearing from the leader.
+            election_timeout_min_ms: 0,
+            ..RaftConfig::default()
+        }
+    }
+
+    fn node(id: u32, voters: u32, config: RaftConfig) -> Node {
+        sim::node(id, voters, config)
+    }
+
+    #[test]
+    fn elects_a_leader_with_a_majority_of_votes() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        assert!(net.node(0).is_leader());
+        // Followers learn the commit index from the next heartbeat.
+        net.heartbeat(0);
+        for id in 0..3 {
+            assert_eq!(net.node(id).current_term, 1);
+            assert_eq!(net.node(id).leader_id, Some(NodeId(0)));
+        }
+        // The new leader's no-op commits everywhere.
+        for id in 0..3 {
+            assert_eq!(net.node(id).commit_index, 1);
+        }
+    }
+
+    #[test]
+    fn candidate_without_a_majority_stays_candidate() {
+        let mut net = Net::new(3, config());
+        net.cut.extend([NodeId(1), NodeId(2)]);
+        net.campaign(0);
+        assert!(matches!(
+            net.node(0).current_state,
+            ServerState::Candidate { .. }
+        ));
+    }
+
+    #[test]
+    fn votes_once_per_term() {
+        let mut voter = node(0, 3, config());
+        let (_, granted) = voter.handle_request_vote(1, NodeId(1), 0, 0, false);
+        assert!(granted);
+        let (_, granted) = voter.handle_request_vote(1, NodeId(2), 0, 0, false);
+        assert!(!granted);
+        // The same candidate asking again, say after a lost reply, gets the vote again.
+        let (_, granted) = voter.handle_request_vote(1, NodeId(1), 0, 0, false);
+        assert!(granted);
+    }
+
+    #[test]
+    fn refuses_votes_to_candidates_with_stale_logs() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        net.cut.insert(NodeId(2));
+        drop(net.propose(0, 5));
+        net.cut.clear();
+        net.cut.insert(NodeId(0));
+        // Node 1 has the entry node 2 lacks, so node 2 can't win.
+        net.campaign(2);
+        assert!(!net.node(2).is_leader());
+        net.campaign(1);
+        assert!(net.node(1).is_leader());
+    }
+
+    #[test]
+    fn truncates_conflicting_entries() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        // Node 0 appends entries nobody else gets, then loses leadership.
+        net.cut.insert(NodeId(0));
+        let lost = net.propose(0, 5);
+        let peer = Peer {
+            id: NodeId(3),
+            addr: String::new(),
+        };
+        net.node(0)
+            .propose_membership_change(Command::AddPeer { peer })
+            .unwrap();
+        assert!(net.node(0).cluster.peers.contains(&NodeId(3)));
+        assert_eq!(net.terms(0), vec![1, 1, 1]);
+
+        net.campaign(1);
+        let kept = net.propose(1, 7);
+        net.cut.clear();
+        net.heartbeat(1);
+
+        assert!(!net.node(0).is_leader());
+        assert_eq!(net.terms(0), net.terms(1));
+        assert_eq!(net.terms(0), vec![1, 2, 2]);
+        assert!(matches!(
+            lost.now_or_never(),
+            Some(Err(ProposeError::Overwritten))
+        ));
+        assert!(matches!(kept.now_or_never(), Some(Ok(7))));
+        // The membership change went with the entries it was in.
+        assert!(!net.node(0).cluster.peers.contains(&NodeId(3)));
+        assert_eq!(net.node(0).state_machine.total, 7);
+    }
+
+    #[test]
+    fn commits_earlier_terms_only_through_the_current_term() {
+        // One entry per AppendEntries, so replication can be stopped between entries.
+        let mut net = Net::new(
+            3,
+            RaftConfig {
+                max_append_entries: 1,
+                ..config()
+            },
+        );
+        net.campaign(0);
+        net.cut.extend([NodeId(1), NodeId(2)]);
+        drop(net.propose(0, 5));
+        net.cut.clear();
+
+        // A reply from a later term pushes node 0 into term 2 as a follower, so it wins term 3
+        // with its longer log and appends its no-op at index 3.
+        net.node(0)
+            .handle_append_entries_response(NodeId(1), 2, false, 0, BASE_FEATURE_VERSION);
+        assert!(!net.node(0).is_leader());
+        let messages = net.node(0).handle_missed_heartbeat();
+        net.send(NodeId(0), messages);
+        while net.step() {
+            let ServerState::Leader { match_index, .. } = &net.node(0).current_state else {
+                continue;
+            };
+            if match_index.values().any(|matched| *matched >= 2) {
+                break;
+            }
+        }
+        assert!(net.node(0).is_leader());
+        assert_eq!(net.node(0).log.term_at(2), Some(1));
+        // Index 2 is on a majority, but it's from term 1, so it can't commit on its own.
+        assert_eq!(net.node(0).commit_index, 1);
+
+        net.run();
+        assert_eq!(net.terms(0), vec![1, 1, 3]);
+        assert_eq!(net.node(0).commit_index, 3);
+        assert_eq!(net.node(0).state_machine.total, 5);
+    }
+
+    #[test]
+    fn installs_a_snapshot_on_a_follower_behind_the_log() {
+        let mut net = Net::new(
+            3,
+            RaftConfig {
+                snapshot_threshold: 2,
+                ..config()
+            },
+        );
+        net.campaign(0);
+        net.cut.insert(NodeId(2));
+        let mut watcher = net.node(2).watch(0).ok().unwrap();
+        for command in 1..=4 {
+            drop(net.propose(0, command));
+        }
+        assert!(net.node(0).log.snapshot_index > 0);
+        net.cut.clear();
+        net.heartbeat(0);
+
+        let follower = net.node(2);
+        // The watcher never saw the entries the snapshot skipped over.
+        assert!(watcher.compacted.has_changed().unwrap());
+        assert_eq!(
+            *watcher.compacted.borrow_and_update(),
+            follower.log.snapshot_index
+        );
+        assert_eq!(follower.state_machine.total, 10);
+        assert_eq!(follower.last_applied(), 5);
+        assert!(follower.cluster.voter);
+        assert!(follower.cluster.peers.contains(&NodeId(0)));
+        assert!(follower.cluster.peers.contains(&NodeId(1)));
+    }
+
+    #[test]
+    fn watching_from_compacted_history_fails() {
+        let mut net = Net::new(
+            3,
+            RaftConfig {
+                snapshot_threshold: 2,
+                ..config()
+            },
+        );
+        net.campaign(0);
+        for command in 1..=4 {
+            drop(net.propose(0, command));
+        }
+        let leader = net.node(0);
+        let compacted = leader.log.snapshot_index;
+        assert!(compacted > 0);
+        assert!(matches!(leader.watch(compacted - 1), Err(index) if index == compacted));
+        let watcher = leader.watch(compacted).ok().unwrap();
+        let backlog: Vec<u32> = watcher.backlog.iter().map(|(index, _)| *index).collect();
+        let expected: Vec<u32> = (compacted + 1..=leader.last_applied()).collect();
+        assert_eq!(backlog, expected);
+    }
+
+    #[test]
+    fn refuses_proposals_past_the_uncommitted_limit() {
+        let mut net = Net::new(
+            3,
+            RaftConfig {
+                max_uncommitted_entries: 3,
+                ..config()
+            },
+        );
+        net.campaign(0);
+        net.cut.extend([NodeId(1), NodeId(2)]);
+        let leader = net.node(0);
+        let limit = leader.max_uncommitted();
+        while leader.uncommitted() < limit {
+            drop(leader.propose(1));
+        }
+        assert!(matches!(leader.propose(1), Err(ProposeError::Backlogged)));
+        assert!(matches!(
+            leader.read_barrier(),
+            Err(ProposeError::Backlogged)
+        ));
+
+        // Once a quorum catches up, there's room again.
+        net.cut.clear();
+        net.heartbeat(0);
+        assert_eq!(net.node(0).uncommitted(), 0);
+        assert!(net.node(0).propose(1).is_ok());
+    }
+
+    #[test]
+    fn concurrent_reads_share_a_barrier() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        net.cut.extend([NodeId(1), NodeId(2)]);
+        let leader = net.node(0);
+        let before = leader.uncommitted();
+        let mut first = leader.read_barrier().unwrap();
+        let mut second = leader.read_barrier().unwrap();
+        assert_eq!(leader.uncommitted(), before + 1);
+        assert_eq!(leader.waiting(), 2);
+
+        net.cut.clear();
+        net.heartbeat(0);
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+
+        // Once that one has committed, a later read needs a barrier of its own.
+        let leader = net.node(0);
+        let before = leader.uncommitted();
+        drop(leader.read_barrier().unwrap());
+        assert_eq!(leader.uncommitted(), before + 1);
+    }
+
+    #[test]
+    fn allows_one_membership_change_at_a_time() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        net.cut.extend([NodeId(1), NodeId(2)]);
+        let peer = Peer {
+            id: NodeId(3),
+            addr: String::new(),
+        };
+        net.node(0)
+            .propose_membership_change(Command::AddPeer { peer })
+            .unwrap();
+        let removal = net
+            .node(0)
+            .propose_membership_change(Command::RemovePeer { id: NodeId(2) });
+        assert!(matches!(
+            removal,
+            Err(ProposeError::MembershipChangePending)
+        ));
+        // Four voters now, so three replicas are needed.
+        assert_eq!(net.node(0).cluster.quorum(), 3);
+    }
+
+    #[test]
+    fn hands_off_leadership_only_once_every_node_understands_timeout_now() {
+        let mut net = Net::new(3, config());
+        net.campaign(0);
+        net.node(0).feature_version = BASE_FEATURE_VERSION;
+        assert!(net.node(0).transfer_leadership().is_none());
+
+        net.node(0).feature_version = TIMEOUT_NOW_VERSION;
+        let messages = net.node(0).transfer_leadership().unwrap();
+        assert!(matches!(
+            messages.as_slice(),
+            [Outbound {
+                msg: WSMessage::TimeoutNow { .. },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn non_voters_do_not_campaign() {
+        let mut outsider = node(3, 3, config());
+        assert!(!outsider.cluster.voter);
+        assert!(outsider.handle_missed_heartbeat().is_empty());
+        assert!(matches!(outsider.current_state, ServerState::Follower));
     }
 }
@@ -0,0 +1,191 @@
+//! A cluster of `RaftState`s wired together in memory, for tests.
+
+use std::collections::{HashSet, VecDeque};
+
+use super::super::super::config::RaftConfig;
+use super::super::super::websocket::shared::{Outbound, WSMessage};
+use super::super::cluster::Cluster;
+use super::super::log::FEATURE_VERSION;
+use super::super::shared::{NodeId, StatusInfo};
+use super::super::state_machine::StateMachine;
+use super::{Proposal, RaftState, Snapshot};
+
+/// A node of a `voters`-strong cluster, starting where a cluster of this build ends up, so no
+/// `SetFeatureVersion` entries appear.
+pub fn node<S: StateMachine + Default>(id: u32, voters: u32, config: RaftConfig) -> RaftState<S> {
+    let status_info = StatusInfo {
+        id: NodeId(id),
+        ..StatusInfo::default()
+    };
+    let cluster = Cluster::new(status_info, (0..voters).map(NodeId).collect());
+    let mut node = RaftState::new(cluster, S::default(), config, 16);
+    node.feature_version = FEATURE_VERSION;
+    node
+}
+
+/// Nodes exchanging messages in order, with some of them cut off from the rest.
+pub struct Net<S: StateMachine> {
+    pub nodes: Vec<RaftState<S>>,
+    pub queue: VecDeque<(NodeId, NodeId, WSMessage)>,
+    pub cut: HashSet<NodeId>,
+}
+
+impl<S: StateMachine + Default> Net<S> {
+    pub fn new(size: u32, config: RaftConfig) -> Self {
+        Self::at_version(size, config, FEATURE_VERSION)
+    }
+
+    /// A cluster whose nodes all start at `feature_version`, as after a rolling upgrade that
+    /// hasn't been committed yet.
+    pub fn at_version(size: u32, config: RaftConfig, feature_version: u32) -> Self {
+        let nodes = (0..size)
+            .map(|id| {
+                let mut node = node(id, size, config.clone());
+                node.feature_version = feature_version;
+                node
+            })
+            .collect();
+        Net {
+            nodes,
+            queue: VecDeque::new(),
+            cut: HashSet::new(),
+        }
+    }
+}
+
+impl<S: StateMachine> Net<S> {
+    pub fn node(&mut self, id: u32) -> &mut RaftState<S> {
+        &mut self.nodes[id as usize]
+    }
+
+    /// Takes a node out of the cluster, to be driven by something other than the simulator.
+    pub fn into_node(mut self, id: u32) -> RaftState<S> {
+        self.nodes.swap_remove(id as usize)
+    }
+
+    pub fn send(&mut self, from: NodeId, outbound: Vec<Outbound>) {
+        for out in outbound {
+            let targets: Vec<NodeId> = match out.to {
+                Some(to) => vec![to],
+                None => (0..self.nodes.len() as u32)
+                    .map(NodeId)
+                    .filter(|id| *id != from)
+                    .collect(),
+            };
+            for to in targets {
+                if !self.cut.contains(&from) && !self.cut.contains(&to) {
+                    self.queue.push_back((from, to, out.msg.clone()));
+                }
+            }
+        }
+    }
+
+    /// Delivers the next message, returning false once there are none.
+    pub fn step(&mut self) -> bool {
+        let Some((_, to, msg)) = self.queue.pop_front() else {
+            return false;
+        };
+        let node = &mut self.nodes[to.0 as usize];
+        let replies = match msg {
+            WSMessage::AppendEntries {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                node.handle_append_entries(
+                    term,
+                    leader_id,
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit,
+                )
+                .0
+            }
+            WSMessage::AppendEntriesResponse {
+                from,
+                term,
+                success,
+                match_index,
+                supported_version,
+            } => node.handle_append_entries_response(
+                from,
+                term,
+                success,
+                match_index,
+                supported_version,
+            ),
+            WSMessage::InstallSnapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                peers,
+                feature_version,
+                data,
+            } => {
+                let snapshot = Snapshot {
+                    last_index: last_included_index,
+                    last_term: last_included_term,
+                    peers,
+                    feature_version,
+                    data,
+                };
+                node.handle_install_snapshot(term, leader_id, snapshot).0
+            }
+            WSMessage::RequestVote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+                transfer,
+            } => {
+                node.handle_request_vote(
+                    term,
+                    candidate_id,
+                    last_log_index,
+                    last_log_term,
+                    transfer,
+                )
+                .0
+            }
+            WSMessage::RequestVoteResponse {
+                from,
+                term,
+                vote_granted,
+            } => node.handle_request_vote_response(from, term, vote_granted),
+            _ => Vec::new(),
+        };
+        self.send(to, replies);
+        true
+    }
+
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    pub fn campaign(&mut self, id: u32) {
+        let messages = self.node(id).handle_missed_heartbeat();
+        self.send(NodeId(id), messages);
+        self.run();
+    }
+
+    pub fn heartbeat(&mut self, id: u32) {
+        let messages = self.node(id).send_messages();
+        self.send(NodeId(id), messages);
+        self.run();
+    }
+
+    pub fn propose(&mut self, id: u32, command: S::Command) -> Proposal<S::Response> {
+        let proposal = self.node(id).propose(command).unwrap();
+        self.heartbeat(id);
+        proposal
+    }
+
+    pub fn terms(&mut self, id: u32) -> Vec<u32> {
+        self.node(id).log.entries.iter().map(|e| e.term).collect()
+    }
+}
@@ -1,34 +1,81 @@
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Stable identity of a node, independent of whatever address it currently has.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(transparent)]
+pub struct NodeId(pub u32);
+
+impl NodeId {
+    /// Derives an id from a StatefulSet pod name such as `whitewater-3`.
+    pub fn from_ordinal(name: &str) -> Option<NodeId> {
+        let (_, ordinal) = name.rsplit_once('-')?;
+        ordinal.parse().ok().map(NodeId)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(NodeId)
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 pub struct Peer {
-    pub ip: String,
+    pub id: NodeId,
+    pub addr: String,
+}
+
+/// Where each node can currently be reached. Addresses change as pods are rescheduled, so this
+/// is kept apart from anything that identifies a node.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AddressBook {
+    addrs: HashMap<NodeId, String>,
+}
+
+impl AddressBook {
+    /// Records an address, returning whether it changed.
+    pub fn insert(&mut self, id: NodeId, addr: String) -> bool {
+        self.addrs.insert(id, addr.clone()).as_ref() != Some(&addr)
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        self.addrs.remove(id);
+    }
+
+    pub fn resolve(&self, id: &NodeId) -> Option<&String> {
+        self.addrs.get(id)
+    }
 }
 
 #[derive(Serialize, Clone, Default)]
 pub struct StatusInfo {
+    pub id: NodeId,
     pub name: String,
     pub ip: String,
 }
 
-impl StatusInfo {
-    pub fn to_peer(&self) -> Peer {
-        Peer {
-            ip: self.ip.clone(),
-        }
-    }
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ServerState {
     Leader {
-        next_index: HashMap<Peer, u32>,
-        match_index: HashMap<Peer, u32>,
+        next_index: HashMap<NodeId, u32>,
+        match_index: HashMap<NodeId, u32>,
     },
     Follower,
     Candidate {
-        voted_for: HashSet<Peer>,
+        voted_for: HashSet<NodeId>,
     },
 }
 
@@ -37,21 +84,33 @@ impl ServerState {
         ServerState::Follower
     }
 
-    pub fn candidate(status_info: StatusInfo) -> ServerState {
+    pub fn candidate(status_info: &StatusInfo) -> ServerState {
         let mut voted_for = HashSet::new();
-        voted_for.insert(status_info.to_peer());
+        voted_for.insert(status_info.id);
         ServerState::Candidate { voted_for }
     }
 
-    pub fn leader(peers: Vec<Peer>, latest_applied: u32) -> ServerState {
-        let next_index: HashMap<Peer, u32> = peers
-            .iter()
-            .map(|p| (p.clone(), latest_applied + 1))
-            .collect();
-        let match_index: HashMap<Peer, u32> = peers.iter().map(|p| (p.clone(), 0)).collect();
+    pub fn leader(peers: &[NodeId], last_log_index: u32) -> ServerState {
+        let next_index: HashMap<NodeId, u32> =
+            peers.iter().map(|p| (*p, last_log_index + 1)).collect();
+        let match_index: HashMap<NodeId, u32> = peers.iter().map(|p| (*p, 0)).collect();
         ServerState::Leader {
             next_index,
             match_index,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_id_from_a_stateful_set_ordinal() {
+        assert_eq!(NodeId::from_ordinal("whitewater-3"), Some(NodeId(3)));
+        assert_eq!(NodeId::from_ordinal("white-water-12"), Some(NodeId(12)));
+        assert_eq!(NodeId::from_ordinal("whitewater"), None);
+        assert_eq!(NodeId::from_ordinal("whitewater-"), None);
+        assert_eq!(NodeId::from_ordinal("whitewater-a"), None);
+    }
+}
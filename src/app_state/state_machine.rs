@@ -1,54 +1,39 @@
+pub mod idempotency;
+pub mod kv;
+pub mod lease;
+pub mod session;
+pub mod store;
+pub mod txn;
 pub mod user;
 
-use std::collections::HashMap;
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt::Debug;
 
-use super::shared::{Peer, StatusInfo};
-use user::{CreateUserRequest, User};
+/// An application replicated through the Raft log.
+///
+/// Commands are applied in log order on every node, so `apply` must be deterministic: the same
+/// sequence of commands has to produce the same state and responses everywhere. Each command comes
+/// with its log index, which is the same on every node and so can serve as a revision. Snapshots
+/// let the log be compacted and bring far-behind followers up to date.
+pub trait StateMachine: Send + 'static {
+    type Command: Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static;
+    type Response: Clone + Debug + Send + 'static;
 
-#[derive(Clone)]
-pub struct StateMachine {
-    pub status_info: StatusInfo,
-    pub peers: Vec<Peer>,
-    pub users: HashMap<u32, User>,
-    pub next_id: u32,
-}
-
-impl StateMachine {
-    pub fn new(status_info: StatusInfo) -> Self {
-        StateMachine {
-            status_info: status_info.clone(),
-            peers: Vec::new(),
-            users: HashMap::new(),
-            next_id: 1,
-        }
-    }
-
-    pub fn add_peer(&mut self, peer: Peer) {
-        self.peers.push(peer);
-    }
+    /// The cluster feature version a command needs before it can be proposed. Each new kind of
+    /// command needs a version above every existing one; see `log::FEATURE_VERSION`.
+    fn required_version(command: &Self::Command) -> u32;
 
-    fn next_id(&mut self) -> u32 {
-        let id = self.next_id;
-        self.next_id += 1;
-        id
-    }
+    fn apply(&mut self, index: u32, command: &Self::Command) -> Self::Response;
 
-    pub fn create_user(&mut self, req: CreateUserRequest) -> User {
-        let id = self.next_id();
-        let user = User {
-            id,
-            name: req.name.clone(),
-            email: req.email.clone(),
-        };
-        self.users.insert(id, user.clone());
-        user
-    }
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>>;
 
-    pub fn get_user(&self, id: u32) -> Option<User> {
-        self.users.get(&id).cloned()
-    }
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()>;
 
-    pub fn list_users(&self) -> Vec<User> {
-        self.users.values().cloned().collect()
+    /// A hook for state machines backed by persistent storage to write out anything they've
+    /// held back, called once on shutdown after the last entry is applied. It guarantees nothing
+    /// by itself: `Store`, like the log, is only held in memory and is rebuilt from the cluster
+    /// after a restart, so it keeps this default.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
     }
 }
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::store::{StoreCommand, StoreResponse};
+
+/// A command sent with an `Idempotency-Key`. The leader stamps it with its clock and the
+/// configured window when proposing, so every replica forgets outcomes at the same point.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Idempotent {
+    pub key: String,
+    pub proposed_at_ms: u64,
+    pub window_ms: u64,
+    pub command: Box<StoreCommand>,
+}
+
+/// What a key was first used for, and what came of it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Outcome {
+    request: serde_json::Value,
+    response: StoreResponse,
+    expires_at_ms: u64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct IdempotencyStore {
+    outcomes: BTreeMap<String, Outcome>,
+    /// The same keys, ordered by when they're forgotten.
+    expiry: BTreeSet<(u64, String)>,
+}
+
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        IdempotencyStore::default()
+    }
+
+    /// Forgets every outcome whose window has passed as of `now_ms`.
+    fn evict(&mut self, now_ms: u64) {
+        while let Some((expires_at_ms, _)) = self.expiry.first()
+            && *expires_at_ms <= now_ms
+        {
+            if let Some((_, key)) = self.expiry.pop_first() {
+                self.outcomes.remove(&key);
+            }
+        }
+    }
+
+    /// The outcome already recorded under the command's key. Fails if the key was used for a
+    /// different request.
+    pub fn replay(
+        &mut self,
+        idempotent: &Idempotent,
+    ) -> Result<Option<StoreResponse>, StoreResponse> {
+        self.evict(idempotent.proposed_at_ms);
+        let Some(outcome) = self.outcomes.get(&idempotent.key) else {
+            return Ok(None);
+        };
+        if outcome.request != request_of(idempotent) {
+            return Err(StoreResponse::IdempotencyKeyReused);
+        }
+        Ok(Some(outcome.response.clone()))
+    }
+
+    pub fn record(&mut self, idempotent: &Idempotent, response: &StoreResponse) {
+        let expires_at_ms = idempotent
+            .proposed_at_ms
+            .saturating_add(idempotent.window_ms);
+        let outcome = Outcome {
+            request: request_of(idempotent),
+            response: response.clone(),
+            expires_at_ms,
+        };
+        if let Some(old) = self.outcomes.insert(idempotent.key.clone(), outcome) {
+            self.expiry
+                .remove(&(old.expires_at_ms, idempotent.key.clone()));
+        }
+        self.expiry.insert((expires_at_ms, idempotent.key.clone()));
+    }
+}
+
+fn request_of(idempotent: &Idempotent) -> serde_json::Value {
+    serde_json::to_value(&idempotent.command).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use super::super::StateMachine;
+    use super::super::store::Store;
+    use super::super::user::{UserCommand, UserResponse};
+    use super::*;
+    use crate::app_state::RequestError;
+
+    fn add(proposed_at_ms: u64, email: &str) -> StoreCommand {
+        StoreCommand::Idempotent(Idempotent {
+            key: "k".to_string(),
+            proposed_at_ms,
+            window_ms: 1000,
+            command: Box::new(StoreCommand::User(UserCommand::Add {
+                name: "a".to_string(),
+                email: email.to_string(),
+            })),
+        })
+    }
+
+    #[test]
+    fn the_same_request_gets_the_same_response() {
+        let mut store = Store::new();
+        let first = store.apply(1, &add(0, "a@example.com"));
+        assert!(matches!(
+            first,
+            StoreResponse::User(UserResponse::Created(_))
+        ));
+        let retry = store.apply(2, &add(500, "a@example.com"));
+        let StoreResponse::Replayed(replayed) = retry else {
+            panic!("not replayed: {retry:?}");
+        };
+        assert_eq!(
+            serde_json::to_value(&*replayed).unwrap(),
+            serde_json::to_value(&first).unwrap()
+        );
+        assert!(store.users.get_user(2).is_none());
+    }
+
+    #[test]
+    fn a_key_reused_for_another_request_is_rejected() {
+        let mut store = Store::new();
+        store.apply(1, &add(0, "a@example.com"));
+        assert!(matches!(
+            store.apply(2, &add(500, "b@example.com")),
+            StoreResponse::IdempotencyKeyReused
+        ));
+        assert!(store.users.get_user_by_email("b@example.com").is_none());
+        assert_eq!(
+            RequestError::IdempotencyKeyReused.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn a_key_is_forgotten_once_its_window_passes() {
+        let mut store = Store::new();
+        store.apply(1, &add(0, "a@example.com"));
+        assert!(matches!(
+            store.apply(2, &add(1000, "b@example.com")),
+            StoreResponse::User(UserResponse::Created(_))
+        ));
+        assert!(store.users.get_user_by_email("b@example.com").is_some());
+    }
+}
@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvEntry {
+    pub key: String,
+    pub value: String,
+    /// Log index of the last change to the key.
+    pub revision: u32,
+    /// Number of puts since the key was created.
+    pub version: u32,
+    /// The lease the key is attached to, if any; it's deleted when the lease ends.
+    pub lease: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct PutKvRequest {
+    pub value: String,
+    /// Compare-and-swap: only put if the key's revision is this, with 0 meaning it must not
+    /// exist yet.
+    pub expected_revision: Option<u32>,
+    /// Attaches the key to this lease. A put without one detaches it from any previous lease.
+    pub lease: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteKvQuery {
+    pub expected_revision: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KvCommand {
+    Put {
+        key: String,
+        value: String,
+        expected_revision: Option<u32>,
+        #[serde(default)]
+        lease: Option<u32>,
+    },
+    Delete {
+        key: String,
+        expected_revision: Option<u32>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KvResponse {
+    Put(KvEntry),
+    Deleted(KvEntry),
+    NotFound,
+    /// The compare-and-swap failed; carries the key's actual revision, 0 if it doesn't exist.
+    RevisionMismatch(u32),
+    /// The put named a lease that doesn't exist.
+    LeaseNotFound,
+}
+
+impl PutKvRequest {
+    pub fn to_command(&self, key: String) -> KvCommand {
+        KvCommand::Put {
+            key,
+            value: self.value.clone(),
+            expected_revision: self.expected_revision,
+            lease: self.lease,
+        }
+    }
+}
+
+impl KvCommand {
+    pub fn key(&self) -> &str {
+        match self {
+            KvCommand::Put { key, .. } | KvCommand::Delete { key, .. } => key,
+        }
+    }
+}
+
+impl DeleteKvQuery {
+    pub fn to_command(&self, key: String) -> KvCommand {
+        KvCommand::Delete {
+            key,
+            expected_revision: self.expected_revision,
+        }
+    }
+}
+
+/// A replicated key-value map, ordered by key.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct KvStore {
+    entries: BTreeMap<String, KvEntry>,
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        KvStore::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<KvEntry> {
+        self.entries.get(key).cloned()
+    }
+
+    pub fn revision_of(&self, key: &str) -> u32 {
+        self.entries.get(key).map_or(0, |entry| entry.revision)
+    }
+
+    fn check_revision(&self, key: &str, expected: Option<u32>) -> Result<(), KvResponse> {
+        let actual = self.revision_of(key);
+        match expected {
+            Some(expected) if expected != actual => Err(KvResponse::RevisionMismatch(actual)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes a key unconditionally, as when its lease ends.
+    pub fn remove(&mut self, key: &str) -> Option<KvEntry> {
+        self.entries.remove(key)
+    }
+
+    /// Puts an entry back exactly as it was, as when rolling back a transaction.
+    pub fn insert(&mut self, entry: KvEntry) {
+        self.entries.insert(entry.key.clone(), entry);
+    }
+
+    fn put(
+        &mut self,
+        index: u32,
+        key: &str,
+        value: &str,
+        expected: Option<u32>,
+        lease: Option<u32>,
+    ) -> KvResponse {
+        if let Err(mismatch) = self.check_revision(key, expected) {
+            return mismatch;
+        }
+        let version = self.entries.get(key).map_or(0, |entry| entry.version) + 1;
+        let entry = KvEntry {
+            key: key.to_string(),
+            value: value.to_string(),
+            revision: index,
+            version,
+            lease,
+        };
+        self.entries.insert(key.to_string(), entry.clone());
+        KvResponse::Put(entry)
+    }
+
+    fn delete(&mut self, key: &str, expected: Option<u32>) -> KvResponse {
+        if let Err(mismatch) = self.check_revision(key, expected) {
+            return mismatch;
+        }
+        match self.entries.remove(key) {
+            Some(entry) => KvResponse::Deleted(entry),
+            None => KvResponse::NotFound,
+        }
+    }
+
+    pub fn apply(&mut self, index: u32, command: &KvCommand) -> KvResponse {
+        match command {
+            KvCommand::Put {
+                key,
+                value,
+                expected_revision,
+                lease,
+            } => self.put(index, key, value, *expected_revision, *lease),
+            KvCommand::Delete {
+                key,
+                expected_revision,
+            } => self.delete(key, *expected_revision),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: &str, expected_revision: Option<u32>) -> KvCommand {
+        KvCommand::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected_revision,
+            lease: None,
+        }
+    }
+
+    fn delete(key: &str, expected_revision: Option<u32>) -> KvCommand {
+        KvCommand::Delete {
+            key: key.to_string(),
+            expected_revision,
+        }
+    }
+
+    #[test]
+    fn revisions_are_the_log_index_of_the_last_change() {
+        let mut kv = KvStore::new();
+        kv.apply(3, &put("a", "1", None));
+        kv.apply(7, &put("b", "1", None));
+        kv.apply(9, &put("a", "2", None));
+        let a = kv.get("a").unwrap();
+        assert_eq!((a.revision, a.version, a.value.as_str()), (9, 2, "2"));
+        assert_eq!(kv.revision_of("b"), 7);
+        assert_eq!(kv.revision_of("missing"), 0);
+    }
+
+    #[test]
+    fn a_put_against_a_stale_revision_fails() {
+        let mut kv = KvStore::new();
+        kv.apply(3, &put("a", "1", None));
+        kv.apply(4, &put("a", "2", Some(3)));
+        assert!(matches!(
+            kv.apply(5, &put("a", "3", Some(3))),
+            KvResponse::RevisionMismatch(4)
+        ));
+        assert_eq!(kv.get("a").unwrap().value, "2");
+    }
+
+    #[test]
+    fn revision_zero_only_creates() {
+        let mut kv = KvStore::new();
+        assert!(matches!(
+            kv.apply(3, &put("a", "1", Some(0))),
+            KvResponse::Put(_)
+        ));
+        assert!(matches!(
+            kv.apply(4, &put("a", "2", Some(0))),
+            KvResponse::RevisionMismatch(3)
+        ));
+        assert_eq!(kv.get("a").unwrap().value, "1");
+    }
+
+    #[test]
+    fn a_delete_checks_the_revision() {
+        let mut kv = KvStore::new();
+        kv.apply(3, &put("a", "1", None));
+        assert!(matches!(
+            kv.apply(4, &delete("a", Some(2))),
+            KvResponse::RevisionMismatch(3)
+        ));
+        assert!(kv.get("a").is_some());
+        assert!(matches!(
+            kv.apply(5, &delete("a", Some(3))),
+            KvResponse::Deleted(_)
+        ));
+        assert!(matches!(
+            kv.apply(6, &delete("a", Some(3))),
+            KvResponse::RevisionMismatch(0)
+        ));
+        assert!(matches!(
+            kv.apply(7, &delete("a", None)),
+            KvResponse::NotFound
+        ));
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::kv::KvEntry;
+
+/// A lease with the keys attached to it. Its id is the log index of the grant. The deadline
+/// isn't replicated: only the leader tracks it, and a new leader starts every lease afresh.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub id: u32,
+    pub ttl_ms: u64,
+    pub keys: BTreeSet<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GrantLeaseRequest {
+    pub ttl_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseCommand {
+    Grant {
+        ttl_ms: u64,
+    },
+    Revoke {
+        id: u32,
+    },
+    /// Proposed by the leader once a lease's deadline passes.
+    Expire {
+        id: u32,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseResponse {
+    Granted(Lease),
+    /// The lease is gone, along with every key attached to it.
+    Revoked {
+        lease: Lease,
+        deleted: Vec<KvEntry>,
+    },
+    NotFound,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LeaseStore {
+    leases: BTreeMap<u32, Lease>,
+}
+
+impl LeaseStore {
+    pub fn new() -> Self {
+        LeaseStore::default()
+    }
+
+    pub fn get(&self, id: u32) -> Option<Lease> {
+        self.leases.get(&id).cloned()
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.leases.contains_key(&id)
+    }
+
+    /// Every lease's id and TTL, for the leader's expiry tracking.
+    pub fn ttls(&self) -> Vec<(u32, u64)> {
+        self.leases
+            .values()
+            .map(|lease| (lease.id, lease.ttl_ms))
+            .collect()
+    }
+
+    pub fn grant(&mut self, id: u32, ttl_ms: u64) -> Lease {
+        let lease = Lease {
+            id,
+            ttl_ms,
+            keys: BTreeSet::new(),
+        };
+        self.leases.insert(id, lease.clone());
+        lease
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Lease> {
+        self.leases.remove(&id)
+    }
+
+    pub fn attach(&mut self, id: u32, key: &str) {
+        if let Some(lease) = self.leases.get_mut(&id) {
+            lease.keys.insert(key.to_string());
+        }
+    }
+
+    pub fn detach(&mut self, id: u32, key: &str) {
+        if let Some(lease) = self.leases.get_mut(&id) {
+            lease.keys.remove(key);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::store::{StoreCommand, StoreResponse};
+
+/// How many unacknowledged responses a session keeps. Past this, the oldest are forgotten as
+/// if the client had acknowledged them, so a client that never sends `Client-Acked` can't grow
+/// its session without bound.
+const MAX_SESSION_RESPONSES: usize = 1024;
+
+/// A client session, following the Raft dissertation's scheme for exactly-once semantics.
+/// Its id is the log index it was registered at. The session is bound to a lease and ends
+/// with it, so sessions expire through the lease's `Expire` entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: u32,
+    pub lease: u32,
+    /// The client has seen the responses to every sequence number up to this one.
+    acked: u64,
+    /// Responses to sequence numbers above `acked`, replayed if a command is retried.
+    responses: BTreeMap<u64, Reply>,
+}
+
+/// What a sequence number was first used for, and what came of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reply {
+    request: serde_json::Value,
+    response: StoreResponse,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterSessionRequest {
+    pub lease: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionCommand {
+    Register { lease: u32 },
+    Close { id: u32 },
+}
+
+/// A command sent within a session. Applying the same sequence number twice gives back the
+/// first response rather than running the command again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tracked {
+    pub client_id: u32,
+    pub sequence: u64,
+    /// Responses up to here have been received and can be forgotten.
+    pub acked: Option<u64>,
+    pub command: Box<StoreCommand>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionResponse {
+    Registered(Session),
+    Closed(Session),
+    /// The session never existed, was closed, or its lease ended.
+    NotFound,
+    LeaseNotFound,
+    /// The sequence number was already acknowledged, so its response is gone.
+    Stale {
+        acked: u64,
+    },
+    /// The sequence number was already used for a different command.
+    SequenceReused {
+        sequence: u64,
+    },
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SessionStore {
+    sessions: BTreeMap<u32, Session>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        SessionStore::default()
+    }
+
+    pub fn register(&mut self, id: u32, lease: u32) -> Session {
+        let session = Session {
+            id,
+            lease,
+            acked: 0,
+            responses: BTreeMap::new(),
+        };
+        self.sessions.insert(id, session.clone());
+        session
+    }
+
+    pub fn close(&mut self, id: u32) -> Option<Session> {
+        self.sessions.remove(&id)
+    }
+
+    /// Ends every session bound to a lease that has ended.
+    pub fn close_for_lease(&mut self, lease: u32) {
+        self.sessions.retain(|_, session| session.lease != lease);
+    }
+
+    /// The response already given to `tracked`, if it's a retry. Fails if the session is gone, the
+    /// response has been forgotten, or the sequence number was used for a different command.
+    /// Forgets the responses the client has acknowledged.
+    pub fn replay(&mut self, tracked: &Tracked) -> Result<Option<StoreResponse>, SessionResponse> {
+        let session = self
+            .sessions
+            .get_mut(&tracked.client_id)
+            .ok_or(SessionResponse::NotFound)?;
+        if let Some(acked) = tracked.acked
+            && acked > session.acked
+        {
+            session.acked = acked;
+            session.responses = session.responses.split_off(&(acked + 1));
+        }
+        if let Some(reply) = session.responses.get(&tracked.sequence) {
+            if reply.request != request_of(tracked) {
+                return Err(SessionResponse::SequenceReused {
+                    sequence: tracked.sequence,
+                });
+            }
+            return Ok(Some(reply.response.clone()));
+        }
+        if tracked.sequence <= session.acked {
+            return Err(SessionResponse::Stale {
+                acked: session.acked,
+            });
+        }
+        Ok(None)
+    }
+
+    pub fn record(&mut self, tracked: &Tracked, response: &StoreResponse) {
+        let Some(session) = self.sessions.get_mut(&tracked.client_id) else {
+            return;
+        };
+        let reply = Reply {
+            request: request_of(tracked),
+            response: response.clone(),
+        };
+        session.responses.insert(tracked.sequence, reply);
+        while session.responses.len() > MAX_SESSION_RESPONSES
+            && let Some((sequence, _)) = session.responses.pop_first()
+        {
+            session.acked = session.acked.max(sequence);
+        }
+    }
+}
+
+fn request_of(tracked: &Tracked) -> serde_json::Value {
+    serde_json::to_value(&tracked.command).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::StateMachine;
+    use super::super::lease::LeaseCommand;
+    use super::super::store::Store;
+    use super::super::user::{UserCommand, UserResponse};
+    use super::*;
+
+    /// A store with session 2, bound to lease 1.
+    fn store() -> Store {
+        let mut store = Store::new();
+        store.apply(
+            1,
+            &StoreCommand::Lease(LeaseCommand::Grant { ttl_ms: 1000 }),
+        );
+        store.apply(
+            2,
+            &StoreCommand::Session(SessionCommand::Register { lease: 1 }),
+        );
+        store
+    }
+
+    fn add(sequence: u64, acked: Option<u64>, email: &str) -> StoreCommand {
+        StoreCommand::Tracked(Tracked {
+            client_id: 2,
+            sequence,
+            acked,
+            command: Box::new(StoreCommand::User(UserCommand::Add {
+                name: "a".to_string(),
+                email: email.to_string(),
+            })),
+        })
+    }
+
+    #[test]
+    fn a_retry_gets_the_first_response_without_applying_again() {
+        let mut store = store();
+        let first = store.apply(3, &add(1, None, "a@example.com"));
+        assert!(matches!(
+            first,
+            StoreResponse::User(UserResponse::Created(_))
+        ));
+        let retry = store.apply(4, &add(1, None, "a@example.com"));
+        let StoreResponse::Replayed(replayed) = retry else {
+            panic!("not replayed: {retry:?}");
+        };
+        assert_eq!(
+            serde_json::to_value(&*replayed).unwrap(),
+            serde_json::to_value(&first).unwrap()
+        );
+        assert!(store.users.get_user(2).is_none());
+    }
+
+    #[test]
+    fn a_sequence_number_reused_for_another_command_is_rejected() {
+        let mut store = store();
+        store.apply(3, &add(1, None, "a@example.com"));
+        assert!(matches!(
+            store.apply(4, &add(1, None, "b@example.com")),
+            StoreResponse::Session(SessionResponse::SequenceReused { sequence: 1 })
+        ));
+        assert!(store.users.get_user_by_email("b@example.com").is_none());
+    }
+
+    #[test]
+    fn an_acknowledged_sequence_number_is_stale() {
+        let mut store = store();
+        store.apply(3, &add(1, None, "a@example.com"));
+        store.apply(4, &add(2, Some(1), "b@example.com"));
+        assert!(matches!(
+            store.apply(5, &add(1, None, "a@example.com")),
+            StoreResponse::Session(SessionResponse::Stale { acked: 1 })
+        ));
+    }
+
+    #[test]
+    fn the_oldest_responses_are_forgotten_past_the_limit() {
+        let mut sessions = SessionStore::new();
+        sessions.register(2, 1);
+        let tracked = |sequence| Tracked {
+            client_id: 2,
+            sequence,
+            acked: None,
+            command: Box::new(StoreCommand::User(UserCommand::Delete { id: 1 })),
+        };
+        let response = StoreResponse::User(UserResponse::NotFound);
+        let last = MAX_SESSION_RESPONSES as u64 + 1;
+        for sequence in 1..=last {
+            sessions.record(&tracked(sequence), &response);
+        }
+        assert!(matches!(
+            sessions.replay(&tracked(1)),
+            Err(SessionResponse::Stale { acked: 1 })
+        ));
+        assert!(matches!(sessions.replay(&tracked(2)), Ok(Some(_))));
+        assert!(matches!(sessions.replay(&tracked(last)), Ok(Some(_))));
+    }
+}
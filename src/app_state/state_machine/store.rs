@@ -0,0 +1,366 @@
+use serde::{Deserialize, Serialize};
+
+use super::super::log::{BASE_FEATURE_VERSION, SERVICES_VERSION};
+use super::StateMachine;
+use super::idempotency::{IdempotencyStore, Idempotent};
+use super::kv::{KvCommand, KvEntry, KvResponse, KvStore};
+use super::lease::{LeaseCommand, LeaseResponse, LeaseStore};
+use super::session::{SessionCommand, SessionResponse, SessionStore, Tracked};
+use super::txn::{Txn, TxnOp, TxnResponse};
+use super::user::{UserCommand, UserResponse, UserStore};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum StoreCommand {
+    User(UserCommand),
+    Kv(KvCommand),
+    Txn(Txn),
+    Lease(LeaseCommand),
+    Session(SessionCommand),
+    Tracked(Tracked),
+    Idempotent(Idempotent),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreResponse {
+    User(UserResponse),
+    Kv(KvResponse),
+    Txn(TxnResponse),
+    Lease(LeaseResponse),
+    Session(SessionResponse),
+    /// A retried command's original response. It made no changes this time round.
+    Replayed(Box<StoreResponse>),
+    /// The idempotency key was already used for a different request.
+    IdempotencyKeyReused,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    User,
+    Kv,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Put,
+    Delete,
+}
+
+/// A change to one user or key, as reported to watchers. `value` is the state after a put, or
+/// the last state before a delete.
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub resource: Resource,
+    pub key: String,
+    pub kind: ChangeKind,
+    pub value: serde_json::Value,
+}
+
+impl Change {
+    fn new(resource: Resource, key: String, kind: ChangeKind, value: &impl Serialize) -> Self {
+        Change {
+            resource,
+            key,
+            kind,
+            value: serde_json::to_value(value).unwrap_or_default(),
+        }
+    }
+}
+
+impl StoreResponse {
+    /// The changes the command made; one that failed made none.
+    pub fn changes(&self) -> Vec<Change> {
+        match self {
+            StoreResponse::User(UserResponse::Created(user))
+            | StoreResponse::User(UserResponse::Updated(user)) => vec![Change::new(
+                Resource::User,
+                user.id.to_string(),
+                ChangeKind::Put,
+                user,
+            )],
+            StoreResponse::User(UserResponse::Deleted(user)) => vec![Change::new(
+                Resource::User,
+                user.id.to_string(),
+                ChangeKind::Delete,
+                user,
+            )],
+            StoreResponse::Kv(KvResponse::Put(entry)) => vec![Change::new(
+                Resource::Kv,
+                entry.key.clone(),
+                ChangeKind::Put,
+                entry,
+            )],
+            StoreResponse::Kv(KvResponse::Deleted(entry)) => vec![Change::new(
+                Resource::Kv,
+                entry.key.clone(),
+                ChangeKind::Delete,
+                entry,
+            )],
+            StoreResponse::Txn(TxnResponse::Committed { responses, .. }) => {
+                responses.iter().flat_map(|r| r.changes()).collect()
+            }
+            StoreResponse::Lease(LeaseResponse::Revoked { deleted, .. }) => deleted
+                .iter()
+                .map(|entry| {
+                    Change::new(Resource::Kv, entry.key.clone(), ChangeKind::Delete, entry)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Everything whitewater replicates: each service's state machine, side by side in one log.
+/// Services added after the first start out empty when restoring a snapshot from before them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Store {
+    pub users: UserStore,
+    #[serde(default)]
+    pub kv: KvStore,
+    #[serde(default)]
+    pub leases: LeaseStore,
+    #[serde(default)]
+    pub sessions: SessionStore,
+    #[serde(default)]
+    pub idempotency: IdempotencyStore,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Store::new()
+    }
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Store {
+            users: UserStore::new(),
+            kv: KvStore::new(),
+            leases: LeaseStore::new(),
+            sessions: SessionStore::new(),
+            idempotency: IdempotencyStore::new(),
+        }
+    }
+
+    pub fn apply_op(&mut self, index: u32, op: &TxnOp) -> StoreResponse {
+        match op {
+            TxnOp::User(command) => StoreResponse::User(self.users.apply(command)),
+            TxnOp::Kv(command) => StoreResponse::Kv(self.apply_kv(index, command)),
+        }
+    }
+
+    /// Puts `key` back as it was, `None` meaning it didn't exist, along with the lease it was
+    /// attached to.
+    pub fn revert_kv(&mut self, key: &str, previous: Option<KvEntry>) {
+        if let Some(KvEntry {
+            lease: Some(lease), ..
+        }) = self.kv.remove(key)
+        {
+            self.leases.detach(lease, key);
+        }
+        if let Some(entry) = previous {
+            if let Some(lease) = entry.lease {
+                self.leases.attach(lease, key);
+            }
+            self.kv.insert(entry);
+        }
+    }
+
+    /// Applies a kv command, keeping each lease's set of attached keys in step with it.
+    fn apply_kv(&mut self, index: u32, command: &KvCommand) -> KvResponse {
+        if let KvCommand::Put {
+            lease: Some(lease), ..
+        } = command
+            && !self.leases.contains(*lease)
+        {
+            return KvResponse::LeaseNotFound;
+        }
+        let previous = self.kv.get(command.key()).and_then(|entry| entry.lease);
+        let response = self.kv.apply(index, command);
+        if let KvResponse::Put(_) | KvResponse::Deleted(_) = response
+            && let Some(previous) = previous
+        {
+            self.leases.detach(previous, command.key());
+        }
+        if let KvResponse::Put(KvEntry {
+            lease: Some(lease), ..
+        }) = &response
+        {
+            self.leases.attach(*lease, command.key());
+        }
+        response
+    }
+
+    /// Revocation and expiry end a lease the same way, deleting every key attached to it.
+    fn apply_lease(&mut self, index: u32, command: &LeaseCommand) -> LeaseResponse {
+        match command {
+            LeaseCommand::Grant { ttl_ms } => {
+                LeaseResponse::Granted(self.leases.grant(index, *ttl_ms))
+            }
+            LeaseCommand::Revoke { id } | LeaseCommand::Expire { id } => {
+                match self.leases.remove(*id) {
+                    Some(lease) => {
+                        self.sessions.close_for_lease(lease.id);
+                        let deleted = lease
+                            .keys
+                            .iter()
+                            .filter_map(|key| self.kv.remove(key))
+                            .collect();
+                        LeaseResponse::Revoked { lease, deleted }
+                    }
+                    None => LeaseResponse::NotFound,
+                }
+            }
+        }
+    }
+
+    fn apply_session(&mut self, index: u32, command: &SessionCommand) -> SessionResponse {
+        match command {
+            SessionCommand::Register { lease } if self.leases.contains(*lease) => {
+                SessionResponse::Registered(self.sessions.register(index, *lease))
+            }
+            SessionCommand::Register { .. } => SessionResponse::LeaseNotFound,
+            SessionCommand::Close { id } => match self.sessions.close(*id) {
+                Some(session) => SessionResponse::Closed(session),
+                None => SessionResponse::NotFound,
+            },
+        }
+    }
+
+    fn apply_tracked(&mut self, index: u32, tracked: &Tracked) -> StoreResponse {
+        match self.sessions.replay(tracked) {
+            Ok(Some(response)) => StoreResponse::Replayed(Box::new(response)),
+            Ok(None) => {
+                let response = self.apply(index, &tracked.command);
+                self.sessions.record(tracked, &response);
+                response
+            }
+            Err(response) => StoreResponse::Session(response),
+        }
+    }
+
+    fn apply_idempotent(&mut self, index: u32, idempotent: &Idempotent) -> StoreResponse {
+        match self.idempotency.replay(idempotent) {
+            Ok(Some(response)) => StoreResponse::Replayed(Box::new(response)),
+            Ok(None) => {
+                let response = self.apply(index, &idempotent.command);
+                // A command replayed by its session is remembered by what it first answered.
+                let outcome = match &response {
+                    StoreResponse::Replayed(response) => response,
+                    response => response,
+                };
+                self.idempotency.record(idempotent, outcome);
+                response
+            }
+            Err(response) => response,
+        }
+    }
+}
+
+impl StateMachine for Store {
+    type Command = StoreCommand;
+    type Response = StoreResponse;
+
+    fn required_version(command: &StoreCommand) -> u32 {
+        match command {
+            StoreCommand::User(_) => BASE_FEATURE_VERSION,
+            StoreCommand::Kv(_)
+            | StoreCommand::Txn(_)
+            | StoreCommand::Lease(_)
+            | StoreCommand::Session(_) => SERVICES_VERSION,
+            StoreCommand::Tracked(tracked) => {
+                Self::required_version(&tracked.command).max(SERVICES_VERSION)
+            }
+            StoreCommand::Idempotent(idempotent) => {
+                Self::required_version(&idempotent.command).max(SERVICES_VERSION)
+            }
+        }
+    }
+
+    fn apply(&mut self, index: u32, command: &StoreCommand) -> StoreResponse {
+        match command {
+            StoreCommand::User(command) => StoreResponse::User(self.users.apply(command)),
+            StoreCommand::Kv(command) => StoreResponse::Kv(self.apply_kv(index, command)),
+            StoreCommand::Txn(txn) => StoreResponse::Txn(txn.apply(index, self)),
+            StoreCommand::Lease(command) => StoreResponse::Lease(self.apply_lease(index, command)),
+            StoreCommand::Session(command) => {
+                StoreResponse::Session(self.apply_session(index, command))
+            }
+            StoreCommand::Tracked(tracked) => self.apply_tracked(index, tracked),
+            StoreCommand::Idempotent(idempotent) => self.apply_idempotent(index, idempotent),
+        }
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        *self = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_snapshots_from_before_later_services() {
+        let mut old = Store::new();
+        old.apply(
+            1,
+            &StoreCommand::User(UserCommand::Add {
+                name: "a".to_string(),
+                email: "a@example.com".to_string(),
+            }),
+        );
+        let mut snapshot = serde_json::to_value(&old).unwrap();
+        let services = snapshot.as_object_mut().unwrap();
+        for service in ["kv", "leases", "sessions", "idempotency"] {
+            services.remove(service);
+        }
+
+        let mut store = Store::new();
+        store.apply(
+            1,
+            &StoreCommand::Lease(LeaseCommand::Grant { ttl_ms: 1000 }),
+        );
+        store.apply(
+            2,
+            &StoreCommand::Session(SessionCommand::Register { lease: 1 }),
+        );
+        store.apply(
+            3,
+            &StoreCommand::Idempotent(Idempotent {
+                key: "k".to_string(),
+                proposed_at_ms: 0,
+                window_ms: 1000,
+                command: Box::new(StoreCommand::Kv(KvCommand::Put {
+                    key: "k".to_string(),
+                    value: "v".to_string(),
+                    expected_revision: None,
+                    lease: None,
+                })),
+            }),
+        );
+        assert!(store.kv.get("k").is_some());
+        assert!(store.leases.get(1).is_some());
+        store
+            .restore(&serde_json::to_vec(&snapshot).unwrap())
+            .unwrap();
+
+        let user = store.users.get_user(1).unwrap();
+        assert_eq!(user.email, "a@example.com");
+        let empty = serde_json::to_value(Store {
+            users: store.users.clone(),
+            ..Store::new()
+        })
+        .unwrap();
+        assert_eq!(serde_json::to_value(&store).unwrap(), empty);
+        assert!(store.kv.get("k").is_none());
+        assert!(store.leases.get(1).is_none());
+    }
+}
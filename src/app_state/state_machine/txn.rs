@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+
+use super::kv::{KvCommand, KvEntry, KvResponse};
+use super::lease::LeaseResponse;
+use super::session::SessionResponse;
+use super::store::{Store, StoreResponse};
+use super::user::{User, UserCommand, UserResponse};
+
+/// A condition checked against the state at the moment the transaction is applied.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    /// The key's revision equals `revision`, with 0 meaning it doesn't exist.
+    KvRevision {
+        key: String,
+        revision: u32,
+    },
+    KvValue {
+        key: String,
+        value: String,
+    },
+    UserExists {
+        id: u32,
+        exists: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxnOp {
+    User(UserCommand),
+    Kv(KvCommand),
+}
+
+/// `POST /txn`: if every comparison holds the success ops run, otherwise the failure ops do.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Txn {
+    #[serde(default)]
+    pub compare: Vec<Compare>,
+    #[serde(default)]
+    pub success: Vec<TxnOp>,
+    #[serde(default)]
+    pub failure: Vec<TxnOp>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxnResponse {
+    /// Every op in the branch took effect.
+    Committed {
+        succeeded: bool,
+        responses: Vec<StoreResponse>,
+    },
+    /// An op in the branch failed, so none of them took effect.
+    Aborted {
+        succeeded: bool,
+        failed_op: usize,
+        response: Box<StoreResponse>,
+    },
+}
+
+impl Compare {
+    fn holds(&self, store: &Store) -> bool {
+        match self {
+            Compare::KvRevision { key, revision } => store.kv.revision_of(key) == *revision,
+            Compare::KvValue { key, value } => {
+                store.kv.get(key).is_some_and(|entry| entry.value == *value)
+            }
+            Compare::UserExists { id, exists } => store.users.get_user(*id).is_some() == *exists,
+        }
+    }
+}
+
+/// What an op is about to change, as it was beforehand, so an aborted transaction can put it
+/// back.
+enum Undo {
+    User {
+        id: u32,
+        previous: Option<User>,
+        next_id: u32,
+    },
+    Kv {
+        key: String,
+        previous: Option<KvEntry>,
+    },
+}
+
+impl Undo {
+    fn revert(self, store: &mut Store) {
+        match self {
+            Undo::User {
+                id,
+                previous,
+                next_id,
+            } => store.users.revert(id, previous, next_id),
+            Undo::Kv { key, previous } => store.revert_kv(&key, previous),
+        }
+    }
+}
+
+impl TxnOp {
+    fn undo(&self, store: &Store) -> Undo {
+        match self {
+            TxnOp::User(command) => {
+                let next_id = store.users.upcoming_id();
+                let id = match command {
+                    UserCommand::Add { .. } => next_id,
+                    UserCommand::Update { id, .. } | UserCommand::Delete { id } => *id,
+                };
+                Undo::User {
+                    id,
+                    previous: store.users.get_user(id),
+                    next_id,
+                }
+            }
+            TxnOp::Kv(command) => Undo::Kv {
+                key: command.key().to_string(),
+                previous: store.kv.get(command.key()),
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            TxnOp::User(command) => command.validate(),
+            TxnOp::Kv(_) => Ok(()),
+        }
+    }
+}
+
+impl Txn {
+    pub fn validate(&self) -> Result<(), String> {
+        for op in self.success.iter().chain(&self.failure) {
+            op.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Applies the transaction to `store`, leaving it as it was unless the whole branch succeeds.
+    pub fn apply(&self, index: u32, store: &mut Store) -> TxnResponse {
+        let succeeded = self.compare.iter().all(|compare| compare.holds(store));
+        let ops = if succeeded {
+            &self.success
+        } else {
+            &self.failure
+        };
+        // Ops run against the store itself, so later ops see the effects of earlier ones as they
+        // would outside a transaction. What each one changed is noted first, and put back if a
+        // later op fails. A failed op changes nothing.
+        let mut undo = Vec::with_capacity(ops.len());
+        let mut responses = Vec::with_capacity(ops.len());
+        for (i, op) in ops.iter().enumerate() {
+            undo.push(op.undo(store));
+            let response = store.apply_op(index, op);
+            if !response.is_success() {
+                undo.pop();
+                for change in undo.into_iter().rev() {
+                    change.revert(store);
+                }
+                return TxnResponse::Aborted {
+                    succeeded,
+                    failed_op: i,
+                    response: Box::new(response),
+                };
+            }
+            responses.push(response);
+        }
+        TxnResponse::Committed {
+            succeeded,
+            responses,
+        }
+    }
+}
+
+impl StoreResponse {
+    pub fn is_success(&self) -> bool {
+        match self {
+            StoreResponse::User(response) => matches!(
+                response,
+                UserResponse::Created(_) | UserResponse::Updated(_) | UserResponse::Deleted(_)
+            ),
+            StoreResponse::Kv(response) => {
+                matches!(response, KvResponse::Put(_) | KvResponse::Deleted(_))
+            }
+            StoreResponse::Txn(TxnResponse::Committed { .. }) => true,
+            StoreResponse::Txn(TxnResponse::Aborted { .. }) => false,
+            StoreResponse::Lease(response) => !matches!(response, LeaseResponse::NotFound),
+            StoreResponse::Session(response) => matches!(
+                response,
+                SessionResponse::Registered(_) | SessionResponse::Closed(_)
+            ),
+            StoreResponse::Replayed(response) => response.is_success(),
+            StoreResponse::IdempotencyKeyReused => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::StateMachine;
+    use super::super::lease::LeaseCommand;
+    use super::super::store::StoreCommand;
+    use super::*;
+
+    fn put(key: &str, lease: Option<u32>) -> TxnOp {
+        TxnOp::Kv(KvCommand::Put {
+            key: key.to_string(),
+            value: "v".to_string(),
+            expected_revision: None,
+            lease,
+        })
+    }
+
+    #[test]
+    fn aborted_transactions_leave_the_store_as_it_was() {
+        let mut store = Store::new();
+        store.apply(
+            1,
+            &StoreCommand::Lease(LeaseCommand::Grant { ttl_ms: 1000 }),
+        );
+        let add = UserCommand::Add {
+            name: "a".to_string(),
+            email: "a@example.com".to_string(),
+        };
+        store.apply(2, &StoreCommand::User(add));
+        store.apply_op(3, &put("leased", Some(1)));
+        store.apply_op(4, &put("plain", None));
+        let before = store.snapshot().unwrap();
+
+        let txn = Txn {
+            compare: Vec::new(),
+            success: vec![
+                TxnOp::User(UserCommand::Update {
+                    id: 1,
+                    name: None,
+                    email: Some("b@example.com".to_string()),
+                }),
+                TxnOp::User(UserCommand::Add {
+                    name: "c".to_string(),
+                    email: "a@example.com".to_string(),
+                }),
+                put("leased", None),
+                put("plain", Some(1)),
+                put("new", Some(1)),
+                TxnOp::Kv(KvCommand::Delete {
+                    key: "new".to_string(),
+                    expected_revision: None,
+                }),
+                TxnOp::User(UserCommand::Delete { id: 1 }),
+                TxnOp::User(UserCommand::Delete { id: 1 }),
+            ],
+            failure: Vec::new(),
+        };
+        let response = txn.apply(5, &mut store);
+        assert!(matches!(
+            response,
+            TxnResponse::Aborted { failed_op: 7, .. }
+        ));
+        assert_eq!(store.snapshot().unwrap(), before);
+    }
+}
@@ -1,6 +1,12 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
 
-use super::super::log::{Command, ToCommand};
+use super::super::log::ToCommand;
+use index::{IndexKind, Indexes};
+
+mod index;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -9,17 +15,567 @@ pub struct User {
     pub email: String,
 }
 
+pub const MAX_NAME_LEN: usize = 100;
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+static EMAIL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s.]+$").unwrap());
+
+/// Names are stored as given, so surrounding whitespace counts towards the limit, but a name
+/// can't be whitespace alone.
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("name must be 1 to {MAX_NAME_LEN} characters"));
+    }
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<(), String> {
+    if !EMAIL.is_match(email) {
+        return Err(format!("{email} is not a valid email address"));
+    }
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     pub name: String,
     pub email: String,
 }
 
-impl ToCommand for CreateUserRequest {
-    fn to_command(&self) -> Command {
-        Command::AddUser {
+#[derive(Deserialize)]
+pub struct UpdateUserRequest {
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct PatchUserRequest {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Query parameters for `GET /users`.
+#[derive(Deserialize, Default)]
+pub struct ListUsersQuery {
+    pub limit: Option<usize>,
+    /// Only users with an id above this, i.e. the `next` cursor of the previous page.
+    pub after: Option<u32>,
+    /// Exact match, looked up in the email index.
+    pub email: Option<String>,
+    /// Exact match, looked up in the name index.
+    pub name: Option<String>,
+    pub name_prefix: Option<String>,
+    pub email_domain: Option<String>,
+}
+
+impl ListUsersQuery {
+    fn matches(&self, user: &User) -> bool {
+        let name_ok = self
+            .name_prefix
+            .as_ref()
+            .is_none_or(|prefix| user.name.starts_with(prefix.as_str()));
+        let domain_ok = self.email_domain.as_ref().is_none_or(|domain| {
+            user.email
+                .rsplit_once('@')
+                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain))
+        });
+        name_ok && domain_ok
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    /// Pass as `after` to get the following page; absent on the last one.
+    pub next: Option<u32>,
+    /// Users matching the filters across all pages.
+    pub total: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserCommand {
+    Add {
+        name: String,
+        email: String,
+    },
+    /// Sets whichever fields are given; PUT gives both.
+    Update {
+        id: u32,
+        name: Option<String>,
+        email: Option<String>,
+    },
+    Delete {
+        id: u32,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserResponse {
+    Created(User),
+    Updated(User),
+    Deleted(User),
+    NotFound,
+    /// Another user already has the email.
+    EmailTaken,
+}
+
+impl UserCommand {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            UserCommand::Add { name, email } => {
+                validate_name(name)?;
+                validate_email(email)
+            }
+            UserCommand::Update { name, email, .. } => {
+                if let Some(name) = name {
+                    validate_name(name)?;
+                }
+                if let Some(email) = email {
+                    validate_email(email)?;
+                }
+                Ok(())
+            }
+            UserCommand::Delete { .. } => Ok(()),
+        }
+    }
+}
+
+impl CreateUserRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name)?;
+        validate_email(&self.email)
+    }
+}
+
+impl ToCommand<UserCommand> for CreateUserRequest {
+    fn to_command(&self) -> UserCommand {
+        UserCommand::Add {
             name: self.name.clone(),
             email: self.email.clone(),
         }
     }
 }
+
+impl UpdateUserRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_name(&self.name)?;
+        validate_email(&self.email)
+    }
+
+    pub fn to_command(&self, id: u32) -> UserCommand {
+        UserCommand::Update {
+            id,
+            name: Some(self.name.clone()),
+            email: Some(self.email.clone()),
+        }
+    }
+}
+
+impl PatchUserRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.name {
+            validate_name(name)?;
+        }
+        if let Some(email) = &self.email {
+            validate_email(email)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_command(&self, id: u32) -> UserCommand {
+        UserCommand::Update {
+            id,
+            name: self.name.clone(),
+            email: self.email.clone(),
+        }
+    }
+}
+
+/// The users service: a replicated map of users, ordered by id.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserStore {
+    users: BTreeMap<u32, User>,
+    indexes: Indexes,
+    next_id: u32,
+}
+
+impl UserStore {
+    pub fn new() -> Self {
+        UserStore {
+            users: BTreeMap::new(),
+            indexes: Indexes::default(),
+            next_id: 1,
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Whether a user other than `id` has `email`. Emails compare case-insensitively.
+    fn email_taken(&self, email: &str, id: Option<u32>) -> bool {
+        self.indexes
+            .get(IndexKind::Email, email)
+            .iter()
+            .any(|other| Some(*other) != id)
+    }
+
+    fn create_user(&mut self, name: &str, email: &str) -> UserResponse {
+        if self.email_taken(email, None) {
+            return UserResponse::EmailTaken;
+        }
+        let id = self.next_id();
+        let user = User {
+            id,
+            name: name.to_string(),
+            email: email.to_string(),
+        };
+        self.indexes.insert(&user);
+        self.users.insert(id, user.clone());
+        UserResponse::Created(user)
+    }
+
+    fn update_user(
+        &mut self,
+        id: u32,
+        name: Option<&String>,
+        email: Option<&String>,
+    ) -> UserResponse {
+        if let Some(email) = email
+            && self.users.contains_key(&id)
+            && self.email_taken(email, Some(id))
+        {
+            return UserResponse::EmailTaken;
+        }
+        let Some(user) = self.users.get_mut(&id) else {
+            return UserResponse::NotFound;
+        };
+        self.indexes.remove(user);
+        if let Some(name) = name {
+            user.name = name.clone();
+        }
+        if let Some(email) = email {
+            user.email = email.clone();
+        }
+        self.indexes.insert(user);
+        UserResponse::Updated(user.clone())
+    }
+
+    fn delete_user(&mut self, id: u32) -> UserResponse {
+        match self.users.remove(&id) {
+            Some(user) => {
+                self.indexes.remove(&user);
+                UserResponse::Deleted(user)
+            }
+            None => UserResponse::NotFound,
+        }
+    }
+
+    /// The id the next user created will get.
+    pub fn upcoming_id(&self) -> u32 {
+        self.next_id
+    }
+
+    /// Puts user `id` back as it was, `None` meaning it didn't exist, and rewinds the id
+    /// counter to `next_id`. Used to roll back an aborted transaction.
+    pub fn revert(&mut self, id: u32, previous: Option<User>, next_id: u32) {
+        if let Some(user) = self.users.remove(&id) {
+            self.indexes.remove(&user);
+        }
+        if let Some(user) = previous {
+            self.indexes.insert(&user);
+            self.users.insert(id, user);
+        }
+        self.next_id = next_id;
+    }
+
+    pub fn get_user(&self, id: u32) -> Option<User> {
+        self.users.get(&id).cloned()
+    }
+
+    pub fn get_user_by_email(&self, email: &str) -> Option<User> {
+        let ids = self.indexes.get(IndexKind::Email, email);
+        ids.first().and_then(|id| self.get_user(*id))
+    }
+
+    /// Ids satisfying the query's exact-match filters, found through the indexes. `None` when
+    /// the query has no such filters.
+    fn indexed_ids(&self, query: &ListUsersQuery) -> Option<BTreeSet<u32>> {
+        let mut ids: Option<BTreeSet<u32>> = None;
+        for (kind, value) in [
+            (IndexKind::Email, &query.email),
+            (IndexKind::Name, &query.name),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            let found = self.indexes.get(kind, value);
+            ids = Some(match ids {
+                Some(ids) => ids.intersection(&found).copied().collect(),
+                None => found,
+            });
+        }
+        ids
+    }
+
+    pub fn list_users(&self, query: &ListUsersQuery) -> UserPage {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let matching: Vec<&User> = match self.indexed_ids(query) {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| self.users.get(id))
+                .filter(|u| query.matches(u))
+                .collect(),
+            None => self.users.values().filter(|u| query.matches(u)).collect(),
+        };
+        let total = matching.len();
+        let start = query
+            .after
+            .map_or(0, |after| matching.partition_point(|u| u.id <= after));
+        let mut matching = matching[start..].iter();
+        let users: Vec<User> = matching
+            .by_ref()
+            .take(limit)
+            .map(|u| (*u).clone())
+            .collect();
+        let next = match matching.next() {
+            Some(_) => users.last().map(|u| u.id),
+            None => None,
+        };
+        UserPage { users, next, total }
+    }
+
+    pub fn apply(&mut self, command: &UserCommand) -> UserResponse {
+        match command {
+            UserCommand::Add { name, email } => self.create_user(name, email),
+            UserCommand::Update { id, name, email } => {
+                self.update_user(*id, name.as_ref(), email.as_ref())
+            }
+            UserCommand::Delete { id } => self.delete_user(*id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(users: &mut UserStore, name: &str, email: &str) -> u32 {
+        let command = UserCommand::Add {
+            name: name.to_string(),
+            email: email.to_string(),
+        };
+        match users.apply(&command) {
+            UserResponse::Created(user) => user.id,
+            other => panic!("not created: {other:?}"),
+        }
+    }
+
+    fn ids(page: &UserPage) -> Vec<u32> {
+        page.users.iter().map(|user| user.id).collect()
+    }
+
+    #[test]
+    fn pages_follow_the_next_cursor() {
+        let mut users = UserStore::new();
+        for i in 0..5 {
+            add(&mut users, "a", &format!("{i}@example.com"));
+        }
+        let mut query = ListUsersQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut pages = Vec::new();
+        loop {
+            let page = users.list_users(&query);
+            assert_eq!(page.total, 5);
+            pages.push(ids(&page));
+            match page.next {
+                Some(next) => query.after = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, [vec![1, 2], vec![3, 4], vec![5]]);
+    }
+
+    #[test]
+    fn a_cursor_past_a_deleted_user_still_resumes() {
+        let mut users = UserStore::new();
+        for i in 0..4 {
+            add(&mut users, "a", &format!("{i}@example.com"));
+        }
+        users.apply(&UserCommand::Delete { id: 2 });
+        let page = users.list_users(&ListUsersQuery {
+            after: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(ids(&page), [3, 4]);
+        assert_eq!(page.next, None);
+        assert_eq!(page.total, 3);
+    }
+
+    #[test]
+    fn filters_by_name_prefix_and_email_domain() {
+        let mut users = UserStore::new();
+        add(&mut users, "alice", "alice@example.com");
+        add(&mut users, "albert", "albert@other.org");
+        add(&mut users, "bob", "bob@EXAMPLE.com");
+        let by_prefix = users.list_users(&ListUsersQuery {
+            name_prefix: Some("al".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&by_prefix), [1, 2]);
+        let by_domain = users.list_users(&ListUsersQuery {
+            email_domain: Some("example.com".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&by_domain), [1, 3]);
+        let both = users.list_users(&ListUsersQuery {
+            name_prefix: Some("al".to_string()),
+            email_domain: Some("example.com".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&both), [1]);
+        assert_eq!(both.total, 1);
+    }
+
+    fn by_email(users: &UserStore, email: &str) -> Vec<u32> {
+        ids(&users.list_users(&ListUsersQuery {
+            email: Some(email.to_string()),
+            ..Default::default()
+        }))
+    }
+
+    fn by_name(users: &UserStore, name: &str) -> Vec<u32> {
+        ids(&users.list_users(&ListUsersQuery {
+            name: Some(name.to_string()),
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn changing_an_email_moves_it_in_the_index() {
+        let mut users = UserStore::new();
+        let id = add(&mut users, "a", "old@example.com");
+        users.apply(&UserCommand::Update {
+            id,
+            name: None,
+            email: Some("new@example.com".to_string()),
+        });
+        assert!(users.get_user_by_email("old@example.com").is_none());
+        assert_eq!(users.get_user_by_email("NEW@example.com").unwrap().id, id);
+        assert!(by_email(&users, "old@example.com").is_empty());
+        assert_eq!(by_name(&users, "a"), [id]);
+        // The old email is free for someone else.
+        add(&mut users, "b", "old@example.com");
+    }
+
+    #[test]
+    fn a_patch_only_reindexes_what_it_changes() {
+        let mut users = UserStore::new();
+        let id = add(&mut users, "a", "a@example.com");
+        users.apply(&UserCommand::Update {
+            id,
+            name: Some("b".to_string()),
+            email: None,
+        });
+        assert!(by_name(&users, "a").is_empty());
+        assert_eq!(by_name(&users, "b"), [id]);
+        assert_eq!(by_email(&users, "a@example.com"), [id]);
+    }
+
+    #[test]
+    fn a_full_update_reindexes_both_fields() {
+        let mut users = UserStore::new();
+        let id = add(&mut users, "a", "a@example.com");
+        users.apply(&UserCommand::Update {
+            id,
+            name: Some("b".to_string()),
+            email: Some("b@example.com".to_string()),
+        });
+        assert!(by_name(&users, "a").is_empty());
+        assert!(by_email(&users, "a@example.com").is_empty());
+        let both = users.list_users(&ListUsersQuery {
+            name: Some("b".to_string()),
+            email: Some("b@example.com".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(ids(&both), [id]);
+    }
+
+    #[test]
+    fn a_taken_email_leaves_the_indexes_alone() {
+        let mut users = UserStore::new();
+        add(&mut users, "a", "a@example.com");
+        let id = add(&mut users, "b", "b@example.com");
+        assert!(matches!(
+            users.apply(&UserCommand::Update {
+                id,
+                name: Some("c".to_string()),
+                email: Some("A@example.com".to_string()),
+            }),
+            UserResponse::EmailTaken
+        ));
+        assert_eq!(by_name(&users, "b"), [id]);
+        assert_eq!(by_email(&users, "b@example.com"), [id]);
+    }
+
+    #[test]
+    fn deleting_a_user_drops_it_from_every_index() {
+        let mut users = UserStore::new();
+        let id = add(&mut users, "a", "a@example.com");
+        users.apply(&UserCommand::Delete { id });
+        assert!(users.get_user_by_email("a@example.com").is_none());
+        assert!(by_email(&users, "a@example.com").is_empty());
+        assert!(by_name(&users, "a").is_empty());
+    }
+
+    #[test]
+    fn rejects_blank_or_long_names_and_malformed_emails() {
+        let add = |name: &str, email: &str| UserCommand::Add {
+            name: name.to_string(),
+            email: email.to_string(),
+        };
+        assert!(add("a", "a@example.com").validate().is_ok());
+        assert!(
+            add(&"a".repeat(MAX_NAME_LEN), "a@example.com")
+                .validate()
+                .is_ok()
+        );
+        assert!(add("", "a@example.com").validate().is_err());
+        assert!(add("  ", "a@example.com").validate().is_err());
+        assert!(
+            add(&"a".repeat(MAX_NAME_LEN + 1), "a@example.com")
+                .validate()
+                .is_err()
+        );
+        // Padding is stored with the name, so it counts.
+        let padded = format!(" {} ", "a".repeat(MAX_NAME_LEN - 1));
+        assert!(add(&padded, "a@example.com").validate().is_err());
+        for email in ["", "a", "a@example", "a@@example.com", "a b@example.com"] {
+            assert!(add("a", email).validate().is_err(), "accepted {email}");
+        }
+
+        let patch = UserCommand::Update {
+            id: 1,
+            name: None,
+            email: Some("not an email".to_string()),
+        };
+        assert!(patch.validate().is_err());
+        let patch = UserCommand::Update {
+            id: 1,
+            name: None,
+            email: None,
+        };
+        assert!(patch.validate().is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::User;
+
+/// The secondary indexes over users. Adding one is a matter of adding a variant here along
+/// with the key it extracts; `Indexes` keeps every variant up to date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum IndexKind {
+    /// Unique; emails compare case-insensitively.
+    Email,
+    Name,
+}
+
+impl IndexKind {
+    const ALL: [IndexKind; 2] = [IndexKind::Email, IndexKind::Name];
+
+    pub fn key_of(self, value: &str) -> String {
+        match self {
+            IndexKind::Email => value.to_ascii_lowercase(),
+            IndexKind::Name => value.to_string(),
+        }
+    }
+
+    fn key(self, user: &User) -> String {
+        match self {
+            IndexKind::Email => self.key_of(&user.email),
+            IndexKind::Name => self.key_of(&user.name),
+        }
+    }
+}
+
+/// Every secondary index, mapping keys to user ids. Only changed during apply, alongside the
+/// users themselves, and carried in snapshots so followers don't have to rebuild them.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Indexes(BTreeMap<IndexKind, BTreeMap<String, BTreeSet<u32>>>);
+
+impl Indexes {
+    pub fn insert(&mut self, user: &User) {
+        for kind in IndexKind::ALL {
+            self.0
+                .entry(kind)
+                .or_default()
+                .entry(kind.key(user))
+                .or_default()
+                .insert(user.id);
+        }
+    }
+
+    pub fn remove(&mut self, user: &User) {
+        for kind in IndexKind::ALL {
+            let Some(index) = self.0.get_mut(&kind) else {
+                continue;
+            };
+            let key = kind.key(user);
+            if let Some(ids) = index.get_mut(&key) {
+                ids.remove(&user.id);
+                if ids.is_empty() {
+                    index.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Ids of users whose `kind` key matches `value`.
+    pub fn get(&self, kind: IndexKind, value: &str) -> BTreeSet<u32> {
+        self.0
+            .get(&kind)
+            .and_then(|index| index.get(&kind.key_of(value)))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
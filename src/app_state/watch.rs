@@ -0,0 +1,264 @@
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::{StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+
+use super::raft_state::{Applied, Watcher};
+use super::state_machine::store::{Change, Resource, Store};
+
+/// Query parameters for `GET /watch`.
+#[derive(Deserialize, Default)]
+pub struct WatchQuery {
+    /// Stream changes applied after this log index; defaults to the last one applied here. The
+    /// `Last-Event-ID` header takes precedence, so reconnecting clients resume where they left
+    /// off.
+    pub after: Option<u32>,
+    pub resource: Option<Resource>,
+    pub prefix: Option<String>,
+}
+
+impl WatchQuery {
+    fn matches(&self, change: &Change) -> bool {
+        self.resource.is_none_or(|r| r == change.resource)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| change.key.starts_with(prefix.as_str()))
+    }
+}
+
+#[derive(Serialize)]
+struct WatchEvent<'a> {
+    revision: u32,
+    #[serde(flatten)]
+    change: &'a Change,
+}
+
+struct WatchStream {
+    query: WatchQuery,
+    backlog: VecDeque<Applied<Store>>,
+    applied: broadcast::Receiver<Applied<Store>>,
+    compacted: watch::Receiver<u32>,
+    pending: VecDeque<Event>,
+    last_index: u32,
+    done: bool,
+}
+
+impl WatchStream {
+    async fn next_applied(
+        backlog: &mut VecDeque<Applied<Store>>,
+        applied: &mut broadcast::Receiver<Applied<Store>>,
+    ) -> Result<Applied<Store>, RecvError> {
+        match backlog.pop_front() {
+            Some(applied) => Ok(applied),
+            None => applied.recv().await,
+        }
+    }
+
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.done {
+                return None;
+            }
+            let next = tokio::select! {
+                biased;
+                Ok(()) = self.compacted.changed() => None,
+                next = Self::next_applied(&mut self.backlog, &mut self.applied) => Some(next),
+            };
+            let Some(next) = next else {
+                let compacted = *self.compacted.borrow_and_update();
+                if compacted <= self.last_index {
+                    continue;
+                }
+                // As for a watch started too far back, the client has to list afresh.
+                self.done = true;
+                let error = serde_json::json!({
+                    "error": "compacted",
+                    "compacted_through": compacted,
+                });
+                return Event::default().event("error").json_data(error).ok();
+            };
+            let (index, response) = match next {
+                Ok(applied) => applied,
+                // The client can reconnect with the last id it saw and pick up from the history.
+                Err(RecvError::Lagged(_)) => {
+                    self.done = true;
+                    return Some(Event::default().event("error").data("lagged"));
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            if index <= self.last_index {
+                continue;
+            }
+            self.last_index = index;
+            for change in response.changes() {
+                if !self.query.matches(&change) {
+                    continue;
+                }
+                let event = WatchEvent {
+                    revision: index,
+                    change: &change,
+                };
+                if let Ok(event) = Event::default().id(index.to_string()).json_data(event) {
+                    self.pending.push_back(event);
+                }
+            }
+        }
+    }
+}
+
+/// Streams the changes applied after `after` as server-sent events, each with its revision as
+/// the event id. Changes from one transaction share a revision and are sent together. The
+/// stream ends once `stopped` resolves, or with an error event if the watcher falls behind or a
+/// snapshot skips over changes it hasn't sent.
+pub fn stream(
+    query: WatchQuery,
+    after: u32,
+    watcher: Watcher<Store>,
+    stopped: impl Future<Output = ()> + Send + 'static,
+) -> Response {
+    let watch = WatchStream {
+        query,
+        backlog: watcher.backlog.into(),
+        applied: watcher.applied,
+        compacted: watcher.compacted,
+        pending: VecDeque::new(),
+        last_index: after,
+        done: false,
+    };
+    let events = stream::unfold(watch, |mut watch| async move {
+        let event = watch.next_event().await?;
+        Some((Ok::<_, Infallible>(event), watch))
+    })
+    .take_until(stopped);
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::state_machine::StateMachine;
+    use super::super::state_machine::kv::KvCommand;
+    use super::super::state_machine::store::StoreCommand;
+    use super::super::state_machine::user::UserCommand;
+    use super::*;
+
+    fn put(key: &str) -> StoreCommand {
+        StoreCommand::Kv(KvCommand::Put {
+            key: key.to_string(),
+            value: String::new(),
+            expected_revision: None,
+            lease: None,
+        })
+    }
+
+    /// Applies `commands` from index 1 on, the first `history` of them before the watch
+    /// starts, and returns the `(revision, key)` of every event streamed.
+    async fn watch(
+        commands: Vec<StoreCommand>,
+        history: usize,
+        after: u32,
+        query: WatchQuery,
+    ) -> Vec<(u32, String)> {
+        let mut store = Store::new();
+        let applied: Vec<Applied<Store>> = (1..)
+            .zip(&commands)
+            .map(|(index, command)| (index, store.apply(index, command)))
+            .collect();
+        let (tx, rx) = broadcast::channel(16);
+        // The live feed overlaps the backlog by one, as it can when a watch starts.
+        for applied in &applied[history.saturating_sub(1)..] {
+            tx.send(applied.clone()).unwrap();
+        }
+        drop(tx);
+        let watcher = Watcher {
+            backlog: applied[..history]
+                .iter()
+                .filter(|(index, _)| *index > after)
+                .cloned()
+                .collect(),
+            applied: rx,
+            compacted: watch::channel(0).1,
+        };
+        let response = stream(query, after, watcher, std::future::pending());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| {
+                let event: serde_json::Value = serde_json::from_str(data).unwrap();
+                let revision = event["revision"].as_u64().unwrap() as u32;
+                (revision, event["key"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_last_event_seen() {
+        let commands = vec![put("a"), put("b"), put("c"), put("d")];
+        let events = watch(commands, 3, 1, WatchQuery::default()).await;
+        let expected = [(2, "b"), (3, "c"), (4, "d")].map(|(r, k)| (r, k.to_string()));
+        assert_eq!(events, expected);
+    }
+
+    #[tokio::test]
+    async fn filters_by_resource_and_prefix() {
+        let user = StoreCommand::User(UserCommand::Add {
+            name: "a".to_string(),
+            email: "a@example.com".to_string(),
+        });
+        let commands = vec![put("a/1"), user, put("b/1"), put("a/2")];
+        let query = WatchQuery {
+            resource: Some(Resource::Kv),
+            prefix: Some("a/".to_string()),
+            ..Default::default()
+        };
+        let events = watch(commands.clone(), 0, 0, query).await;
+        let expected = [(1, "a/1"), (4, "a/2")].map(|(r, k)| (r, k.to_string()));
+        assert_eq!(events, expected);
+
+        let query = WatchQuery {
+            resource: Some(Resource::User),
+            ..Default::default()
+        };
+        let events = watch(commands, 0, 0, query).await;
+        assert_eq!(events, [(2, "1".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn ends_with_an_error_when_a_snapshot_skips_changes() {
+        let (tx, rx) = broadcast::channel(16);
+        let (installed_tx, compacted) = watch::channel(0);
+        let watcher = Watcher {
+            backlog: Vec::new(),
+            applied: rx,
+            compacted,
+        };
+        installed_tx.send_replace(9);
+        let response = stream(WatchQuery::default(), 3, watcher, std::future::pending());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("event: error"));
+        let data = body
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let error: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(error["error"], "compacted");
+        assert_eq!(error["compacted_through"], 9);
+        drop(tx);
+    }
+}
@@ -0,0 +1,763 @@
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::time::Duration;
+
+use super::app_state::shared::NodeId;
+
+/// Command-line flags. Every setting can also come from the environment; values given here
+/// override the environment, which in turn overrides the config file.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file
+    #[arg(long, env = "WHITEWATER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the resolved configuration and exit
+    #[arg(long)]
+    print_config: bool,
+
+    #[arg(long, env = "CLIENT_BIND_ADDR")]
+    client_addr: Option<SocketAddr>,
+    #[arg(long, env = "PEER_BIND_ADDR")]
+    peer_addr: Option<SocketAddr>,
+
+    /// Stable node id; derived from the pod name's StatefulSet ordinal when not given
+    #[arg(long, env = "NODE_ID")]
+    node_id: Option<NodeId>,
+    #[arg(long, env = "POD_NAME")]
+    node_name: Option<String>,
+    #[arg(long, env = "POD_IP")]
+    node_ip: Option<String>,
+
+    #[arg(long, env = "SERVICE_NAME")]
+    service_name: Option<String>,
+    #[arg(long, env = "NAMESPACE")]
+    namespace: Option<String>,
+    #[arg(long, env = "SERVICE_PORT_NAME")]
+    service_port_name: Option<String>,
+    /// Static peer addresses; when given, SRV discovery is skipped
+    #[arg(long, env = "PEERS", value_delimiter = ',')]
+    peers: Option<Vec<String>>,
+    #[arg(long, env = "DISCOVERY_DELAY_MS")]
+    discovery_delay_ms: Option<u64>,
+    #[arg(long, env = "DISCOVERY_INTERVAL_MS")]
+    discovery_interval_ms: Option<u64>,
+    /// Consecutive discovery rounds a voter has to be dead for before its removal is proposed
+    #[arg(long, env = "DISCOVERY_STALE_ROUNDS")]
+    discovery_stale_rounds: Option<u32>,
+
+    #[arg(long, env = "CLUSTER_TOKEN", hide_env_values = true)]
+    cluster_token: Option<String>,
+    #[arg(long, env = "CLUSTER_TOKEN_FILE")]
+    cluster_token_file: Option<PathBuf>,
+
+    #[arg(long, env = "ELECTION_TIMEOUT_MIN_MS")]
+    election_timeout_min_ms: Option<u64>,
+    #[arg(long, env = "ELECTION_TIMEOUT_MAX_MS")]
+    election_timeout_max_ms: Option<u64>,
+    #[arg(long, env = "HEARTBEAT_INTERVAL_MS")]
+    heartbeat_interval_ms: Option<u64>,
+    /// Number of nodes the cluster is bootstrapped with; they're taken to be ids 0 to n - 1
+    #[arg(long, env = "CLUSTER_SIZE")]
+    cluster_size: Option<u32>,
+    #[arg(long, env = "MAX_APPEND_ENTRIES")]
+    max_append_entries: Option<usize>,
+    /// Applied entries to accumulate before the log is compacted into a snapshot
+    #[arg(long, env = "SNAPSHOT_THRESHOLD")]
+    snapshot_threshold: Option<u32>,
+    /// How long a client request waits for its entry to be applied before giving up with a 504
+    #[arg(long, env = "PROPOSAL_TIMEOUT_MS")]
+    proposal_timeout_ms: Option<u64>,
+    /// Entries the leader may hold uncommitted before it turns proposals away with a 503
+    #[arg(long, env = "MAX_UNCOMMITTED_ENTRIES")]
+    max_uncommitted_entries: Option<u32>,
+    /// Proposals and linearizable reads a node waits on at once before answering 429
+    #[arg(long, env = "MAX_IN_FLIGHT_PROPOSALS")]
+    max_in_flight_proposals: Option<usize>,
+
+    #[arg(long, env = "SWIM_PROTOCOL_PERIOD_MS")]
+    swim_protocol_period_ms: Option<u64>,
+    #[arg(long, env = "SWIM_PING_TIMEOUT_MS")]
+    swim_ping_timeout_ms: Option<u64>,
+    #[arg(long, env = "SWIM_INDIRECT_CHECKS")]
+    swim_indirect_checks: Option<usize>,
+    #[arg(long, env = "SWIM_SUSPICION_TIMEOUT_MS")]
+    swim_suspicion_timeout_ms: Option<u64>,
+    /// Each update is gossiped this many times log(n) before it's dropped
+    #[arg(long, env = "SWIM_RETRANSMIT_MULT")]
+    swim_retransmit_mult: Option<u32>,
+    /// Updates piggybacked on a single ping or ack, besides the sender's own
+    #[arg(long, env = "SWIM_MAX_PIGGYBACK")]
+    swim_max_piggyback: Option<usize>,
+    /// How long a dead member is remembered before it's forgotten
+    #[arg(long, env = "SWIM_DEAD_MEMBER_TTL_MS")]
+    swim_dead_member_ttl_ms: Option<u64>,
+
+    #[arg(long, env = "PROCESS_CHANNEL_SIZE")]
+    process_channel_size: Option<usize>,
+    #[arg(long, env = "BROADCAST_CHANNEL_SIZE")]
+    broadcast_channel_size: Option<usize>,
+    /// Applied changes a watcher can fall behind by before its stream is cut
+    #[arg(long, env = "WATCH_CHANNEL_SIZE")]
+    watch_channel_size: Option<usize>,
+
+    /// How often the leader looks for expired leases
+    #[arg(long, env = "LEASE_CHECK_INTERVAL_MS")]
+    lease_check_interval_ms: Option<u64>,
+    #[arg(long, env = "LEASE_MIN_TTL_MS")]
+    lease_min_ttl_ms: Option<u64>,
+
+    /// How long the outcome of a request with an Idempotency-Key is remembered
+    #[arg(long, env = "IDEMPOTENCY_WINDOW_MS")]
+    idempotency_window_ms: Option<u64>,
+
+    /// How long a node has to shut down after SIGTERM; keep it under the pod's
+    /// terminationGracePeriodSeconds
+    #[arg(long, env = "SHUTDOWN_GRACE_PERIOD_MS")]
+    shutdown_grace_period_ms: Option<u64>,
+
+    /// Whether a follower that knows no leader still answers /readyz with 200
+    #[arg(long, env = "READY_WITHOUT_LEADER")]
+    ready_without_leader: Option<bool>,
+    /// How many committed entries a node may have left to apply and still be ready
+    #[arg(long, env = "MAX_READY_LAG")]
+    max_ready_lag: Option<u32>,
+    /// How long past its period a background loop may go without running before /healthz fails
+    #[arg(long, env = "LOOP_STALL_TIMEOUT_MS")]
+    loop_stall_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub client_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    pub node: NodeConfig,
+    pub discovery: DiscoveryConfig,
+    pub auth: AuthConfig,
+    pub raft: RaftConfig,
+    pub swim: SwimConfig,
+    pub channels: ChannelConfig,
+    pub leases: LeaseConfig,
+    pub idempotency: IdempotencyConfig,
+    pub shutdown: ShutdownConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub id: Option<NodeId>,
+    pub name: String,
+    pub ip: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub peers: Vec<String>,
+    pub service_name: Option<String>,
+    pub namespace: Option<String>,
+    pub port_name: Option<String>,
+    pub delay_ms: u64,
+    pub interval_ms: u64,
+    pub stale_rounds: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub cluster_token: Option<String>,
+    pub cluster_token_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftConfig {
+    pub election_timeout_min_ms: u64,
+    pub election_timeout_max_ms: u64,
+    pub heartbeat_interval_ms: u64,
+    pub cluster_size: u32,
+    pub max_append_entries: usize,
+    pub snapshot_threshold: u32,
+    pub proposal_timeout_ms: u64,
+    pub max_uncommitted_entries: u32,
+    pub max_in_flight_proposals: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SwimConfig {
+    pub protocol_period_ms: u64,
+    pub ping_timeout_ms: u64,
+    pub indirect_checks: usize,
+    pub suspicion_timeout_ms: u64,
+    pub retransmit_mult: u32,
+    pub max_piggyback: usize,
+    pub dead_member_ttl_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub process: usize,
+    pub broadcast: usize,
+    pub watch: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    pub check_interval_ms: u64,
+    pub min_ttl_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    pub window_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub grace_period_ms: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub ready_without_leader: bool,
+    pub max_ready_lag: u32,
+    pub stall_timeout_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            client_addr: SocketAddr::from(([0, 0, 0, 0], 8090)),
+            peer_addr: SocketAddr::from(([0, 0, 0, 0], 8091)),
+            node: NodeConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            auth: AuthConfig::default(),
+            raft: RaftConfig::default(),
+            swim: SwimConfig::default(),
+            channels: ChannelConfig::default(),
+            leases: LeaseConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            peers: Vec::new(),
+            service_name: None,
+            namespace: None,
+            port_name: None,
+            delay_ms: 5000,
+            interval_ms: 10000,
+            stale_rounds: 3,
+        }
+    }
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_timeout_min_ms: 100,
+            election_timeout_max_ms: 300,
+            heartbeat_interval_ms: 30,
+            cluster_size: 1,
+            max_append_entries: 64,
+            snapshot_threshold: 1000,
+            proposal_timeout_ms: 5000,
+            max_uncommitted_entries: 1024,
+            max_in_flight_proposals: 512,
+        }
+    }
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        SwimConfig {
+            protocol_period_ms: 1000,
+            ping_timeout_ms: 300,
+            indirect_checks: 3,
+            suspicion_timeout_ms: 5000,
+            retransmit_mult: 3,
+            max_piggyback: 8,
+            dead_member_ttl_ms: 600_000,
+        }
+    }
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            process: 100,
+            broadcast: 100,
+            watch: 1024,
+        }
+    }
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        LeaseConfig {
+            check_interval_ms: 100,
+            min_ttl_ms: 1000,
+        }
+    }
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            window_ms: 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace_period_ms: 25000,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            ready_without_leader: false,
+            max_ready_lag: 100,
+            stall_timeout_ms: 5000,
+        }
+    }
+}
+
+impl RaftConfig {
+    pub fn random_election_timeout(&self) -> Duration {
+        let ms = rand::random_range(self.election_timeout_min_ms..=self.election_timeout_max_ms);
+        Duration::from_millis(ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    pub fn proposal_timeout(&self) -> Duration {
+        Duration::from_millis(self.proposal_timeout_ms)
+    }
+
+    /// The voters a fresh cluster starts with. Anyone else joins through `AddPeer`.
+    pub fn initial_voters(&self) -> Vec<NodeId> {
+        (0..self.cluster_size).map(NodeId).collect()
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl SwimConfig {
+    pub fn protocol_period(&self) -> Duration {
+        Duration::from_millis(self.protocol_period_ms)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_timeout_ms)
+    }
+
+    pub fn suspicion_timeout(&self) -> Duration {
+        Duration::from_millis(self.suspicion_timeout_ms)
+    }
+
+    pub fn dead_member_ttl(&self) -> Duration {
+        Duration::from_millis(self.dead_member_ttl_ms)
+    }
+}
+
+impl LeaseConfig {
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_millis(self.check_interval_ms)
+    }
+}
+
+impl ShutdownConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_millis(self.grace_period_ms)
+    }
+}
+
+impl HealthConfig {
+    pub fn stall_timeout(&self) -> Duration {
+        Duration::from_millis(self.stall_timeout_ms)
+    }
+}
+
+impl NodeConfig {
+    /// Uses the configured id, then the pod name's ordinal. A node with neither a name nor an id
+    /// is taken to be a lone local node and gets id 0.
+    pub fn resolve_id(&self) -> anyhow::Result<NodeId> {
+        if let Some(id) = self.id {
+            return Ok(id);
+        }
+        if self.name.is_empty() {
+            return Ok(NodeId::default());
+        }
+        NodeId::from_ordinal(&self.name)
+            .ok_or_else(|| anyhow!("Can't derive a node id from name {}", self.name))
+    }
+}
+
+impl Config {
+    /// Parses flags, then builds the config from file, environment and flags (lowest to highest
+    /// precedence). Exits after printing when `--print-config` is given.
+    pub fn load() -> anyhow::Result<Config> {
+        let cli = Cli::parse();
+        let print_config = cli.print_config;
+        let config = Config::from_cli(cli)?;
+
+        if print_config {
+            println!("{}", config.to_toml()?);
+            std::process::exit(0);
+        }
+        Ok(config)
+    }
+
+    fn from_cli(cli: Cli) -> anyhow::Result<Config> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Couldn't read config {}: {e}", path.display()))?;
+                toml::from_str(&contents)?
+            }
+            None => Config::default(),
+        };
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        fn set_opt<T>(target: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *target = value;
+            }
+        }
+
+        set(&mut self.client_addr, cli.client_addr);
+        set(&mut self.peer_addr, cli.peer_addr);
+        set_opt(&mut self.node.id, cli.node_id);
+        set(&mut self.node.name, cli.node_name);
+        set(&mut self.node.ip, cli.node_ip);
+        set(&mut self.discovery.peers, cli.peers);
+        set_opt(&mut self.discovery.service_name, cli.service_name);
+        set_opt(&mut self.discovery.namespace, cli.namespace);
+        set_opt(&mut self.discovery.port_name, cli.service_port_name);
+        set(&mut self.discovery.delay_ms, cli.discovery_delay_ms);
+        set(&mut self.discovery.interval_ms, cli.discovery_interval_ms);
+        set(&mut self.discovery.stale_rounds, cli.discovery_stale_rounds);
+        set_opt(&mut self.auth.cluster_token, cli.cluster_token);
+        set_opt(&mut self.auth.cluster_token_file, cli.cluster_token_file);
+        set(
+            &mut self.raft.election_timeout_min_ms,
+            cli.election_timeout_min_ms,
+        );
+        set(
+            &mut self.raft.election_timeout_max_ms,
+            cli.election_timeout_max_ms,
+        );
+        set(
+            &mut self.raft.heartbeat_interval_ms,
+            cli.heartbeat_interval_ms,
+        );
+        set(&mut self.raft.cluster_size, cli.cluster_size);
+        set(&mut self.raft.max_append_entries, cli.max_append_entries);
+        set(&mut self.raft.snapshot_threshold, cli.snapshot_threshold);
+        set(&mut self.raft.proposal_timeout_ms, cli.proposal_timeout_ms);
+        set(
+            &mut self.raft.max_uncommitted_entries,
+            cli.max_uncommitted_entries,
+        );
+        set(
+            &mut self.raft.max_in_flight_proposals,
+            cli.max_in_flight_proposals,
+        );
+        set(
+            &mut self.swim.protocol_period_ms,
+            cli.swim_protocol_period_ms,
+        );
+        set(&mut self.swim.ping_timeout_ms, cli.swim_ping_timeout_ms);
+        set(&mut self.swim.indirect_checks, cli.swim_indirect_checks);
+        set(
+            &mut self.swim.suspicion_timeout_ms,
+            cli.swim_suspicion_timeout_ms,
+        );
+        set(&mut self.swim.retransmit_mult, cli.swim_retransmit_mult);
+        set(&mut self.swim.max_piggyback, cli.swim_max_piggyback);
+        set(
+            &mut self.swim.dead_member_ttl_ms,
+            cli.swim_dead_member_ttl_ms,
+        );
+        set(&mut self.channels.process, cli.process_channel_size);
+        set(&mut self.channels.broadcast, cli.broadcast_channel_size);
+        set(&mut self.channels.watch, cli.watch_channel_size);
+        set(
+            &mut self.leases.check_interval_ms,
+            cli.lease_check_interval_ms,
+        );
+        set(&mut self.leases.min_ttl_ms, cli.lease_min_ttl_ms);
+        set(&mut self.idempotency.window_ms, cli.idempotency_window_ms);
+        set(
+            &mut self.shutdown.grace_period_ms,
+            cli.shutdown_grace_period_ms,
+        );
+        set(
+            &mut self.health.ready_without_leader,
+            cli.ready_without_leader,
+        );
+        set(&mut self.health.max_ready_lag, cli.max_ready_lag);
+        set(&mut self.health.stall_timeout_ms, cli.loop_stall_timeout_ms);
+    }
+
+    /// The address other nodes should use to reach this node's peer listener.
+    pub fn advertised_peer_addr(&self) -> String {
+        if self.node.ip.is_empty() {
+            self.peer_addr.to_string()
+        } else {
+            format!("{}:{}", self.node.ip, self.peer_addr.port())
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.node.resolve_id()?;
+        let raft = &self.raft;
+        if raft.election_timeout_min_ms == 0 || raft.heartbeat_interval_ms == 0 {
+            bail!("Election timeout and heartbeat interval must be non-zero");
+        }
+        if raft.election_timeout_min_ms > raft.election_timeout_max_ms {
+            bail!(
+                "election_timeout_min_ms ({}) is above election_timeout_max_ms ({})",
+                raft.election_timeout_min_ms,
+                raft.election_timeout_max_ms
+            );
+        }
+        // A follower should see several heartbeats before its shortest timeout fires.
+        if raft.heartbeat_interval_ms.saturating_mul(3) > raft.election_timeout_min_ms {
+            bail!(
+                "heartbeat_interval_ms ({}) must be at most a third of election_timeout_min_ms ({})",
+                raft.heartbeat_interval_ms,
+                raft.election_timeout_min_ms
+            );
+        }
+        if raft.cluster_size == 0 || raft.max_append_entries == 0 || raft.snapshot_threshold == 0 {
+            bail!("cluster_size, max_append_entries and snapshot_threshold must be non-zero");
+        }
+        if raft.proposal_timeout_ms == 0 {
+            bail!("proposal_timeout_ms must be non-zero");
+        }
+        if raft.max_uncommitted_entries == 0 || raft.max_in_flight_proposals == 0 {
+            bail!("max_uncommitted_entries and max_in_flight_proposals must be non-zero");
+        }
+        if self.discovery.interval_ms == 0 || self.discovery.stale_rounds == 0 {
+            bail!("Discovery interval and stale rounds must be non-zero");
+        }
+        let swim = &self.swim;
+        if swim.ping_timeout_ms == 0 || swim.ping_timeout_ms >= swim.protocol_period_ms {
+            bail!(
+                "SWIM ping_timeout_ms ({}) must be non-zero and below protocol_period_ms ({})",
+                swim.ping_timeout_ms,
+                swim.protocol_period_ms
+            );
+        }
+        // A suspect needs at least a period to hear it's suspected and refute.
+        if swim.suspicion_timeout_ms < swim.protocol_period_ms {
+            bail!(
+                "SWIM suspicion_timeout_ms ({}) must be at least protocol_period_ms ({})",
+                swim.suspicion_timeout_ms,
+                swim.protocol_period_ms
+            );
+        }
+        if swim.retransmit_mult == 0 || swim.max_piggyback == 0 {
+            bail!("SWIM retransmit_mult and max_piggyback must be non-zero");
+        }
+        // Discovery needs a dead voter in view for stale_rounds rounds to propose its removal.
+        let stale_ms = self
+            .discovery
+            .interval_ms
+            .saturating_mul(u64::from(self.discovery.stale_rounds));
+        if swim.dead_member_ttl_ms <= stale_ms {
+            bail!(
+                "SWIM dead_member_ttl_ms ({}) must be above discovery interval_ms times stale_rounds ({stale_ms})",
+                swim.dead_member_ttl_ms
+            );
+        }
+        if self.channels.process == 0 || self.channels.broadcast == 0 || self.channels.watch == 0 {
+            bail!("Channel sizes must be non-zero");
+        }
+        if self.leases.check_interval_ms == 0 {
+            bail!("Lease check interval must be non-zero");
+        }
+        // A lease has to be able to outlive the election that follows its leader's failure.
+        if self.leases.min_ttl_ms <= self.raft.election_timeout_max_ms {
+            bail!(
+                "Lease min_ttl_ms ({}) must be above election_timeout_max_ms ({})",
+                self.leases.min_ttl_ms,
+                self.raft.election_timeout_max_ms
+            );
+        }
+        if self.idempotency.window_ms == 0 {
+            bail!("Idempotency window must be non-zero");
+        }
+        if self.shutdown.grace_period_ms == 0 {
+            bail!("Shutdown grace period must be non-zero");
+        }
+        if self.health.stall_timeout_ms == 0 {
+            bail!("Loop stall timeout must be non-zero");
+        }
+        for peer in &self.discovery.peers {
+            match peer.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+                _ => bail!("Peer {peer} must be given as host:port"),
+            }
+        }
+        if self.client_addr == self.peer_addr {
+            bail!("Client and peer listeners can't share {}", self.client_addr);
+        }
+        Ok(())
+    }
+
+    /// Renders the config with secrets redacted.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        let mut redacted = self.clone();
+        if redacted.auth.cluster_token.is_some() {
+            redacted.auth.cluster_token = Some("<redacted>".to_string());
+        }
+        Ok(toml::to_string_pretty(&redacted)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Held while parsing flags, which reads the environment, or changing the environment.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn cli(args: &[&str]) -> Cli {
+        let _env = ENV.lock().unwrap();
+        Cli::try_parse_from(std::iter::once("whitewater").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let path = std::env::temp_dir().join(format!("whitewater-{}.toml", std::process::id()));
+        let file =
+            "[raft]\nsnapshot_threshold = 10\nmax_append_entries = 20\nproposal_timeout_ms = 30\n";
+        fs::write(&path, file).unwrap();
+        let args = [
+            "whitewater",
+            "--config",
+            path.to_str().unwrap(),
+            "--snapshot-threshold",
+            "12",
+        ];
+        let parsed = {
+            let _env = ENV.lock().unwrap();
+            // SAFETY: every test that reads the environment holds the lock.
+            unsafe {
+                std::env::set_var("SNAPSHOT_THRESHOLD", "11");
+                std::env::set_var("MAX_APPEND_ENTRIES", "21");
+            }
+            let parsed = Cli::try_parse_from(args);
+            unsafe {
+                std::env::remove_var("SNAPSHOT_THRESHOLD");
+                std::env::remove_var("MAX_APPEND_ENTRIES");
+            }
+            parsed.unwrap()
+        };
+        let config = Config::from_cli(parsed);
+        fs::remove_file(&path).unwrap();
+
+        let raft = config.unwrap().raft;
+        assert_eq!(raft.snapshot_threshold, 12);
+        assert_eq!(raft.max_append_entries, 21);
+        assert_eq!(raft.proposal_timeout_ms, 30);
+        assert_eq!(
+            raft.max_uncommitted_entries,
+            RaftConfig::default().max_uncommitted_entries
+        );
+    }
+
+    #[test]
+    fn the_example_config_is_the_defaults() {
+        let example: Config = toml::from_str(include_str!("../whitewater.example.toml")).unwrap();
+        assert_eq!(
+            example.to_toml().unwrap(),
+            Config::default().to_toml().unwrap()
+        );
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_inconsistent_settings() {
+        let invalid = [
+            cli(&["--election-timeout-min-ms", "400"]),
+            cli(&["--heartbeat-interval-ms", "50"]),
+            cli(&["--heartbeat-interval-ms", &u64::MAX.to_string()]),
+            cli(&["--swim-ping-timeout-ms", "1000"]),
+            cli(&["--swim-suspicion-timeout-ms", "500"]),
+            cli(&["--swim-dead-member-ttl-ms", "30000"]),
+            cli(&["--discovery-interval-ms", &u64::MAX.to_string()]),
+            cli(&["--lease-min-ttl-ms", "300"]),
+            cli(&["--loop-stall-timeout-ms", "0"]),
+            cli(&["--peers", "node-1"]),
+            cli(&["--peer-addr", "0.0.0.0:8090"]),
+        ];
+        for cli in invalid {
+            let flags = format!("{cli:?}");
+            assert!(Config::from_cli(cli).is_err(), "accepted {flags}");
+        }
+        assert!(Config::from_cli(cli(&["--swim-suspicion-timeout-ms", "1000"])).is_ok());
+    }
+
+    #[test]
+    fn resolves_the_node_id_from_config_then_the_pod_name() {
+        let node = |id: Option<u32>, name: &str| NodeConfig {
+            id: id.map(NodeId),
+            name: name.to_string(),
+            ip: String::new(),
+        };
+        assert_eq!(
+            node(Some(7), "whitewater-3").resolve_id().unwrap(),
+            NodeId(7)
+        );
+        assert_eq!(node(None, "whitewater-3").resolve_id().unwrap(), NodeId(3));
+        assert_eq!(node(None, "").resolve_id().unwrap(), NodeId(0));
+        assert!(node(None, "whitewater").resolve_id().is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use futures_util::future::join_all;
+use hickory_resolver::TokioResolver;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use tokio::time::timeout;
+
+use super::app_state::{
+    AppState,
+    log::Command,
+    shared::{NodeId, Peer},
+};
+use super::config::DiscoveryConfig;
+use super::handler::Handler;
+use super::membership::MemberStatus;
+use super::websocket::{auth::PeerAuth, connection::Connection};
+
+fn peer_to_ws_addr(addr: &str) -> String {
+    let re = Regex::new(r"\.:").unwrap();
+    let addr = re.replace_all(addr, ":");
+    format!("ws://{}/ws", addr)
+}
+
+async fn resolve_srv_peers(discovery: &DiscoveryConfig) -> anyhow::Result<Vec<String>> {
+    let (Some(service), Some(namespace), Some(port_name)) = (
+        &discovery.service_name,
+        &discovery.namespace,
+        &discovery.port_name,
+    ) else {
+        anyhow::bail!("Service discovery is not configured");
+    };
+
+    let resolver = TokioResolver::builder_tokio()?.build();
+
+    let srv_query = format!(
+        "_{}._{}.{}.{}.svc.cluster.local",
+        port_name, "tcp", service, namespace
+    );
+
+    let records = resolver.srv_lookup(&srv_query).await?;
+
+    Ok(records
+        .iter()
+        .map(|srv| format!("{}:{}", srv.target().to_utf8(), srv.port()))
+        .collect())
+}
+
+/// Periodically re-resolves peer addresses and reconciles them against open connections and the
+/// voter set in `Cluster::peers`.
+///
+/// Addresses are only a way to reach a node: each connection learns the peer's `NodeId` from the
+/// handshake, which is also how a node spots its own address among the resolved ones. Addresses
+/// of peers already connected, whichever side dialed, aren't dialed again. Connected peers join
+/// the SWIM membership, whose gossip in turn supplies more addresses to connect to.
+/// Connections and the address book are updated directly, but voter changes are only ever
+/// proposed through the log, driven by the membership view: alive members are proposed as voters
+/// and voters SWIM has declared dead for `stale_rounds` consecutive rounds are proposed for
+/// removal, so one that's only briefly cut off and refutes in time keeps its vote.
+///
+/// Each round dials every new address at once, giving up on any that take longer than the
+/// interval. The loop waits on DNS and the network, so it isn't tracked for `/healthz`: a slow
+/// or unreachable peer would otherwise get a healthy node restarted.
+pub struct Discovery {
+    app_state: AppState,
+    handler: Handler,
+    auth: PeerAuth,
+    config: DiscoveryConfig,
+    own_addrs: HashSet<String>,
+    dead_rounds: HashMap<NodeId, u32>,
+}
+
+impl Discovery {
+    pub fn spawn(
+        app_state: &AppState,
+        handler: &Handler,
+        auth: &PeerAuth,
+        config: DiscoveryConfig,
+    ) {
+        let mut discovery = Discovery {
+            app_state: app_state.clone(),
+            handler: handler.clone(),
+            auth: auth.clone(),
+            config,
+            own_addrs: HashSet::new(),
+            dead_rounds: HashMap::new(),
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(discovery.config.delay()).await;
+            loop {
+                if let Err(e) = discovery.reconcile().await {
+                    eprintln!("Peer discovery failed: {e}");
+                }
+                tokio::time::sleep(discovery.config.interval()).await;
+            }
+        });
+    }
+
+    async fn discover(&self) -> anyhow::Result<HashSet<String>> {
+        let mut addrs = if self.config.peers.is_empty() {
+            resolve_srv_peers(&self.config).await?
+        } else {
+            self.config.peers.clone()
+        };
+        let membership = self.app_state.membership.lock().await;
+        for status in [MemberStatus::Alive, MemberStatus::Suspect] {
+            addrs.extend(
+                membership
+                    .with_status(status)
+                    .iter()
+                    .map(|m| m.addr.clone()),
+            );
+        }
+        let dead: HashSet<NodeId> = membership
+            .with_status(MemberStatus::Dead)
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        drop(membership);
+        // Voters stay reachable through the address book even if they've dropped out of DNS,
+        // until SWIM declares them dead.
+        let known: Vec<String> = {
+            let raft_state = self.app_state.raft_state.lock().await;
+            raft_state
+                .cluster
+                .peers
+                .iter()
+                .filter(|id| !dead.contains(id))
+                .filter_map(|id| raft_state.cluster.addresses.resolve(id).cloned())
+                .collect()
+        };
+        Ok(addrs
+            .into_iter()
+            .chain(known)
+            .filter(|addr| !self.own_addrs.contains(addr))
+            .collect())
+    }
+
+    async fn reconcile(&mut self) -> anyhow::Result<()> {
+        let discovered = self.discover().await?;
+        self.reconcile_connections(&discovered).await;
+        self.reconcile_voters().await;
+        Ok(())
+    }
+
+    async fn reconcile_connections(&mut self, discovered: &HashSet<String>) {
+        let connections = self.handler.connections();
+        connections.prune(|addr| discovered.contains(addr));
+
+        let connected: HashSet<String> = {
+            let raft_state = self.app_state.raft_state.lock().await;
+            connections
+                .connected()
+                .iter()
+                .filter_map(|id| raft_state.cluster.addresses.resolve(id).cloned())
+                .collect()
+        };
+
+        let dial_timeout = self.config.interval();
+        let dials = discovered.difference(&connected).map(|addr| {
+            let url = peer_to_ws_addr(addr);
+            let dial = Connection::connect(addr, url, self.handler.clone(), self.auth.clone());
+            async move { (addr, timeout(dial_timeout, dial).await) }
+        });
+        for (addr, dialed) in join_all(dials).await {
+            let (id, kept) = match dialed {
+                Ok(Some(connected)) => connected,
+                Ok(None) => continue,
+                Err(_) => {
+                    eprintln!("Timed out connecting to {addr}");
+                    continue;
+                }
+            };
+            if id == self.handler.node_id() {
+                println!("{addr} is this node; skipping it from now on");
+                self.own_addrs.insert(addr.clone());
+                continue;
+            }
+            if kept {
+                println!("Connected to node {id} at {addr}");
+            }
+            self.app_state.update_address(id, addr.clone()).await;
+            self.app_state
+                .membership
+                .lock()
+                .await
+                .join(id, addr.clone());
+        }
+    }
+
+    async fn reconcile_voters(&mut self) {
+        // Only the leader can propose, and it's the one with the authoritative voter set.
+        let voters: HashSet<NodeId> = {
+            let raft_state = self.app_state.raft_state.lock().await;
+            if !raft_state.is_leader() {
+                return;
+            }
+            raft_state.cluster.peers.iter().copied().collect()
+        };
+
+        let (alive, dead): (Vec<Peer>, Vec<NodeId>) = {
+            let membership = self.app_state.membership.lock().await;
+            let alive = membership
+                .with_status(MemberStatus::Alive)
+                .into_iter()
+                .map(|m| Peer {
+                    id: m.id,
+                    addr: m.addr.clone(),
+                })
+                .collect();
+            let dead = membership
+                .with_status(MemberStatus::Dead)
+                .into_iter()
+                .map(|m| m.id)
+                .collect();
+            (alive, dead)
+        };
+
+        self.dead_rounds
+            .retain(|id, _| dead.contains(id) && voters.contains(id));
+        for id in dead.iter().filter(|id| voters.contains(id)) {
+            *self.dead_rounds.entry(*id).or_insert(0) += 1;
+        }
+
+        // Only one membership change may be in flight, so this proposes at most one and leaves
+        // the rest to later rounds. Dead voters go first, as they count against the quorum.
+        let removal = self
+            .dead_rounds
+            .iter()
+            .find(|(_, rounds)| **rounds >= self.config.stale_rounds)
+            .map(|(id, _)| Command::RemovePeer { id: *id });
+        let addition = alive
+            .into_iter()
+            .find(|peer| !voters.contains(&peer.id))
+            .map(|peer| Command::AddPeer { peer });
+        let Some(change) = removal.or(addition) else {
+            return;
+        };
+        match &change {
+            Command::RemovePeer { id } => println!("Proposing removal of node {id}"),
+            Command::AddPeer { peer } => println!("Proposing addition of node {}", peer.id),
+            _ => {}
+        }
+        if let Err(e) = self.app_state.propose_membership_change(change).await {
+            eprintln!("Couldn't propose membership change: {e}");
+        }
+    }
+}
@@ -1,35 +1,129 @@
-use tokio::sync::broadcast;
-use tokio::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc::{Receiver, Sender, channel, error::TrySendError};
+use tokio::sync::{broadcast, watch};
 use tokio::time::{Duration, timeout};
 
-use super::app_state::AppState;
-use super::websocket::shared::WSMessage;
+use super::app_state::{AppState, raft_state::Snapshot, shared::NodeId};
+use super::config::{Config, RaftConfig};
+use super::status::{Direction, LinkGuard, Links, NodeStatus};
+use super::websocket::connection::Connections;
+use super::websocket::shared::{Outbound, WSMessage};
 
+/// Routes peer messages through the Raft and SWIM state and back out.
+///
+/// Both channels are bounded. When the process queue is full, a connection's reader waits for
+/// room, which pushes back on that peer through its socket. When a connection's writer falls
+/// more than the broadcast capacity behind, the oldest messages are dropped for it; Raft and
+/// SWIM both retry, so that only costs time. Each event is counted for `/metrics`.
 #[derive(Clone)]
 pub struct Handler {
+    node_id: NodeId,
     server_tx: Sender<WSMessage>,
-    client_tx: broadcast::Sender<WSMessage>,
+    client_tx: broadcast::Sender<Outbound>,
+    process_full: Arc<AtomicU64>,
+    broadcast_dropped: Arc<AtomicU64>,
+    links: Arc<Links>,
+    connections: Arc<Connections>,
+    /// Set once the node is shutting down, telling every connection to close.
+    closing: watch::Sender<bool>,
 }
 
 impl Handler {
-    pub fn spawn(app_state: &AppState) -> Self {
-        let (heartbeat_tx, heartbeat_rx) = channel::<()>(1);
-        let (server_tx, server_rx) = channel::<WSMessage>(100);
-        let (client_tx, _) = broadcast::channel::<WSMessage>(100);
-        Self::setup_process_loop(app_state, heartbeat_tx.clone(), server_rx);
-        Self::setup_missed_heartbeat_loop(app_state, heartbeat_rx, client_tx.clone());
-        Self {
+    /// Creates the handler along with the receiving end of its process queue, which `spawn`
+    /// takes once the app state holding the handler exists.
+    pub fn new(config: &Config, node_id: NodeId) -> (Self, Receiver<WSMessage>) {
+        let (server_tx, server_rx) = channel::<WSMessage>(config.channels.process);
+        let (client_tx, _) = broadcast::channel::<Outbound>(config.channels.broadcast);
+        let handler = Self {
+            node_id,
             server_tx,
             client_tx,
-        }
+            process_full: Arc::new(AtomicU64::new(0)),
+            broadcast_dropped: Arc::new(AtomicU64::new(0)),
+            links: Arc::new(Links::default()),
+            connections: Arc::new(Connections::default()),
+            closing: watch::Sender::new(false),
+        };
+        (handler, server_rx)
     }
 
+    /// Starts processing peer messages, and the election timer and replication loops.
+    pub fn spawn(&self, app_state: &AppState, config: &Config, server_rx: Receiver<WSMessage>) {
+        let (heartbeat_tx, heartbeat_rx) = channel::<()>(1);
+        Self::setup_process_loop(
+            app_state,
+            config.raft.heartbeat_interval(),
+            heartbeat_tx.clone(),
+            self.client_tx.clone(),
+            server_rx,
+        );
+        Self::setup_missed_heartbeat_loop(
+            app_state,
+            config.raft.clone(),
+            heartbeat_rx,
+            self.client_tx.clone(),
+        );
+        Self::setup_replication_loop(app_state, config.raft.clone(), self.client_tx.clone());
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Queues a peer's message, waiting for room if the queue is full.
     pub async fn send_msg_to_process(&self, msg: WSMessage) {
+        let msg = match self.server_tx.try_send(msg) {
+            Err(TrySendError::Full(msg)) => {
+                self.process_full.fetch_add(1, Ordering::Relaxed);
+                msg
+            }
+            _ => return,
+        };
         let _ = self.server_tx.send(msg).await;
     }
 
-    pub async fn send_broadcast_msg(&self, msg: WSMessage) {
-        match self.client_tx.send(msg) {
+    /// Records messages a lagging connection never got to send.
+    pub fn note_dropped(&self, skipped: u64) {
+        self.broadcast_dropped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// Peer messages waiting to be processed.
+    pub fn process_depth(&self) -> usize {
+        self.server_tx.max_capacity() - self.server_tx.capacity()
+    }
+
+    /// Outbound messages the slowest connection has yet to send.
+    pub fn broadcast_depth(&self) -> usize {
+        self.client_tx.len()
+    }
+
+    /// Times a peer message found the process queue full.
+    pub fn process_full(&self) -> u64 {
+        self.process_full.load(Ordering::Relaxed)
+    }
+
+    pub fn broadcast_dropped(&self) -> u64 {
+        self.broadcast_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection with `peer` as open for `/status` until the guard is dropped.
+    pub fn link_opened(&self, peer: NodeId, direction: Direction) -> LinkGuard {
+        self.links.open(peer, direction)
+    }
+
+    /// The peer connections currently open, as counted for `/status`.
+    pub fn links(&self) -> &Links {
+        &self.links
+    }
+
+    /// The connection kept with each peer.
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    pub fn send_outbound(&self, outbound: Outbound) {
+        match self.client_tx.send(outbound) {
             Ok(_) => {}
             Err(e) => {
                 eprintln!("Error sending broadcast message: {e}");
@@ -37,52 +131,101 @@ impl Handler {
         };
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<WSMessage> {
+    pub fn subscribe(&self) -> broadcast::Receiver<Outbound> {
         self.client_tx.subscribe()
     }
 
+    /// Asks every peer connection, open or yet to open, to send a Close frame and stop.
+    pub fn close_connections(&self) {
+        self.closing.send_replace(true);
+    }
+
+    pub fn closing(&self) -> watch::Receiver<bool> {
+        self.closing.subscribe()
+    }
+
+    /// Processes peer messages in order, going round at least once a `period` even when none
+    /// arrive so an idle node still shows up as alive.
     fn setup_process_loop(
         app_state: &AppState,
+        period: Duration,
         heartbeat_tx: Sender<()>,
+        client_tx: broadcast::Sender<Outbound>,
         mut server_rx: Receiver<WSMessage>,
     ) {
         let app_state = app_state.clone();
+        app_state.loops.register("process", period);
         tokio::spawn(async move {
-            while let Some(msg) = server_rx.recv().await {
-                let _ = Self::process_msg(&app_state, heartbeat_tx.clone(), msg).await;
+            loop {
+                app_state.loops.beat("process");
+                match timeout(period, server_rx.recv()).await {
+                    Ok(Some(msg)) => {
+                        Self::process_msg(&app_state, heartbeat_tx.clone(), client_tx.clone(), msg)
+                            .await
+                    }
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
             }
         });
     }
 
     fn setup_missed_heartbeat_loop(
         app_state: &AppState,
+        raft_config: RaftConfig,
         mut heartbeat_rx: Receiver<()>,
-        client_tx: broadcast::Sender<WSMessage>,
+        client_tx: broadcast::Sender<Outbound>,
     ) {
         let app_state = app_state.clone();
+        let period = Duration::from_millis(raft_config.election_timeout_max_ms);
+        app_state.loops.register("election_timer", period);
         tokio::spawn(async move {
             loop {
-                let timeout_ms = rand::random_range(100..=300);
-                let timeout_duration = Duration::from_millis(timeout_ms);
+                app_state.loops.beat("election_timer");
+                let timeout_duration = raft_config.random_election_timeout();
                 let app_state = app_state.clone();
                 match timeout(timeout_duration, heartbeat_rx.recv()).await {
                     Ok(Some(_)) => continue,
                     Ok(None) => break,
                     Err(_) => {
-                        let state_machine = app_state.state_machine.lock().await;
-                        app_state
-                            .raft_state
-                            .lock()
-                            .await
-                            .handle_missed_heartbeat(client_tx.clone(), &state_machine)
-                            .await;
+                        let messages = app_state.raft_state.lock().await.handle_missed_heartbeat();
+                        Self::send_all(&client_tx, messages);
                     }
                 };
             }
         });
     }
 
-    async fn process_msg(_app_state: &AppState, heartbeat_tx: Sender<()>, msg: WSMessage) {
+    /// While leader, sends every follower its missing entries each heartbeat interval, or sooner
+    /// when a proposal is waiting.
+    fn setup_replication_loop(
+        app_state: &AppState,
+        raft_config: RaftConfig,
+        client_tx: broadcast::Sender<Outbound>,
+    ) {
+        let app_state = app_state.clone();
+        app_state
+            .loops
+            .register("replication", raft_config.heartbeat_interval());
+        tokio::spawn(async move {
+            loop {
+                app_state.loops.beat("replication");
+                tokio::select! {
+                    _ = tokio::time::sleep(raft_config.heartbeat_interval()) => {}
+                    _ = app_state.replicate_now.notified() => {}
+                }
+                let messages = app_state.raft_state.lock().await.send_messages();
+                Self::send_all(&client_tx, messages);
+            }
+        });
+    }
+
+    async fn process_msg(
+        app_state: &AppState,
+        heartbeat_tx: Sender<()>,
+        client_tx: broadcast::Sender<Outbound>,
+        msg: WSMessage,
+    ) {
         match msg {
             WSMessage::AppendEntries {
                 term,
@@ -90,17 +233,147 @@ impl Handler {
                 prev_log_index,
                 prev_log_term,
                 entries,
+                leader_commit,
             } => {
-                let _ = heartbeat_tx.send(()).await;
+                let (replies, from_leader) =
+                    app_state.raft_state.lock().await.handle_append_entries(
+                        term,
+                        leader_id,
+                        prev_log_index,
+                        prev_log_term,
+                        entries,
+                        leader_commit,
+                    );
+                if from_leader {
+                    let _ = heartbeat_tx.try_send(());
+                }
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::AppendEntriesResponse {
+                from,
+                term,
+                success,
+                match_index,
+                supported_version,
+            } => {
+                let replies = app_state
+                    .raft_state
+                    .lock()
+                    .await
+                    .handle_append_entries_response(
+                        from,
+                        term,
+                        success,
+                        match_index,
+                        supported_version,
+                    );
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::InstallSnapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                peers,
+                feature_version,
+                data,
+            } => {
+                let snapshot = Snapshot {
+                    last_index: last_included_index,
+                    last_term: last_included_term,
+                    peers,
+                    feature_version,
+                    data,
+                };
+                let (replies, from_leader) = app_state
+                    .raft_state
+                    .lock()
+                    .await
+                    .handle_install_snapshot(term, leader_id, snapshot);
+                if from_leader {
+                    let _ = heartbeat_tx.try_send(());
+                }
+                Self::send_all(&client_tx, replies);
             }
-            WSMessage::AppendEntriesResponse { term, success } => {}
             WSMessage::RequestVote {
                 term,
                 candidate_id,
                 last_log_index,
                 last_log_term,
-            } => {}
-            WSMessage::RequestVoteResponse { term, vote_granted } => {}
+                transfer,
+            } => {
+                let (replies, vote_granted) =
+                    app_state.raft_state.lock().await.handle_request_vote(
+                        term,
+                        candidate_id,
+                        last_log_index,
+                        last_log_term,
+                        transfer,
+                    );
+                if vote_granted {
+                    let _ = heartbeat_tx.try_send(());
+                }
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::RequestVoteResponse {
+                from,
+                term,
+                vote_granted,
+            } => {
+                let replies = app_state
+                    .raft_state
+                    .lock()
+                    .await
+                    .handle_request_vote_response(from, term, vote_granted);
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::TimeoutNow { term, leader_id } => {
+                let replies = app_state
+                    .raft_state
+                    .lock()
+                    .await
+                    .handle_timeout_now(term, leader_id);
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::Ping { from, seq, updates } => {
+                let replies = app_state
+                    .membership
+                    .lock()
+                    .await
+                    .handle_ping(from, seq, updates);
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::PingReq {
+                from,
+                target,
+                seq,
+                updates,
+            } => {
+                let replies = app_state
+                    .membership
+                    .lock()
+                    .await
+                    .handle_ping_req(from, target, seq, updates);
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::Ack { seq, updates, .. } => {
+                let replies = app_state.membership.lock().await.handle_ack(seq, updates);
+                Self::send_all(&client_tx, replies);
+            }
+            WSMessage::StatusRequest { from, seq } => {
+                let status = Box::new(NodeStatus::collect(app_state).await);
+                let reply = WSMessage::StatusResponse { seq, status };
+                Self::send_all(&client_tx, vec![Outbound::to(from, reply)]);
+            }
+            WSMessage::StatusResponse { seq, status } => {
+                app_state.status_requests.deliver(seq, *status);
+            }
+        }
+    }
+
+    fn send_all(client_tx: &broadcast::Sender<Outbound>, outbound: Vec<Outbound>) {
+        for out in outbound {
+            let _ = client_tx.send(out);
         }
     }
 }
@@ -0,0 +1,313 @@
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+use super::app_state::AppState;
+
+struct Beat {
+    period: Duration,
+    last: Instant,
+}
+
+/// When each background loop last went round, so `/healthz` can tell one that's stuck.
+#[derive(Default)]
+pub struct Loops {
+    beats: Mutex<BTreeMap<&'static str, Beat>>,
+}
+
+impl Loops {
+    /// Starts tracking a loop that goes round at least once every `period`.
+    pub fn register(&self, name: &'static str, period: Duration) {
+        let beat = Beat {
+            period,
+            last: Instant::now(),
+        };
+        self.beats.lock().unwrap().insert(name, beat);
+    }
+
+    pub fn beat(&self, name: &'static str) {
+        if let Some(beat) = self.beats.lock().unwrap().get_mut(name) {
+            beat.last = Instant::now();
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(ok: bool, detail: impl Into<String>) -> Self {
+        Check {
+            ok,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// A probe's verdict along with every check that went into it. Answered with 200 if all
+/// passed and 503 otherwise.
+#[derive(Serialize)]
+struct Probe {
+    ok: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl IntoResponse for Probe {
+    fn into_response(self) -> Response {
+        let status = if self.ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+fn probe(checks: BTreeMap<&'static str, Check>) -> Response {
+    Probe {
+        ok: checks.values().all(|check| check.ok),
+        checks,
+    }
+    .into_response()
+}
+
+/// Liveness: every background loop has gone round within its period plus the stall timeout.
+pub async fn healthz(state: &AppState) -> Response {
+    let config = &state.health;
+    let checks = state
+        .loops
+        .beats
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, beat)| {
+            let since = beat.last.elapsed();
+            let ok = since <= beat.period + config.stall_timeout();
+            let detail = format!("last went round {}ms ago", since.as_millis());
+            (*name, Check::new(ok, detail))
+        })
+        .collect();
+    probe(checks)
+}
+
+/// Startup: the node has recovered its log, by catching up with a leader or becoming one.
+pub async fn startupz(state: &AppState) -> Response {
+    let recovered = state.raft_state.lock().await.recovered();
+    let detail = if recovered {
+        "recovered from the cluster"
+    } else {
+        "waiting to catch up with a leader; the log is only held in memory, so it's recovered from the cluster"
+    };
+    probe(BTreeMap::from([("log", Check::new(recovered, detail))]))
+}
+
+/// Readiness: the node has started, knows a leader (unless configured not to care), has
+/// applied nearly everything the leader has committed, hasn't failed its last snapshot, and
+/// isn't shutting down.
+pub async fn readyz(state: &AppState) -> Response {
+    let config = &state.health;
+    let (recovered, leader, lag, storage) = {
+        let raft_state = state.raft_state.lock().await;
+        (
+            raft_state.recovered(),
+            raft_state.leader(),
+            raft_state.apply_lag(),
+            raft_state.snapshot_error().map(str::to_string),
+        )
+    };
+    let leader = match leader {
+        Some(leader) => Check::new(true, format!("node {leader}")),
+        None if config.ready_without_leader => Check::new(true, "none known, which is allowed"),
+        None => Check::new(false, "none known"),
+    };
+    let caught_up = Check::new(
+        lag <= config.max_ready_lag,
+        format!(
+            "{lag} committed entries not yet applied, of at most {}",
+            config.max_ready_lag
+        ),
+    );
+    let storage = match storage {
+        None => Check::new(
+            true,
+            "snapshots succeeding; the log is only held in memory, so there's nothing else to check",
+        ),
+        Some(e) => Check::new(false, e),
+    };
+    let serving = if state.is_stopping() {
+        Check::new(false, "shutting down")
+    } else {
+        Check::new(true, "accepting requests")
+    };
+    probe(BTreeMap::from([
+        (
+            "started",
+            Check::new(
+                recovered,
+                if recovered {
+                    "yes"
+                } else {
+                    "log not yet recovered"
+                },
+            ),
+        ),
+        ("leader", leader),
+        ("caught_up", caught_up),
+        ("storage", storage),
+        ("serving", serving),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::app_state::log::FEATURE_VERSION;
+    use super::super::app_state::raft_state::Snapshot;
+    use super::super::app_state::shared::{NodeId, StatusInfo};
+    use super::super::app_state::state_machine::{StateMachine, store::Store};
+    use super::super::config::Config;
+    use super::super::handler::Handler;
+    use super::super::membership::Membership;
+    use super::*;
+
+    fn state(config: &Config) -> AppState {
+        let (handler, _) = Handler::new(config, NodeId(0));
+        let status_info = StatusInfo {
+            id: NodeId(0),
+            ..StatusInfo::default()
+        };
+        let membership = Membership::new(NodeId(0), String::new(), config.swim.clone());
+        AppState::new(status_info, membership, handler, config)
+    }
+
+    /// The status and, for each check, whether it passed.
+    async fn verdict(response: Response) -> (StatusCode, BTreeMap<String, bool>) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let probe: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let checks = probe["checks"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(name, check)| (name.clone(), check["ok"].as_bool().unwrap()))
+            .collect();
+        (status, checks)
+    }
+
+    #[tokio::test]
+    async fn a_loop_that_stops_going_round_fails_liveness() {
+        let mut config = Config::default();
+        config.health.stall_timeout_ms = 50;
+        let state = state(&config);
+        state.loops.register("fast", Duration::ZERO);
+        state.loops.register("slow", Duration::from_secs(3600));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (status, checks) = verdict(healthz(&state).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!checks["fast"]);
+        assert!(checks["slow"]);
+
+        state.loops.beat("fast");
+        let (status, _) = verdict(healthz(&state).await).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_registered_loops_are_checked() {
+        let state = state(&Config::default());
+        state.loops.beat("unknown");
+        let (status, checks) = verdict(healthz(&state).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(checks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ready_once_leading_and_until_shutdown() {
+        let state = state(&Config::default());
+        let (status, checks) = verdict(readyz(&state).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!checks["started"]);
+        assert!(!checks["leader"]);
+
+        // A single-node cluster elects itself.
+        state.raft_state.lock().await.handle_missed_heartbeat();
+        let (status, checks) = verdict(readyz(&state).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(checks.values().all(|ok| *ok));
+
+        state.stop_serving();
+        let (status, checks) = verdict(readyz(&state).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!checks["serving"]);
+    }
+
+    #[tokio::test]
+    async fn a_follower_far_behind_the_commit_index_is_not_ready() {
+        let mut config = Config::default();
+        config.raft.cluster_size = 3;
+        let state = state(&config);
+        let lag = config.health.max_ready_lag + 1;
+        state
+            .raft_state
+            .lock()
+            .await
+            .handle_append_entries(1, NodeId(1), 0, 0, Vec::new(), lag);
+        let (status, checks) = verdict(readyz(&state).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(checks["leader"]);
+        assert!(!checks["caught_up"]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_snapshot_install_is_not_ready_until_one_succeeds() {
+        let mut config = Config::default();
+        config.health.ready_without_leader = true;
+        config.raft.cluster_size = 3;
+        let state = state(&config);
+        let snapshot = |data: &[u8]| Snapshot {
+            last_index: 5,
+            last_term: 1,
+            peers: Vec::new(),
+            feature_version: FEATURE_VERSION,
+            data: data.to_vec(),
+        };
+        state.raft_state.lock().await.handle_install_snapshot(
+            1,
+            NodeId(1),
+            snapshot(b"not a snapshot"),
+        );
+        let (status, checks) = verdict(readyz(&state).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!checks["storage"]);
+
+        let data = Store::new().snapshot().unwrap();
+        state
+            .raft_state
+            .lock()
+            .await
+            .handle_install_snapshot(1, NodeId(1), snapshot(&data));
+        let (_, checks) = verdict(readyz(&state).await).await;
+        assert!(checks["storage"]);
+    }
+
+    #[tokio::test]
+    async fn no_leader_is_allowed_when_configured() {
+        let mut config = Config::default();
+        config.health.ready_without_leader = true;
+        config.raft.cluster_size = 3;
+        let state = state(&config);
+        let (_, checks) = verdict(readyz(&state).await).await;
+        assert!(checks["leader"]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::app_state::{
+    AppState,
+    state_machine::{lease::LeaseCommand, store::StoreCommand},
+};
+use super::config::LeaseConfig;
+
+/// The leader's view of when each lease runs out.
+///
+/// Leases themselves are replicated, but their deadlines only live here. A node that becomes
+/// leader starts every lease's deadline afresh, so a lease is never cut short by a failover,
+/// only lengthened by up to one TTL. Once a deadline passes the leader proposes an `Expire`,
+/// and every replica deletes the lease's keys when it applies that.
+#[derive(Default)]
+pub struct LeaseKeeper {
+    deadlines: HashMap<u32, Instant>,
+    /// The term the deadlines were set in, or None when this node isn't leading.
+    term: Option<u32>,
+}
+
+impl LeaseKeeper {
+    pub fn new() -> Self {
+        LeaseKeeper::default()
+    }
+
+    /// Pushes a lease's deadline a full TTL out.
+    pub fn keepalive(&mut self, id: u32, ttl_ms: u64) {
+        self.deadlines
+            .insert(id, Instant::now() + Duration::from_millis(ttl_ms));
+    }
+
+    /// Time left on a lease, if this node is tracking it.
+    pub fn remaining(&self, id: u32) -> Option<Duration> {
+        self.deadlines
+            .get(&id)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Brings the deadlines in line with the leases that exist and returns the ones that have
+    /// run out. Expired leases get a fresh deadline, so they're only proposed again if the
+    /// `Expire` is lost.
+    fn tick(&mut self, leader_term: Option<u32>, leases: Vec<(u32, u64)>) -> Vec<u32> {
+        if leader_term != self.term {
+            self.deadlines.clear();
+            self.term = leader_term;
+        }
+        if leader_term.is_none() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut deadlines = HashMap::with_capacity(leases.len());
+        let mut expired = Vec::new();
+        for (id, ttl_ms) in leases {
+            let ttl = Duration::from_millis(ttl_ms);
+            let deadline = match self.deadlines.get(&id) {
+                Some(&deadline) if deadline <= now => {
+                    expired.push(id);
+                    now + ttl
+                }
+                Some(&deadline) => deadline,
+                None => now + ttl,
+            };
+            deadlines.insert(id, deadline);
+        }
+        self.deadlines = deadlines;
+        expired
+    }
+
+    pub fn spawn(app_state: &AppState, config: LeaseConfig) {
+        let app_state = app_state.clone();
+        app_state
+            .loops
+            .register("lease_expiry", config.check_interval());
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config.check_interval()).await;
+                app_state.loops.beat("lease_expiry");
+                let expired = {
+                    let raft_state = app_state.raft_state.lock().await;
+                    let leases = raft_state.state_machine.leases.ttls();
+                    app_state
+                        .leases
+                        .lock()
+                        .await
+                        .tick(raft_state.leader_term(), leases)
+                };
+                for id in expired {
+                    println!("Lease {id} expired");
+                    // Expiries are admitted like client proposals, so they count towards
+                    // max_in_flight_proposals. They're waited on apart from the loop, which has
+                    // to keep going round.
+                    let app_state = app_state.clone();
+                    tokio::spawn(async move {
+                        let expire = StoreCommand::Lease(LeaseCommand::Expire { id });
+                        if let Err(e) = app_state.propose(expire).await {
+                            eprintln!("Couldn't propose expiry of lease {id}: {e}");
+                        }
+                    });
+                }
+            }
+        });
+    }
+}
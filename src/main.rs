@@ -1,134 +1,392 @@
 mod app_state;
+mod config;
+mod discovery;
 mod handler;
+mod health;
+mod leases;
+mod membership;
+mod metrics;
+mod shutdown;
+mod status;
 mod websocket;
 
 use axum::{
     Router,
-    extract::{Json, Path, State, WebSocketUpgrade},
+    extract::{Json, Path, Query, State, WebSocketUpgrade},
+    http::HeaderMap,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
+};
+use tokio::sync::oneshot;
+
+use app_state::state_machine::{
+    kv::{DeleteKvQuery, PutKvRequest},
+    lease::GrantLeaseRequest,
+    session::RegisterSessionRequest,
+    txn::Txn,
+    user::{CreateUserRequest, ListUsersQuery, PatchUserRequest, UpdateUserRequest},
 };
-use hickory_resolver::TokioResolver;
-use regex::Regex;
-use serde::Serialize;
-use std::env;
-use std::net::SocketAddr;
-use tokio::time::Duration;
-
-use app_state::state_machine::user::CreateUserRequest;
 use app_state::{
-    AppState,
-    shared::{Peer, StatusInfo},
+    AppState, ReadQuery,
+    coordination::{AcquireRequest, ReleaseRequest},
+    dedup::Dedup,
+    shared::StatusInfo,
+    watch::WatchQuery,
 };
+use config::{Config, NodeConfig};
+use discovery::Discovery;
 use handler::Handler;
-use websocket::connection::Connection;
+use leases::LeaseKeeper;
+use membership::Membership;
+use websocket::{auth::PeerAuth, connection::Connection};
 
 async fn create_user(
     State(state): State<AppState>,
+    dedup: Dedup,
     Json(req): Json<CreateUserRequest>,
 ) -> impl IntoResponse {
-    state.create_user(req).await
+    state.create_user(req, dedup).await
+}
+
+async fn get_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.get_user(id, read.consistency).await
+}
+
+async fn get_user_by_email(
+    State(state): State<AppState>,
+    Path(email): Path<String>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.get_user_by_email(email, read.consistency).await
 }
 
-async fn get_user(State(state): State<AppState>, Path(id): Path<u32>) -> impl IntoResponse {
-    state.get_user(id).await
+async fn update_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    dedup: Dedup,
+    Json(req): Json<UpdateUserRequest>,
+) -> impl IntoResponse {
+    state.update_user(id, req, dedup).await
 }
 
-async fn list_users(State(state): State<AppState>) -> impl IntoResponse {
-    state.list_users().await
+async fn patch_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    dedup: Dedup,
+    Json(req): Json<PatchUserRequest>,
+) -> impl IntoResponse {
+    state.patch_user(id, req, dedup).await
 }
 
-fn peer_to_ws_addr(peer: Peer) -> String {
-    let re = Regex::new(".:").unwrap();
-    let ip = re.replace_all(&peer.ip, ":");
-    format!("ws://{}/ws", ip)
+async fn delete_user(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    dedup: Dedup,
+) -> impl IntoResponse {
+    state.delete_user(id, dedup).await
 }
 
-async fn discover_peers(app_state: AppState, handler: Handler) -> anyhow::Result<()> {
-    let service = env::var("SERVICE_NAME")?;
-    let namespace = env::var("NAMESPACE")?;
-    let port_name = env::var("SERVICE_PORT_NAME")?;
-    println!("Service {}, NS {}, Port {}", service, namespace, port_name);
+async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<ListUsersQuery>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.list_users(query, read.consistency).await
+}
 
-    let resolver = TokioResolver::builder_tokio()?.build();
+async fn get_kv(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.get_kv(key, read.consistency).await
+}
 
-    let srv_query = format!(
-        "_{}._{}.{}.{}.svc.cluster.local",
-        port_name, "tcp", service, namespace
-    );
-    println!("Using query: {}", srv_query);
-
-    let records = resolver.srv_lookup(&srv_query).await?;
-    println!("Found records: {:?}", records);
-
-    let peers: Vec<Peer> = records
-        .iter()
-        .map(|srv| Peer {
-            ip: format!("{}:{}", srv.target().to_utf8(), srv.port()),
-        })
-        .collect();
-    println!("Found peers: {:?}", peers);
-
-    let status_info = app_state
-        .state_machine
-        .lock()
-        .await
-        .status_info
-        .to_peer()
-        .clone();
-    for peer in peers {
-        if peer.ip != status_info.ip {
-            app_state.add_peer(peer.clone()).await;
-            Connection::connect(peer.ip, handler.clone()).await;
-        }
-    }
+async fn put_kv(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    dedup: Dedup,
+    Json(req): Json<PutKvRequest>,
+) -> impl IntoResponse {
+    state.put_kv(key, req, dedup).await
+}
 
-    Ok(())
+async fn delete_kv(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<DeleteKvQuery>,
+    dedup: Dedup,
+) -> impl IntoResponse {
+    state.delete_kv(key, query, dedup).await
+}
+
+async fn txn(
+    State(state): State<AppState>,
+    dedup: Dedup,
+    Json(txn): Json<Txn>,
+) -> impl IntoResponse {
+    state.txn(txn, dedup).await
+}
+
+async fn grant_lease(
+    State(state): State<AppState>,
+    dedup: Dedup,
+    Json(req): Json<GrantLeaseRequest>,
+) -> impl IntoResponse {
+    state.grant_lease(req, dedup).await
+}
+
+async fn get_lease(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.get_lease(id, read.consistency).await
 }
 
-#[derive(Serialize)]
-struct PeerResult {
-    peers: Option<Vec<Peer>>,
-    error: Option<String>,
+async fn keepalive_lease(State(state): State<AppState>, Path(id): Path<u32>) -> impl IntoResponse {
+    state.keepalive_lease(id).await
 }
 
-fn retrieve_status_info() -> anyhow::Result<StatusInfo> {
-    let name = env::var("POD_NAME")?;
-    let ip = env::var("POD_IP")?;
-    println!("Name: {}, IP: {}", name, ip);
-    Ok(StatusInfo { name, ip })
+async fn revoke_lease(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    dedup: Dedup,
+) -> impl IntoResponse {
+    state.revoke_lease(id, dedup).await
+}
+
+async fn register_session(
+    State(state): State<AppState>,
+    dedup: Dedup,
+    Json(req): Json<RegisterSessionRequest>,
+) -> impl IntoResponse {
+    state.register_session(req, dedup).await
+}
+
+async fn close_session(
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+    dedup: Dedup,
+) -> impl IntoResponse {
+    state.close_session(id, dedup).await
+}
+
+async fn lock(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    dedup: Dedup,
+    Json(req): Json<AcquireRequest>,
+) -> impl IntoResponse {
+    state.lock(name, req, dedup).await
+}
+
+async fn unlock(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(req): Query<ReleaseRequest>,
+    dedup: Dedup,
+) -> impl IntoResponse {
+    state.unlock(name, req, dedup).await
+}
+
+async fn lock_holder(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.lock_holder(name, read.consistency).await
+}
+
+async fn campaign(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    dedup: Dedup,
+    Json(req): Json<AcquireRequest>,
+) -> impl IntoResponse {
+    state.campaign(name, req, dedup).await
+}
+
+async fn resign(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    dedup: Dedup,
+    Json(req): Json<ReleaseRequest>,
+) -> impl IntoResponse {
+    state.resign(name, req, dedup).await
+}
+
+async fn election_leader(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(read): Query<ReadQuery>,
+) -> impl IntoResponse {
+    state.election_leader(name, read.consistency).await
+}
+
+async fn observe_election(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    state.observe_election(name).await
+}
+
+async fn watch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<WatchQuery>,
+) -> impl IntoResponse {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    state.watch(last_event_id.or(query.after), query).await
+}
+
+async fn list_members(State(state): State<AppState>) -> impl IntoResponse {
+    state.list_members().await
+}
+
+async fn node_status(State(state): State<AppState>) -> impl IntoResponse {
+    status::status(&state).await
+}
+
+async fn cluster_status(State(state): State<AppState>) -> impl IntoResponse {
+    status::cluster(&state).await
+}
+
+async fn render_metrics(State(state): State<AppState>, auth: PeerAuth) -> impl IntoResponse {
+    metrics::render(&state, &auth).await
+}
+
+async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    health::healthz(&state).await
+}
+
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    health::readyz(&state).await
+}
+
+async fn startupz(State(state): State<AppState>) -> impl IntoResponse {
+    health::startupz(&state).await
+}
+
+fn retrieve_status_info(node: &NodeConfig) -> anyhow::Result<StatusInfo> {
+    let id = node.resolve_id()?;
+    println!("Id: {}, Name: {}, IP: {}", id, node.name, node.ip);
+    Ok(StatusInfo {
+        id,
+        name: node.name.clone(),
+        ip: node.ip.clone(),
+    })
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let status_info = retrieve_status_info().unwrap_or_default();
-    let state = AppState::new(status_info.clone());
+    let config = Config::load()?;
+    let status_info = retrieve_status_info(&config.node)?;
+    let membership = Membership::new(
+        status_info.id,
+        config.advertised_peer_addr(),
+        config.swim.clone(),
+    );
+    let (handler, peer_messages) = Handler::new(&config, status_info.id);
+    let state = AppState::new(status_info.clone(), membership, handler.clone(), &config);
 
     println!("App state initialized");
 
-    let handler = Handler::spawn(&state);
+    handler.spawn(&state, &config, peer_messages);
+    let auth = PeerAuth::from_config(&config.auth)?;
+
+    Discovery::spawn(&state, &handler, &auth, config.discovery.clone());
+    Membership::spawn(&state, &handler, config.swim.clone());
+    LeaseKeeper::spawn(&state, config.leases.clone());
 
-    let state_c = state.clone();
-    let handler_c = handler.clone();
-    tokio::spawn(async move {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        let _ = discover_peers(state_c, handler_c).await;
-    });
+    // The probes are on both listeners: the peer one stays up while the node drains, so
+    // readiness can report that it's shutting down.
+    let probes = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/startupz", get(startupz));
 
-    let app = Router::new()
-        .without_v07_checks()
-        .route("/users", post(create_user))
-        .route("/users", get(list_users))
-        .route("/users/:id", get(get_user))
+    let client_app = Router::new()
+        .route("/users", get(list_users).post(create_user))
+        .route(
+            "/users/{id}",
+            get(get_user)
+                .put(update_user)
+                .patch(patch_user)
+                .delete(delete_user),
+        )
+        .route("/users/by-email/{email}", get(get_user_by_email))
+        .route("/kv/{*key}", get(get_kv).put(put_kv).delete(delete_kv))
+        .route("/txn", post(txn))
+        .route("/leases", post(grant_lease))
+        .route("/leases/{id}", get(get_lease).delete(revoke_lease))
+        .route("/leases/{id}/keepalive", post(keepalive_lease))
+        .route("/sessions", post(register_session))
+        .route("/sessions/{id}", delete(close_session))
+        .route("/locks/{name}", get(lock_holder).post(lock).delete(unlock))
+        .route("/elections/{name}", get(election_leader))
+        .route("/elections/{name}/campaign", post(campaign))
+        .route("/elections/{name}/resign", post(resign))
+        .route("/elections/{name}/observe", get(observe_election))
+        .route("/watch", get(watch))
+        .route("/membership", get(list_members))
+        .route("/status", get(node_status))
+        .route("/cluster", get(cluster_status))
+        .route(
+            "/metrics",
+            get({
+                let auth = auth.clone();
+                |state: State<AppState>| render_metrics(state, auth)
+            }),
+        )
+        .merge(probes.clone())
+        .with_state(state.clone());
+
+    let peer_app = Router::new()
         .route(
             "/ws",
-            get(|ws: WebSocketUpgrade| Connection::accept(ws, handler)),
+            get({
+                let handler = handler.clone();
+                |ws: WebSocketUpgrade, headers: HeaderMap| {
+                    Connection::accept(ws, headers, handler, auth)
+                }
+            }),
         )
-        .with_state(state);
+        .merge(probes)
+        .with_state(state.clone());
+
+    let client_addr = config.client_addr;
+    let peer_addr = config.peer_addr;
+    let client_listener = tokio::net::TcpListener::bind(client_addr).await?;
+    let peer_listener = tokio::net::TcpListener::bind(peer_addr).await?;
+    println!("Serving clients on {client_addr}, peers on {peer_addr}");
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8090));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await?;
+    // Peers keep being served through shutdown, as handing off leadership needs them.
+    let (stop_clients, clients_stopped) = oneshot::channel::<()>();
+    let mut client_server = tokio::spawn(
+        axum::serve(client_listener, client_app)
+            .with_graceful_shutdown(async {
+                let _ = clients_stopped.await;
+            })
+            .into_future(),
+    );
+    let mut peer_server = tokio::spawn(axum::serve(peer_listener, peer_app).into_future());
+
+    tokio::select! {
+        result = &mut client_server => return Ok(result??),
+        result = &mut peer_server => return Ok(result??),
+        result = shutdown::requested() => result?,
+    }
+    let _ = stop_clients.send(());
+    state.stop_serving();
+    shutdown::drain(&state, &handler, client_server, &config).await;
 
     Ok(())
 }
@@ -0,0 +1,601 @@
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+use super::app_state::{AppState, shared::NodeId};
+use super::config::SwimConfig;
+use super::handler::Handler;
+use super::websocket::shared::{Outbound, WSMessage};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberStatus {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// A piece of membership gossip, piggybacked on pings and acks.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemberUpdate {
+    pub id: NodeId,
+    pub addr: String,
+    pub incarnation: u64,
+    pub status: MemberStatus,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Member {
+    pub id: NodeId,
+    pub addr: String,
+    pub incarnation: u64,
+    pub status: MemberStatus,
+    #[serde(skip)]
+    since: Instant,
+}
+
+impl Member {
+    fn to_update(&self) -> MemberUpdate {
+        MemberUpdate {
+            id: self.id,
+            addr: self.addr.clone(),
+            incarnation: self.incarnation,
+            status: self.status,
+        }
+    }
+
+    /// SWIM's precedence rules: a higher incarnation wins, and at the same incarnation
+    /// Dead beats Suspect beats Alive.
+    fn is_overridden_by(&self, update: &MemberUpdate) -> bool {
+        match (self.status, update.status) {
+            (MemberStatus::Dead, MemberStatus::Dead) => false,
+            (MemberStatus::Dead, _) => update.incarnation > self.incarnation,
+            (_, MemberStatus::Dead) => update.incarnation >= self.incarnation,
+            (MemberStatus::Alive, MemberStatus::Suspect) => update.incarnation >= self.incarnation,
+            _ => update.incarnation > self.incarnation,
+        }
+    }
+}
+
+/// A ping sent on behalf of another member's indirect probe.
+struct Relay {
+    requester: NodeId,
+    seq: u64,
+    since: Instant,
+}
+
+/// SWIM-style membership and failure detection over the peer transport.
+///
+/// Each protocol period one member is pinged directly. If it doesn't ack within the ping
+/// timeout, `indirect_checks` other members are asked to ping it on our behalf; if that fails
+/// too it becomes suspect, and a suspect that doesn't refute within the suspicion timeout is
+/// declared dead. State changes are disseminated by piggybacking updates on every message.
+pub struct Membership {
+    node_id: NodeId,
+    addr: String,
+    incarnation: u64,
+    config: SwimConfig,
+    members: HashMap<NodeId, Member>,
+    gossip: Vec<(MemberUpdate, u32)>,
+    next_seq: u64,
+    outstanding: HashMap<u64, bool>,
+    relays: HashMap<u64, Relay>,
+    probe_order: Vec<NodeId>,
+}
+
+impl Membership {
+    pub fn new(node_id: NodeId, addr: String, config: SwimConfig) -> Self {
+        // Starting from the wall clock means a restarted node outranks what others remember of it.
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Membership {
+            node_id,
+            addr,
+            incarnation,
+            config,
+            members: HashMap::new(),
+            gossip: Vec::new(),
+            next_seq: 0,
+            outstanding: HashMap::new(),
+            relays: HashMap::new(),
+            probe_order: Vec::new(),
+        }
+    }
+
+    /// Every member, including this node.
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().cloned().collect();
+        members.push(Member {
+            id: self.node_id,
+            addr: self.addr.clone(),
+            incarnation: self.incarnation,
+            status: MemberStatus::Alive,
+            since: Instant::now(),
+        });
+        members.sort_by_key(|m| m.id);
+        members
+    }
+
+    pub fn with_status(&self, status: MemberStatus) -> Vec<&Member> {
+        self.members
+            .values()
+            .filter(|m| m.status == status)
+            .collect()
+    }
+
+    /// Records a member found through discovery or an inbound connection. Only a member can
+    /// raise its own incarnation, so one already known keeps its entry until it says otherwise.
+    /// One declared dead is told so again and probed once more, so that if it's still running
+    /// it refutes with a higher incarnation and comes back Alive everywhere.
+    pub fn join(&mut self, id: NodeId, addr: String) {
+        match self.members.get(&id) {
+            Some(member) if member.status == MemberStatus::Dead => {
+                if !self.gossip.iter().any(|(update, _)| update.id == id) {
+                    let update = member.to_update();
+                    self.enqueue(update);
+                }
+                if !self.probe_order.contains(&id) {
+                    self.probe_order.push(id);
+                }
+            }
+            Some(_) => {}
+            None => self.apply(MemberUpdate {
+                id,
+                addr,
+                incarnation: 0,
+                status: MemberStatus::Alive,
+            }),
+        }
+    }
+
+    fn apply(&mut self, update: MemberUpdate) {
+        if update.id == self.node_id {
+            if update.status != MemberStatus::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                println!(
+                    "Refuting {:?} with incarnation {}",
+                    update.status, self.incarnation
+                );
+                let refutation = self.own_update();
+                self.enqueue(refutation);
+            }
+            return;
+        }
+
+        let changed = match self.members.get(&update.id) {
+            Some(member) => member.is_overridden_by(&update),
+            None => true,
+        };
+        if !changed {
+            return;
+        }
+        if self.members.get(&update.id).map(|m| m.status) != Some(update.status) {
+            println!("Node {} is now {:?}", update.id, update.status);
+        }
+        self.members.insert(
+            update.id,
+            Member {
+                id: update.id,
+                addr: update.addr.clone(),
+                incarnation: update.incarnation,
+                status: update.status,
+                since: Instant::now(),
+            },
+        );
+        self.enqueue(update);
+    }
+
+    fn own_update(&self) -> MemberUpdate {
+        MemberUpdate {
+            id: self.node_id,
+            addr: self.addr.clone(),
+            incarnation: self.incarnation,
+            status: MemberStatus::Alive,
+        }
+    }
+
+    fn enqueue(&mut self, update: MemberUpdate) {
+        // Retransmit each update about log(n) times so it reaches everyone with high probability.
+        let transmissions = self.config.retransmit_mult * ((self.members.len() + 2).ilog2() + 1);
+        self.gossip.retain(|(u, _)| u.id != update.id);
+        self.gossip.push((update, transmissions));
+    }
+
+    fn take_gossip(&mut self) -> Vec<MemberUpdate> {
+        let mut updates = vec![self.own_update()];
+        for (update, remaining) in self.gossip.iter_mut().take(self.config.max_piggyback) {
+            updates.push(update.clone());
+            *remaining -= 1;
+        }
+        self.gossip.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+
+    fn receive(&mut self, updates: Vec<MemberUpdate>) {
+        for update in updates {
+            self.apply(update);
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    fn ping(&mut self, to: NodeId, seq: u64) -> Outbound {
+        let updates = self.take_gossip();
+        Outbound::to(
+            to,
+            WSMessage::Ping {
+                from: self.node_id,
+                seq,
+                updates,
+            },
+        )
+    }
+
+    fn ack(&mut self, to: NodeId, seq: u64) -> Outbound {
+        let updates = self.take_gossip();
+        Outbound::to(
+            to,
+            WSMessage::Ack {
+                from: self.node_id,
+                seq,
+                updates,
+            },
+        )
+    }
+
+    /// Picks the next member to probe, walking a shuffled round-robin order. Dead members are
+    /// left out of the order, but one that `join` put back in is probed.
+    fn next_target(&mut self) -> Option<NodeId> {
+        loop {
+            let id = match self.probe_order.pop() {
+                Some(id) => id,
+                None => {
+                    self.probe_order = self
+                        .members
+                        .values()
+                        .filter(|m| m.status != MemberStatus::Dead)
+                        .map(|m| m.id)
+                        .collect();
+                    if self.probe_order.is_empty() {
+                        return None;
+                    }
+                    self.probe_order.shuffle(&mut rand::rng());
+                    continue;
+                }
+            };
+            if self.members.contains_key(&id) {
+                return Some(id);
+            }
+        }
+    }
+
+    fn start_probe(&mut self) -> Option<(NodeId, u64, Outbound)> {
+        let target = self.next_target()?;
+        let seq = self.next_seq();
+        self.outstanding.insert(seq, false);
+        let ping = self.ping(target, seq);
+        Some((target, seq, ping))
+    }
+
+    fn indirect_probe(&mut self, target: NodeId, seq: u64) -> Vec<Outbound> {
+        let mut helpers: Vec<NodeId> = self
+            .members
+            .values()
+            .filter(|m| m.id != target && m.status == MemberStatus::Alive)
+            .map(|m| m.id)
+            .collect();
+        helpers.shuffle(&mut rand::rng());
+        helpers
+            .into_iter()
+            .take(self.config.indirect_checks)
+            .map(|helper| {
+                let updates = self.take_gossip();
+                Outbound::to(
+                    helper,
+                    WSMessage::PingReq {
+                        from: self.node_id,
+                        target,
+                        seq,
+                        updates,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn is_acked(&self, seq: u64) -> bool {
+        self.outstanding.get(&seq).copied().unwrap_or(false)
+    }
+
+    fn finish_probe(&mut self, seq: u64) {
+        self.outstanding.remove(&seq);
+        let period = self.config.protocol_period();
+        self.relays
+            .retain(|_, relay| relay.since.elapsed() < period);
+    }
+
+    fn suspect(&mut self, id: NodeId) {
+        let Some(member) = self.members.get(&id) else {
+            return;
+        };
+        if member.status != MemberStatus::Alive {
+            return;
+        }
+        let update = MemberUpdate {
+            status: MemberStatus::Suspect,
+            ..member.to_update()
+        };
+        self.apply(update);
+    }
+
+    fn expire_suspects(&mut self) {
+        let timeout = self.config.suspicion_timeout();
+        let expired: Vec<MemberUpdate> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Suspect && m.since.elapsed() >= timeout)
+            .map(|m| MemberUpdate {
+                status: MemberStatus::Dead,
+                ..m.to_update()
+            })
+            .collect();
+        for update in expired {
+            self.apply(update);
+        }
+    }
+
+    /// Forgets members that have been dead for longer than the TTL, along with any gossip about
+    /// them, so members that come and go don't pile up. One that turns up again rejoins as new.
+    fn forget_dead(&mut self) {
+        let ttl = self.config.dead_member_ttl();
+        let forgotten: Vec<NodeId> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Dead && m.since.elapsed() >= ttl)
+            .map(|m| m.id)
+            .collect();
+        for id in &forgotten {
+            println!("Forgetting dead node {id}");
+            self.members.remove(id);
+        }
+        self.gossip
+            .retain(|(update, _)| !forgotten.contains(&update.id));
+    }
+
+    pub fn handle_ping(
+        &mut self,
+        from: NodeId,
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    ) -> Vec<Outbound> {
+        self.receive(updates);
+        vec![self.ack(from, seq)]
+    }
+
+    pub fn handle_ping_req(
+        &mut self,
+        from: NodeId,
+        target: NodeId,
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    ) -> Vec<Outbound> {
+        self.receive(updates);
+        let relay_seq = self.next_seq();
+        self.relays.insert(
+            relay_seq,
+            Relay {
+                requester: from,
+                seq,
+                since: Instant::now(),
+            },
+        );
+        vec![self.ping(target, relay_seq)]
+    }
+
+    pub fn handle_ack(&mut self, seq: u64, updates: Vec<MemberUpdate>) -> Vec<Outbound> {
+        self.receive(updates);
+        if let Some(relay) = self.relays.remove(&seq) {
+            return vec![self.ack(relay.requester, relay.seq)];
+        }
+        if let Some(acked) = self.outstanding.get_mut(&seq) {
+            *acked = true;
+        }
+        Vec::new()
+    }
+
+    /// Runs the probe loop for as long as the process lives.
+    pub fn spawn(app_state: &AppState, handler: &Handler, config: SwimConfig) {
+        let app_state = app_state.clone();
+        let handler = handler.clone();
+        app_state
+            .loops
+            .register("membership", config.protocol_period());
+        tokio::spawn(async move {
+            loop {
+                app_state.loops.beat("membership");
+                let period_end = Instant::now() + config.protocol_period();
+                let probe = app_state.membership.lock().await.start_probe();
+                if let Some((target, seq, ping)) = probe {
+                    handler.send_outbound(ping);
+                    tokio::time::sleep(config.ping_timeout()).await;
+
+                    let acked = app_state.membership.lock().await.is_acked(seq);
+                    if !acked {
+                        let requests = app_state
+                            .membership
+                            .lock()
+                            .await
+                            .indirect_probe(target, seq);
+                        for request in requests {
+                            handler.send_outbound(request);
+                        }
+                        tokio::time::sleep_until(period_end).await;
+                        let mut membership = app_state.membership.lock().await;
+                        if !membership.is_acked(seq) {
+                            membership.suspect(target);
+                        }
+                    }
+                    app_state.membership.lock().await.finish_probe(seq);
+                }
+                let mut membership = app_state.membership.lock().await;
+                membership.expire_suspects();
+                membership.forget_dead();
+                drop(membership);
+                tokio::time::sleep_until(period_end).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membership(config: SwimConfig) -> Membership {
+        Membership::new(NodeId(0), "node-0:8081".to_string(), config)
+    }
+
+    fn update(id: u32, incarnation: u64, status: MemberStatus) -> MemberUpdate {
+        MemberUpdate {
+            id: NodeId(id),
+            addr: format!("node-{id}:8081"),
+            incarnation,
+            status,
+        }
+    }
+
+    fn status(membership: &Membership, id: u32) -> Option<MemberStatus> {
+        membership.members.get(&NodeId(id)).map(|m| m.status)
+    }
+
+    #[test]
+    fn higher_incarnations_win_then_dead_beats_suspect_beats_alive() {
+        let mut membership = membership(SwimConfig::default());
+        membership.apply(update(1, 5, MemberStatus::Suspect));
+        let member = &membership.members[&NodeId(1)];
+
+        assert!(member.is_overridden_by(&update(1, 6, MemberStatus::Alive)));
+        assert!(!member.is_overridden_by(&update(1, 5, MemberStatus::Alive)));
+        assert!(!member.is_overridden_by(&update(1, 5, MemberStatus::Suspect)));
+        assert!(member.is_overridden_by(&update(1, 5, MemberStatus::Dead)));
+        assert!(!member.is_overridden_by(&update(1, 4, MemberStatus::Dead)));
+
+        membership.apply(update(1, 5, MemberStatus::Alive));
+        assert_eq!(status(&membership, 1), Some(MemberStatus::Suspect));
+        membership.apply(update(1, 5, MemberStatus::Dead));
+        let member = &membership.members[&NodeId(1)];
+        assert!(!member.is_overridden_by(&update(1, 5, MemberStatus::Alive)));
+        assert!(!member.is_overridden_by(&update(1, 9, MemberStatus::Dead)));
+        assert!(member.is_overridden_by(&update(1, 6, MemberStatus::Alive)));
+    }
+
+    #[test]
+    fn refutes_suspicion_of_itself_with_a_higher_incarnation() {
+        let mut membership = membership(SwimConfig::default());
+        let incarnation = membership.incarnation;
+
+        membership.apply(update(0, incarnation - 1, MemberStatus::Suspect));
+        assert_eq!(membership.incarnation, incarnation);
+        assert!(membership.gossip.is_empty());
+
+        membership.apply(update(0, incarnation, MemberStatus::Suspect));
+        assert_eq!(membership.incarnation, incarnation + 1);
+        assert!(!membership.members.contains_key(&NodeId(0)));
+        let (refutation, _) = &membership.gossip[0];
+        assert_eq!(refutation.id, NodeId(0));
+        assert_eq!(refutation.status, MemberStatus::Alive);
+        assert_eq!(refutation.incarnation, incarnation + 1);
+    }
+
+    #[test]
+    fn an_unrefuted_suspect_is_declared_dead_then_forgotten() {
+        let mut membership = membership(SwimConfig {
+            suspicion_timeout_ms: 0,
+            ..SwimConfig::default()
+        });
+        membership.join(NodeId(1), "node-1:8081".to_string());
+        membership.expire_suspects();
+        assert_eq!(status(&membership, 1), Some(MemberStatus::Alive));
+
+        membership.suspect(NodeId(1));
+        assert_eq!(status(&membership, 1), Some(MemberStatus::Suspect));
+        membership.expire_suspects();
+        assert_eq!(status(&membership, 1), Some(MemberStatus::Dead));
+
+        // Still remembered while the TTL runs, so discovery can see it's dead.
+        membership.forget_dead();
+        assert_eq!(status(&membership, 1), Some(MemberStatus::Dead));
+
+        membership.config.dead_member_ttl_ms = 0;
+        membership.forget_dead();
+        assert_eq!(status(&membership, 1), None);
+        assert!(membership.gossip.iter().all(|(u, _)| u.id != NodeId(1)));
+    }
+
+    #[test]
+    fn relays_an_indirect_probe_and_its_ack() {
+        let mut membership = membership(SwimConfig::default());
+        let pings = membership.handle_ping_req(NodeId(1), NodeId(2), 7, Vec::new());
+        let [ping] = pings.as_slice() else {
+            panic!("expected a single ping, got {}", pings.len());
+        };
+        assert_eq!(ping.to, Some(NodeId(2)));
+        let WSMessage::Ping { seq: relay_seq, .. } = ping.msg else {
+            panic!("expected a ping");
+        };
+
+        let acks = membership.handle_ack(relay_seq, Vec::new());
+        let [ack] = acks.as_slice() else {
+            panic!("expected a single ack, got {}", acks.len());
+        };
+        assert_eq!(ack.to, Some(NodeId(1)));
+        assert!(matches!(ack.msg, WSMessage::Ack { seq: 7, .. }));
+
+        // The relay is done with, so a repeated ack goes nowhere.
+        assert!(membership.handle_ack(relay_seq, Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn marks_its_own_probe_acked() {
+        let mut membership = membership(SwimConfig::default());
+        membership.join(NodeId(1), "node-1:8081".to_string());
+        let (target, seq, _) = membership.start_probe().unwrap();
+        assert_eq!(target, NodeId(1));
+        assert!(!membership.is_acked(seq));
+        assert!(membership.handle_ack(seq, Vec::new()).is_empty());
+        assert!(membership.is_acked(seq));
+    }
+
+    #[test]
+    fn piggybacks_a_bounded_number_of_updates_a_bounded_number_of_times() {
+        let mut membership = membership(SwimConfig {
+            retransmit_mult: 1,
+            max_piggyback: 2,
+            ..SwimConfig::default()
+        });
+        for id in 1..=3 {
+            membership.apply(update(id, 0, MemberStatus::Alive));
+        }
+
+        let updates = membership.take_gossip();
+        assert_eq!(updates.len(), 3);
+        assert_eq!(updates[0].id, NodeId(0));
+
+        // Every update goes out as often as it's owed, oldest first, and is then dropped.
+        let mut sent = HashMap::new();
+        for _ in 0..100 {
+            for update in membership.take_gossip().into_iter().skip(1) {
+                *sent.entry(update.id).or_insert(0) += 1;
+            }
+        }
+        assert!(membership.gossip.is_empty());
+        assert_eq!(sent.len(), 3);
+        assert_eq!(membership.take_gossip().len(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Write;
+
+use super::app_state::{AppState, log::FEATURE_VERSION};
+use super::websocket::auth::PeerAuth;
+
+/// Renders feature versions, queue depths, admission counters and peer authentication failures
+/// in the Prometheus text format.
+pub async fn render(state: &AppState, auth: &PeerAuth) -> String {
+    let handler = &state.handler;
+    let (feature_version, uncommitted, max_uncommitted, waiting, lag) = {
+        let raft_state = state.raft_state.lock().await;
+        (
+            raft_state.feature_version(),
+            raft_state.uncommitted(),
+            raft_state.max_uncommitted(),
+            raft_state.waiting(),
+            raft_state.follower_lag(),
+        )
+    };
+    let admission = &state.admission;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP whitewater_{name} {help}");
+        let _ = writeln!(out, "# TYPE whitewater_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "whitewater_{name}{labels} {value}");
+        }
+    };
+    let one = |value: u64| [(String::new(), value)];
+
+    metric(
+        "feature_version",
+        "gauge",
+        "The cluster's feature version as this node has applied it.",
+        &one(feature_version.into()),
+    );
+    metric(
+        "supported_feature_version",
+        "gauge",
+        "The newest feature version this node's build understands.",
+        &one(FEATURE_VERSION.into()),
+    );
+    metric(
+        "uncommitted_entries",
+        "gauge",
+        "Entries appended to the log but not yet committed.",
+        &one(uncommitted.into()),
+    );
+    metric(
+        "max_uncommitted_entries",
+        "gauge",
+        "Uncommitted entries the leader holds before refusing proposals.",
+        &one(max_uncommitted.into()),
+    );
+    metric(
+        "waiting_proposals",
+        "gauge",
+        "Proposals and read barriers in the log waiting to be applied.",
+        &one(waiting as u64),
+    );
+    metric(
+        "in_flight_proposals",
+        "gauge",
+        "Client requests currently admitted and waiting on the log.",
+        &one(admission.in_flight() as u64),
+    );
+    metric(
+        "max_in_flight_proposals",
+        "gauge",
+        "Client requests admitted at once before answering 429.",
+        &one(admission.max_in_flight() as u64),
+    );
+    metric(
+        "proposals_rejected_total",
+        "counter",
+        "Client requests refused by admission control.",
+        &[
+            (
+                "{reason=\"in_flight\"}".to_string(),
+                admission.rejected_in_flight(),
+            ),
+            (
+                "{reason=\"backlogged\"}".to_string(),
+                admission.rejected_backlogged(),
+            ),
+        ],
+    );
+    metric(
+        "follower_lag_entries",
+        "gauge",
+        "Entries each follower is missing, as the leader sees it.",
+        &lag.iter()
+            .map(|(peer, lag)| (format!("{{peer=\"{peer}\"}}"), u64::from(*lag)))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "process_queue_depth",
+        "gauge",
+        "Peer messages waiting to be processed.",
+        &one(handler.process_depth() as u64),
+    );
+    metric(
+        "process_queue_full_total",
+        "counter",
+        "Times a peer message found the process queue full and waited.",
+        &one(handler.process_full()),
+    );
+    metric(
+        "broadcast_queue_depth",
+        "gauge",
+        "Outbound peer messages the slowest connection has yet to send.",
+        &one(handler.broadcast_depth() as u64),
+    );
+    metric(
+        "broadcast_dropped_total",
+        "counter",
+        "Outbound peer messages dropped for connections that fell behind.",
+        &one(handler.broadcast_dropped()),
+    );
+    metric(
+        "peer_auth_failures_total",
+        "counter",
+        "Peer connections refused for a missing or wrong cluster token.",
+        &one(auth.failed_attempts()),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+
+    use super::super::app_state::shared::{NodeId, StatusInfo};
+    use super::super::config::Config;
+    use super::super::handler::Handler;
+    use super::super::membership::Membership;
+    use super::*;
+
+    #[tokio::test]
+    async fn exports_peer_auth_failures() {
+        let config = Config::default();
+        let (handler, _) = Handler::new(&config, NodeId(0));
+        let status_info = StatusInfo {
+            id: NodeId(0),
+            ..StatusInfo::default()
+        };
+        let membership = Membership::new(NodeId(0), String::new(), config.swim.clone());
+        let state = AppState::new(status_info, membership, handler, &config);
+        let auth = PeerAuth::new(Some("secret".to_string()));
+        auth.verify(&HeaderMap::new());
+        auth.verify(&HeaderMap::new());
+
+        let out = render(&state, &auth).await;
+        assert!(out.contains("# TYPE whitewater_peer_auth_failures_total counter\n"));
+        assert!(out.contains("\nwhitewater_peer_auth_failures_total 2\n"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, timeout_at};
+
+use super::app_state::AppState;
+use super::config::Config;
+use super::handler::Handler;
+
+/// Left at the end of the grace period for Close frames to go out.
+const CLOSE_WAIT: Duration = Duration::from_millis(200);
+
+/// Resolves on SIGTERM, which is how Kubernetes stops a pod, or on Ctrl-C.
+pub async fn requested() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => println!("Got SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            println!("Got Ctrl-C");
+        }
+    }
+    Ok(())
+}
+
+/// Winds the node down once client intake has stopped: hands off leadership while the client
+/// requests already in flight finish, runs the state machine's flush hook, then closes every
+/// peer connection. Whatever is still going when the grace period runs out is abandoned. Nothing
+/// here is persisted: the cluster keeps what was committed.
+pub async fn drain(
+    state: &AppState,
+    handler: &Handler,
+    clients: JoinHandle<io::Result<()>>,
+    config: &Config,
+) {
+    let grace = config.shutdown.grace_period();
+    println!("Shutting down within {grace:?}");
+    let deadline = Instant::now() + grace.saturating_sub(CLOSE_WAIT);
+    state.raft_state.lock().await.retire();
+
+    let (handed_off, clients) = tokio::join!(
+        timeout_at(
+            deadline,
+            hand_off_leadership(state, handler, config.raft.heartbeat_interval())
+        ),
+        timeout_at(deadline, clients),
+    );
+    if handed_off.is_err() {
+        eprintln!("Couldn't hand off leadership in time");
+    }
+    if clients.is_err() {
+        eprintln!("Client requests were still open when the grace period ran out");
+    }
+
+    if let Err(e) = state.raft_state.lock().await.flush() {
+        eprintln!("Couldn't flush the state machine: {e}");
+    }
+
+    handler.close_connections();
+    tokio::time::sleep(CLOSE_WAIT).await;
+    println!("Shut down");
+}
+
+/// While this node leads, keeps trying to hand leadership to a caught-up follower.
+async fn hand_off_leadership(state: &AppState, handler: &Handler, interval: Duration) {
+    loop {
+        let Some(messages) = state.raft_state.lock().await.transfer_leadership() else {
+            return;
+        };
+        for message in messages {
+            handler.send_outbound(message);
+        }
+        state.replicate_now.notify_one();
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::super::app_state::log::FEATURE_VERSION;
+    use super::super::app_state::shared::{NodeId, StatusInfo};
+    use super::super::membership::Membership;
+    use super::super::websocket::shared::WSMessage;
+    use super::*;
+
+    fn state(config: &Config) -> (AppState, Handler) {
+        let (handler, _) = Handler::new(config, NodeId(0));
+        let status_info = StatusInfo {
+            id: NodeId(0),
+            ..StatusInfo::default()
+        };
+        let membership = Membership::new(NodeId(0), String::new(), config.swim.clone());
+        let state = AppState::new(status_info, membership, handler.clone(), config);
+        (state, handler)
+    }
+
+    fn config(cluster_size: u32, grace_period_ms: u64) -> Config {
+        let mut config = Config::default();
+        config.raft.cluster_size = cluster_size;
+        config.shutdown.grace_period_ms = grace_period_ms;
+        config
+    }
+
+    #[tokio::test]
+    async fn retires_before_draining_clients_and_closes_connections_last() {
+        let config = config(3, 5000);
+        let (state, handler) = state(&config);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let clients = {
+            let (state, seen, closing) = (state.clone(), seen.clone(), handler.closing());
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                // A retiring node doesn't stand for election.
+                let campaigned = !state
+                    .raft_state
+                    .lock()
+                    .await
+                    .handle_missed_heartbeat()
+                    .is_empty();
+                seen.lock().unwrap().push((campaigned, *closing.borrow()));
+                Ok(())
+            })
+        };
+
+        let started = Instant::now();
+        drain(&state, &handler, clients, &config).await;
+        assert_eq!(*seen.lock().unwrap(), [(false, false)]);
+        assert!(*handler.closing().borrow());
+        // Nothing to wait for once the clients are done, besides the Close frames.
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_clients_in_time_to_close_connections() {
+        let config = config(1, 400);
+        let (state, handler) = state(&config);
+        let clients = tokio::spawn(std::future::pending());
+
+        let started = Instant::now();
+        drain(&state, &handler, clients, &config).await;
+        let elapsed = started.elapsed();
+        // Clients get the grace period less CLOSE_WAIT, then connections get CLOSE_WAIT.
+        assert!(elapsed >= config.shutdown.grace_period(), "{elapsed:?}");
+        assert!(
+            elapsed < config.shutdown.grace_period() + Duration::from_millis(300),
+            "{elapsed:?}"
+        );
+        assert!(*handler.closing().borrow());
+    }
+
+    #[tokio::test]
+    async fn hands_leadership_to_a_caught_up_follower() {
+        let config = config(2, 5000);
+        let (state, handler) = state(&config);
+        {
+            let mut raft_state = state.raft_state.lock().await;
+            raft_state.handle_missed_heartbeat();
+            raft_state.handle_request_vote_response(NodeId(1), 1, true);
+            assert!(raft_state.is_leader());
+            // The follower acks everything, which also lets the feature version rise.
+            for _ in 0..3 {
+                let last_index = raft_state.log.last_index();
+                raft_state.handle_append_entries_response(
+                    NodeId(1),
+                    1,
+                    true,
+                    last_index,
+                    FEATURE_VERSION,
+                );
+            }
+            assert_eq!(raft_state.feature_version(), FEATURE_VERSION);
+        }
+
+        // Plays the follower: on TimeoutNow it wins an election, unseating this node.
+        let mut outbound = handler.subscribe();
+        let follower = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                loop {
+                    let out = outbound.recv().await.unwrap();
+                    if let WSMessage::TimeoutNow { term, .. } = out.msg {
+                        assert_eq!(out.to, Some(NodeId(1)));
+                        let mut raft_state = state.raft_state.lock().await;
+                        let (last_index, last_term) =
+                            (raft_state.log.last_index(), raft_state.log.last_term());
+                        raft_state.handle_request_vote(
+                            term + 1,
+                            NodeId(1),
+                            last_index,
+                            last_term,
+                            true,
+                        );
+                        return;
+                    }
+                }
+            })
+        };
+
+        let started = Instant::now();
+        let clients = tokio::spawn(async { Ok(()) });
+        drain(&state, &handler, clients, &config).await;
+        follower.await.unwrap();
+        assert!(!state.raft_state.lock().await.is_leader());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
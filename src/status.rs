@@ -0,0 +1,384 @@
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::time::{Duration, Instant, timeout_at};
+
+use super::app_state::{AppState, error_response, log::STATUS_VERSION, shared::NodeId};
+use super::websocket::shared::{Outbound, WSMessage};
+
+/// How long the leader waits on the other nodes' reports for `/cluster`.
+const GATHER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Which side dialed a peer connection.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Open connections with one peer, by which side dialed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct LinkCounts {
+    pub inbound: usize,
+    pub outbound: usize,
+}
+
+/// The peer connections currently open. Connections whose peer never identified itself aren't
+/// counted.
+#[derive(Default)]
+pub struct Links {
+    open: Mutex<HashMap<NodeId, LinkCounts>>,
+}
+
+impl Links {
+    /// Counts a connection with `peer` as open until the returned guard is dropped.
+    pub fn open(self: &Arc<Self>, peer: NodeId, direction: Direction) -> LinkGuard {
+        self.update(peer, direction, |count| *count += 1);
+        LinkGuard {
+            links: self.clone(),
+            peer,
+            direction,
+        }
+    }
+
+    fn update(&self, peer: NodeId, direction: Direction, change: impl FnOnce(&mut usize)) {
+        let mut open = self.open.lock().unwrap();
+        let counts = open.entry(peer).or_default();
+        change(match direction {
+            Direction::Inbound => &mut counts.inbound,
+            Direction::Outbound => &mut counts.outbound,
+        });
+        if counts.inbound == 0 && counts.outbound == 0 {
+            open.remove(&peer);
+        }
+    }
+
+    fn snapshot(&self) -> HashMap<NodeId, LinkCounts> {
+        self.open.lock().unwrap().clone()
+    }
+}
+
+pub struct LinkGuard {
+    links: Arc<Links>,
+    peer: NodeId,
+    direction: Direction,
+}
+
+impl Drop for LinkGuard {
+    fn drop(&mut self) {
+        self.links
+            .update(self.peer, self.direction, |count| *count -= 1);
+    }
+}
+
+/// `/cluster` requests waiting on other nodes' reports, by sequence number.
+#[derive(Default)]
+pub struct StatusRequests {
+    next_seq: AtomicU64,
+    pending: Mutex<HashMap<u64, UnboundedSender<NodeStatus>>>,
+}
+
+impl StatusRequests {
+    fn start(self: &Arc<Self>) -> Gathering {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = unbounded_channel();
+        self.pending.lock().unwrap().insert(seq, tx);
+        Gathering {
+            requests: self.clone(),
+            seq,
+            rx,
+        }
+    }
+
+    /// Hands a node's report to the request that asked for it, if it's still waiting.
+    pub fn deliver(&self, seq: u64, status: NodeStatus) {
+        if let Some(tx) = self.pending.lock().unwrap().get(&seq) {
+            let _ = tx.send(status);
+        }
+    }
+}
+
+/// One `/cluster` request's reports, which stop being collected once it's dropped.
+struct Gathering {
+    requests: Arc<StatusRequests>,
+    seq: u64,
+    rx: UnboundedReceiver<NodeStatus>,
+}
+
+impl Drop for Gathering {
+    fn drop(&mut self) {
+        self.requests.pending.lock().unwrap().remove(&self.seq);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogBounds {
+    pub first_index: u32,
+    pub first_term: u32,
+    pub last_index: u32,
+    pub last_term: u32,
+}
+
+/// How this node sees a peer. `next_index` and `match_index` are only known to the leader.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerStatus {
+    pub voter: bool,
+    pub next_index: Option<u32>,
+    pub match_index: Option<u32>,
+    pub connected: bool,
+    pub connections: LinkCounts,
+}
+
+/// A node's Raft state and peer connections, as served by `/status`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeStatus {
+    pub id: NodeId,
+    pub role: String,
+    pub current_term: u32,
+    pub voted_for: Option<NodeId>,
+    pub commit_index: u32,
+    pub last_applied: u32,
+    pub log: LogBounds,
+    pub leader: Option<NodeId>,
+    pub peers: BTreeMap<NodeId, PeerStatus>,
+}
+
+impl NodeStatus {
+    /// Covers the voters, any follower the leader is replicating to and any connected peer.
+    pub async fn collect(state: &AppState) -> Self {
+        let links = state.handler.links().snapshot();
+        let raft_state = state.raft_state.lock().await;
+        let progress = raft_state.progress();
+        let voters = &raft_state.cluster.peers;
+        let ids: BTreeSet<NodeId> = voters
+            .iter()
+            .chain(progress.keys())
+            .chain(links.keys())
+            .copied()
+            .collect();
+        let peers = ids
+            .into_iter()
+            .map(|id| {
+                let connections = links.get(&id).copied().unwrap_or_default();
+                let progress = progress.get(&id);
+                let peer = PeerStatus {
+                    voter: voters.contains(&id),
+                    next_index: progress.map(|(next, _)| *next),
+                    match_index: progress.map(|(_, matched)| *matched),
+                    connected: connections.inbound + connections.outbound > 0,
+                    connections,
+                };
+                (id, peer)
+            })
+            .collect();
+        let log = &raft_state.log;
+        NodeStatus {
+            id: raft_state.cluster.node_id(),
+            role: raft_state.role().to_string(),
+            current_term: raft_state.current_term(),
+            voted_for: raft_state.voted_for(),
+            commit_index: raft_state.commit_index(),
+            last_applied: raft_state.last_applied(),
+            log: LogBounds {
+                first_index: log.first_index(),
+                first_term: log.first_term(),
+                last_index: log.last_index(),
+                last_term: log.last_term(),
+            },
+            leader: raft_state.leader(),
+            peers,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ClusterStatus {
+    leader: NodeId,
+    nodes: BTreeMap<NodeId, NodeStatus>,
+    /// Peers that didn't report in time.
+    unreachable: Vec<NodeId>,
+    /// Peers that weren't asked, because the cluster's feature version predates status requests.
+    not_asked: Vec<NodeId>,
+}
+
+pub async fn status(state: &AppState) -> Json<NodeStatus> {
+    Json(NodeStatus::collect(state).await)
+}
+
+/// Asks every peer for its status over the peer connections and gathers the reports on the
+/// leader. Peers that haven't answered within `GATHER_TIMEOUT` are listed as unreachable. Until
+/// the cluster reaches `STATUS_VERSION`, only the leader's own status is given.
+pub async fn cluster(state: &AppState) -> Response {
+    let feature_version = {
+        let raft_state = state.raft_state.lock().await;
+        if let Err(e) = raft_state.ensure_leader() {
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+        }
+        raft_state.feature_version()
+    };
+    let own = NodeStatus::collect(state).await;
+    let leader = own.id;
+    let peers = own.peers.keys().copied();
+    if feature_version < STATUS_VERSION {
+        return Json(ClusterStatus {
+            leader,
+            not_asked: peers.collect(),
+            nodes: BTreeMap::from([(leader, own)]),
+            unreachable: Vec::new(),
+        })
+        .into_response();
+    }
+    let mut missing: BTreeSet<NodeId> = peers.collect();
+    let mut gathering = state.status_requests.start();
+    state
+        .handler
+        .send_outbound(Outbound::broadcast(WSMessage::StatusRequest {
+            from: leader,
+            seq: gathering.seq,
+        }));
+
+    let mut nodes = BTreeMap::from([(leader, own)]);
+    let deadline = Instant::now() + GATHER_TIMEOUT;
+    while !missing.is_empty()
+        && let Ok(Some(status)) = timeout_at(deadline, gathering.rx.recv()).await
+    {
+        missing.remove(&status.id);
+        nodes.insert(status.id, status);
+    }
+    Json(ClusterStatus {
+        leader,
+        nodes,
+        unreachable: missing.into_iter().collect(),
+        not_asked: Vec::new(),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::app_state::log::TIMEOUT_NOW_VERSION;
+    use super::super::app_state::raft_state::sim::Net;
+    use super::super::app_state::shared::StatusInfo;
+    use super::super::config::Config;
+    use super::super::handler::Handler;
+    use super::super::membership::Membership;
+    use super::*;
+
+    fn state(config: &Config) -> AppState {
+        let (handler, _) = Handler::new(config, NodeId(0));
+        let status_info = StatusInfo {
+            id: NodeId(0),
+            ..StatusInfo::default()
+        };
+        let membership = Membership::new(NodeId(0), String::new(), config.swim.clone());
+        AppState::new(status_info, membership, handler, config)
+    }
+
+    /// Node 0 of a three-node cluster at `feature_version`, elected by the other two.
+    async fn leader(feature_version: u32) -> AppState {
+        let mut config = Config::default();
+        config.raft.cluster_size = 3;
+        let state = state(&config);
+        let mut net = Net::at_version(3, config.raft.clone(), feature_version);
+        net.campaign(0);
+        assert!(net.node(0).is_leader());
+        *state.raft_state.lock().await = net.into_node(0);
+        state
+    }
+
+    /// Answers status requests as if from `peers`, each reporting a copy of the leader's status.
+    fn answer_as(state: &AppState, peers: Vec<NodeId>) {
+        let mut outbound = state.handler.subscribe();
+        let state = state.clone();
+        tokio::spawn(async move {
+            while let Ok(out) = outbound.recv().await {
+                let WSMessage::StatusRequest { seq, .. } = out.msg else {
+                    continue;
+                };
+                let own = NodeStatus::collect(&state).await;
+                for &id in &peers {
+                    let status = NodeStatus { id, ..own.clone() };
+                    state.status_requests.deliver(seq, status);
+                }
+            }
+        });
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn waits_out_the_window_for_silent_peers() {
+        let state = leader(STATUS_VERSION).await;
+        answer_as(&state, vec![NodeId(1)]);
+
+        let started = Instant::now();
+        let response = cluster(&state).await;
+        let elapsed = started.elapsed();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(elapsed >= GATHER_TIMEOUT, "{elapsed:?}");
+        assert!(
+            elapsed < GATHER_TIMEOUT + Duration::from_millis(500),
+            "{elapsed:?}"
+        );
+        let body = body(response).await;
+        assert_eq!(body["leader"], 0);
+        assert_eq!(body["nodes"]["1"]["id"], 1);
+        assert!(body["nodes"].get("2").is_none());
+        assert_eq!(body["unreachable"], serde_json::json!([2]));
+        assert_eq!(body["not_asked"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn answers_as_soon_as_every_peer_reports() {
+        let state = leader(STATUS_VERSION).await;
+        answer_as(&state, vec![NodeId(1), NodeId(2)]);
+
+        let started = Instant::now();
+        let body = body(cluster(&state).await).await;
+        assert!(started.elapsed() < GATHER_TIMEOUT);
+        let nodes: Vec<&String> = body["nodes"].as_object().unwrap().keys().collect();
+        assert_eq!(nodes, ["0", "1", "2"]);
+        assert_eq!(body["unreachable"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn asks_no_one_before_the_cluster_supports_status_requests() {
+        let state = leader(TIMEOUT_NOW_VERSION).await;
+        assert!(state.raft_state.lock().await.feature_version() < STATUS_VERSION);
+        let mut outbound = state.handler.subscribe();
+
+        let started = Instant::now();
+        let body = body(cluster(&state).await).await;
+        assert!(started.elapsed() < GATHER_TIMEOUT);
+        let nodes: Vec<&String> = body["nodes"].as_object().unwrap().keys().collect();
+        assert_eq!(nodes, ["0"]);
+        assert_eq!(body["not_asked"], serde_json::json!([1, 2]));
+        assert_eq!(body["unreachable"], serde_json::json!([]));
+        while let Ok(out) = outbound.try_recv() {
+            assert!(!matches!(out.msg, WSMessage::StatusRequest { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_on_a_node_that_doesnt_lead() {
+        let mut config = Config::default();
+        config.raft.cluster_size = 3;
+        let state = state(&config);
+        let mut outbound = state.handler.subscribe();
+        let response = cluster(&state).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(outbound.try_recv().is_err());
+    }
+}
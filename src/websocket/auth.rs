@@ -0,0 +1,153 @@
+use axum::http::{HeaderMap, HeaderValue, header::AUTHORIZATION};
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::super::config::AuthConfig;
+
+/// Pre-shared cluster token guarding the peer `/ws` endpoint.
+///
+/// The token is sent as `Authorization: Bearer <token>` on the upgrade request and checked
+/// before the socket is handed to `Connection::handle_socket`. With no token configured, every
+/// peer is accepted.
+#[derive(Clone, Default)]
+pub struct PeerAuth {
+    token: Option<Arc<String>>,
+    failed_attempts: Arc<AtomicU64>,
+}
+
+impl PeerAuth {
+    pub fn new(token: Option<String>) -> Self {
+        PeerAuth {
+            token: token.map(Arc::new),
+            failed_attempts: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Uses the configured token, falling back to reading it from the configured token file.
+    pub fn from_config(config: &AuthConfig) -> anyhow::Result<Self> {
+        let token = match (&config.cluster_token, &config.cluster_token_file) {
+            (Some(token), _) => Some(token.clone()),
+            (None, Some(path)) => Some(fs::read_to_string(path)?),
+            (None, None) => None,
+        };
+        let token = token
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty());
+        if token.is_none() {
+            eprintln!("No cluster token configured; peer endpoint is unauthenticated");
+        }
+        Ok(Self::new(token))
+    }
+
+    pub fn header_value(&self) -> Option<HeaderValue> {
+        self.token
+            .as_ref()
+            .and_then(|t| HeaderValue::from_str(&format!("Bearer {}", t)).ok())
+    }
+
+    /// Upgrades rejected for a missing or wrong token, exported by `/metrics`.
+    pub fn failed_attempts(&self) -> u64 {
+        self.failed_attempts.load(Ordering::Relaxed)
+    }
+
+    pub fn verify(&self, headers: &HeaderMap) -> bool {
+        let Some(expected) = &self.token else {
+            return true;
+        };
+        let presented = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if constant_time_eq(presented.as_bytes(), expected.as_bytes()) => true,
+            _ => {
+                let failures = self.failed_attempts.fetch_add(1, Ordering::Relaxed) + 1;
+                eprintln!(
+                    "Rejected peer connection with invalid token ({failures} failed attempts)"
+                );
+                false
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn accepts_only_the_cluster_token_and_counts_rejections() {
+        let auth = PeerAuth::new(Some("secret".to_string()));
+        assert!(!auth.verify(&HeaderMap::new()));
+        assert!(!auth.verify(&bearer("wrong")));
+        assert!(!auth.verify(&bearer("secre")));
+        assert_eq!(auth.failed_attempts(), 3);
+
+        assert!(auth.verify(&bearer("secret")));
+        assert_eq!(auth.failed_attempts(), 3);
+        // The counter is shared with every clone handed to a connection.
+        assert!(!auth.clone().verify(&bearer("wrong")));
+        assert_eq!(auth.failed_attempts(), 4);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, auth.header_value().unwrap());
+        assert!(auth.verify(&headers));
+    }
+
+    #[test]
+    fn accepts_every_peer_without_a_token() {
+        let auth = PeerAuth::new(None);
+        assert!(auth.verify(&HeaderMap::new()));
+        assert!(auth.header_value().is_none());
+        assert_eq!(auth.failed_attempts(), 0);
+    }
+
+    #[test]
+    fn reads_the_token_from_a_file_unless_one_is_given() {
+        let path = std::env::temp_dir().join(format!("whitewater-token-{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+        let from_file = PeerAuth::from_config(&AuthConfig {
+            cluster_token: None,
+            cluster_token_file: Some(path.clone()),
+        });
+        let given = PeerAuth::from_config(&AuthConfig {
+            cluster_token: Some("given".to_string()),
+            cluster_token_file: Some(path.clone()),
+        });
+        fs::write(&path, " \n").unwrap();
+        let blank = PeerAuth::from_config(&AuthConfig {
+            cluster_token: None,
+            cluster_token_file: Some(path.clone()),
+        });
+        fs::remove_file(&path).unwrap();
+
+        // Trailing newlines, as most ways of writing a secret leave, are trimmed.
+        assert!(from_file.unwrap().verify(&bearer("from-file")));
+        let given = given.unwrap();
+        assert!(given.verify(&bearer("given")));
+        assert!(!given.verify(&bearer("from-file")));
+        assert!(blank.unwrap().verify(&HeaderMap::new()));
+        let missing = AuthConfig {
+            cluster_token: None,
+            cluster_token_file: Some(path),
+        };
+        assert!(PeerAuth::from_config(&missing).is_err());
+    }
+}
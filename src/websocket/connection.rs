@@ -1,10 +1,27 @@
-use axum::extract::ws::{Message as AxumMessage, WebSocket, WebSocketUpgrade};
-use axum::response::IntoResponse;
+use axum::extract::ws::{
+    CloseFrame as AxumCloseFrame, Message as AxumMessage, WebSocket, WebSocketUpgrade, close_code,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header::AUTHORIZATION};
+use axum::response::{IntoResponse, Response};
 use futures_util::{SinkExt, StreamExt};
-use tokio_tungstenite::{connect_async, tungstenite::Message as TungsteniteMessage};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        Message as TungsteniteMessage,
+        client::IntoClientRequest,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
 
+use super::super::app_state::shared::NodeId;
 use super::super::handler::Handler;
-use super::super::websocket::shared::WSMessage;
+use super::super::status::Direction;
+use super::super::websocket::shared::{NODE_ID_HEADER, WSMessage};
+use super::auth::PeerAuth;
 
 enum WSMessageResult {
     Deserialized(WSMessage),
@@ -15,8 +32,13 @@ enum WSMessageResult {
 
 trait WSMessageExt {
     fn deserialize(&self) -> WSMessageResult;
+
+    /// A Close frame saying this node is going away.
+    fn going_away() -> Self;
 }
 
+const GOING_AWAY_REASON: &str = "node shutting down";
+
 impl WSMessageExt for AxumMessage {
     fn deserialize(&self) -> WSMessageResult {
         match self {
@@ -28,6 +50,13 @@ impl WSMessageExt for AxumMessage {
             _ => WSMessageResult::Noop,
         }
     }
+
+    fn going_away() -> Self {
+        AxumMessage::Close(Some(AxumCloseFrame {
+            code: close_code::AWAY,
+            reason: GOING_AWAY_REASON.into(),
+        }))
+    }
 }
 
 impl WSMessageExt for TungsteniteMessage {
@@ -41,28 +70,226 @@ impl WSMessageExt for TungsteniteMessage {
             _ => WSMessageResult::Noop,
         }
     }
+
+    fn going_away() -> Self {
+        TungsteniteMessage::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: GOING_AWAY_REASON.into(),
+        }))
+    }
 }
 
 pub struct Connection;
 
+/// Owns the reader and writer tasks of a peer connection.
+pub struct ConnectionHandle {
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl ConnectionHandle {
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_finished() || self.writer.is_finished()
+    }
+
+    pub fn close(&self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+
+    /// Waits for the peer to stop sending, then closes the connection.
+    async fn close_when_read(mut self) {
+        let _ = (&mut self.reader).await;
+        self.close();
+    }
+}
+
+struct PeerConnection {
+    direction: Direction,
+    /// Where it was dialed; only known for outbound connections.
+    addr: Option<String>,
+    handle: ConnectionHandle,
+}
+
+/// The one connection kept with each peer, whichever side dialed it.
+///
+/// Two nodes that discover each other both dial, and every message sent over both sockets
+/// would be processed twice. So when a second connection with a peer opens, both ends keep the
+/// one dialed by the lower id and close the other, which leaves them on the same socket.
+#[derive(Default)]
+pub struct Connections {
+    open: Mutex<HashMap<NodeId, PeerConnection>>,
+}
+
+impl Connections {
+    /// Takes charge of a new connection with `peer`, closing it or the one it duplicates.
+    /// Returns whether the new one was kept.
+    fn register(
+        &self,
+        own_id: NodeId,
+        peer: NodeId,
+        direction: Direction,
+        addr: Option<String>,
+        handle: ConnectionHandle,
+    ) -> bool {
+        let dialer = |direction| match direction {
+            Direction::Inbound => peer,
+            Direction::Outbound => own_id,
+        };
+        let preferred = own_id.min(peer);
+        let mut open = self.open.lock().unwrap();
+        if let Some(existing) = open.get(&peer)
+            && !existing.handle.is_closed()
+            && dialer(existing.direction) == preferred
+            && dialer(direction) != preferred
+        {
+            handle.close();
+            return false;
+        }
+        let conn = PeerConnection {
+            direction,
+            addr,
+            handle,
+        };
+        if let Some(old) = open.insert(peer, conn) {
+            old.handle.close();
+        }
+        true
+    }
+
+    /// Peers with a connection open.
+    pub fn connected(&self) -> HashSet<NodeId> {
+        let open = self.open.lock().unwrap();
+        open.iter()
+            .filter(|(_, conn)| !conn.handle.is_closed())
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Forgets connections that have closed, and closes outbound ones whose address `keep`
+    /// rejects. Inbound connections are left to the side that dialed them.
+    pub fn prune(&self, keep: impl Fn(&str) -> bool) {
+        self.open.lock().unwrap().retain(|id, conn| {
+            if conn.handle.is_closed() {
+                // One half may still be running; dropping its handle would only detach it.
+                conn.handle.close();
+                return false;
+            }
+            match &conn.addr {
+                Some(addr) if !keep(addr) => {
+                    println!("Closing connection to node {id} at {addr}");
+                    conn.handle.close();
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+}
+
 impl Connection {
-    pub async fn accept(ws: WebSocketUpgrade, handler: Handler) -> impl IntoResponse {
-        ws.on_upgrade(move |socket| Self::handle_axum_socket(socket, handler))
+    pub async fn accept(
+        ws: WebSocketUpgrade,
+        headers: HeaderMap,
+        handler: Handler,
+        auth: PeerAuth,
+    ) -> Response {
+        if !auth.verify(&headers) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        let peer_id = node_id_from(&headers);
+        if let Some(peer_id) = peer_id {
+            println!("Accepted connection from node {peer_id}");
+        }
+        let node_id = HeaderValue::from(handler.node_id().0);
+        let mut response = ws
+            .on_upgrade(move |socket| {
+                Self::handle_axum_socket(socket, peer_id, Direction::Inbound, handler)
+            })
+            .into_response();
+        response.headers_mut().insert(NODE_ID_HEADER, node_id);
+        response
     }
 
-    pub async fn connect(ws_url: String, handler: Handler) {
-        if let Ok((stream, _)) = connect_async(&ws_url).await {
-            let (write, read) = stream.split();
-            Self::handle_socket(write, read, handler).await;
+    /// Connects to a peer at `addr`, returning the `NodeId` it identified itself with and
+    /// whether the connection was kept, as it may duplicate one the peer dialed.
+    pub async fn connect(
+        addr: &str,
+        ws_url: String,
+        handler: Handler,
+        auth: PeerAuth,
+    ) -> Option<(NodeId, bool)> {
+        let Ok(mut request) = ws_url.as_str().into_client_request() else {
+            eprintln!("Invalid peer url: {ws_url}");
+            return None;
+        };
+        if let Some(value) = auth.header_value() {
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        request
+            .headers_mut()
+            .insert(NODE_ID_HEADER, HeaderValue::from(handler.node_id().0));
+        match connect_async(request).await {
+            Ok((stream, response)) => {
+                let Some(peer_id) = node_id_from(response.headers()) else {
+                    eprintln!("Peer at {ws_url} didn't identify itself");
+                    return None;
+                };
+                if peer_id == handler.node_id() {
+                    return Some((peer_id, false));
+                }
+                let (write, read) = stream.split();
+                let handle = Self::handle_socket(
+                    write,
+                    read,
+                    Some(peer_id),
+                    Direction::Outbound,
+                    handler.clone(),
+                );
+                let kept = handler.connections().register(
+                    handler.node_id(),
+                    peer_id,
+                    Direction::Outbound,
+                    Some(addr.to_string()),
+                    handle,
+                );
+                Some((peer_id, kept))
+            }
+            Err(e) => {
+                eprintln!("Couldn't connect to {ws_url}: {e}");
+                None
+            }
         }
     }
 
-    async fn handle_axum_socket(socket: WebSocket, handler: Handler) {
+    async fn handle_axum_socket(
+        socket: WebSocket,
+        peer_id: Option<NodeId>,
+        direction: Direction,
+        handler: Handler,
+    ) {
         let (write, read) = socket.split();
-        Self::handle_socket(write, read, handler).await;
+        let handle = Self::handle_socket(write, read, peer_id, direction, handler.clone());
+        let Some(peer_id) = peer_id else {
+            // Nothing else keeps track of a peer that didn't identify itself.
+            handle.close_when_read().await;
+            return;
+        };
+        if !handler
+            .connections()
+            .register(handler.node_id(), peer_id, direction, None, handle)
+        {
+            println!("Already connected to node {peer_id}; closing its connection");
+        }
     }
 
-    async fn handle_socket<W, R, M, E>(mut write: W, mut read: R, handler: Handler)
+    fn handle_socket<W, R, M, E>(
+        mut write: W,
+        mut read: R,
+        peer_id: Option<NodeId>,
+        direction: Direction,
+        handler: Handler,
+    ) -> ConnectionHandle
     where
         M: WSMessageExt + Unpin + Send + From<WSMessage>,
         W: SinkExt<M> + Unpin + Send + 'static,
@@ -70,8 +297,11 @@ impl Connection {
         E: std::error::Error + Send,
     {
         let handler_clone = handler.clone();
+        // The connection counts as open for as long as it's being read from.
+        let link = peer_id.map(|peer| handler.link_opened(peer, direction));
 
-        tokio::spawn(async move {
+        let reader = tokio::spawn(async move {
+            let _link = link;
             while let Some(Ok(msg)) = read.next().await {
                 match msg.deserialize() {
                     WSMessageResult::Deserialized(ws_msg) => {
@@ -88,11 +318,98 @@ impl Connection {
         });
 
         let mut from_broadcast = handler.subscribe();
+        let mut closing = handler.closing();
 
-        tokio::spawn(async move {
-            while let Ok(msg) = from_broadcast.recv().await {
-                let _ = write.send(msg.into()).await;
+        let writer = tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    received = from_broadcast.recv() => received,
+                    _ = async { closing.wait_for(|closing| *closing).await.is_ok() } => {
+                        let _ = write.send(M::going_away()).await;
+                        break;
+                    }
+                };
+                match received {
+                    Ok(out) if out.is_for(peer_id) => {
+                        if write.send(out.msg.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    // Raft and SWIM both retry, so falling behind only costs some messages.
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("Connection fell behind; skipped {skipped} messages");
+                        handler.note_dropped(skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
             }
         });
+
+        ConnectionHandle { reader, writer }
+    }
+}
+
+fn node_id_from(headers: &HeaderMap) -> Option<NodeId> {
+    headers.get(NODE_ID_HEADER)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle() -> ConnectionHandle {
+        ConnectionHandle {
+            reader: tokio::spawn(std::future::pending()),
+            writer: tokio::spawn(std::future::pending()),
+        }
+    }
+
+    fn kept(connections: &Connections, peer: NodeId) -> Option<Direction> {
+        connections
+            .open
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .map(|conn| conn.direction)
+    }
+
+    #[tokio::test]
+    async fn both_ends_keep_the_connection_dialed_by_the_lower_id() {
+        // Node 1's view: the connection it dialed is the one to keep.
+        let connections = Connections::default();
+        let (own, peer) = (NodeId(1), NodeId(2));
+        assert!(connections.register(own, peer, Direction::Inbound, None, handle()));
+        assert!(connections.register(own, peer, Direction::Outbound, None, handle()));
+        assert!(!connections.register(own, peer, Direction::Inbound, None, handle()));
+        assert!(matches!(
+            kept(&connections, peer),
+            Some(Direction::Outbound)
+        ));
+
+        // Node 2's view of the same pair: the connection node 1 dialed is inbound here.
+        let connections = Connections::default();
+        let (own, peer) = (NodeId(2), NodeId(1));
+        assert!(connections.register(own, peer, Direction::Outbound, None, handle()));
+        assert!(connections.register(own, peer, Direction::Inbound, None, handle()));
+        assert!(!connections.register(own, peer, Direction::Outbound, None, handle()));
+        assert!(matches!(kept(&connections, peer), Some(Direction::Inbound)));
+    }
+
+    #[tokio::test]
+    async fn replaces_a_preferred_connection_once_it_has_closed() {
+        let connections = Connections::default();
+        let (own, peer) = (NodeId(1), NodeId(2));
+        let reader = tokio::spawn(async {});
+        while !reader.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        let closed = ConnectionHandle {
+            reader,
+            writer: tokio::spawn(std::future::pending()),
+        };
+        assert!(connections.register(own, peer, Direction::Outbound, None, closed));
+        assert!(connections.register(own, peer, Direction::Inbound, None, handle()));
+        assert!(matches!(kept(&connections, peer), Some(Direction::Inbound)));
     }
 }
@@ -1,32 +1,117 @@
-use super::super::app_state::log::LogEntry;
-use super::super::app_state::shared::Peer;
+use super::super::app_state::log::{WireEntry, base_feature_version};
+use super::super::app_state::shared::{NodeId, Peer};
+use super::super::membership::MemberUpdate;
+use super::super::status::NodeStatus;
 use axum::extract::ws::Message as AxumMessage;
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
 
+/// Carries the sender's `NodeId` on the `/ws` upgrade request and response.
+pub const NODE_ID_HEADER: &str = "x-whitewater-node-id";
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum WSMessage {
     AppendEntries {
         term: u32,
-        leader_id: Peer,
+        leader_id: NodeId,
         prev_log_index: u32,
         prev_log_term: u32,
-        entries: Vec<LogEntry>,
+        entries: Vec<WireEntry>,
+        leader_commit: u32,
     },
+    /// `match_index` is the last entry known to match the leader on success, and a hint for
+    /// where to retry from on failure. `supported_version` is the newest feature version the
+    /// sender's build understands.
     AppendEntriesResponse {
+        from: NodeId,
         term: u32,
         success: bool,
+        match_index: u32,
+        #[serde(default = "base_feature_version")]
+        supported_version: u32,
+    },
+    /// Sent instead of `AppendEntries` when a follower needs entries that were compacted away.
+    /// Answered with an `AppendEntriesResponse`.
+    InstallSnapshot {
+        term: u32,
+        leader_id: NodeId,
+        last_included_index: u32,
+        last_included_term: u32,
+        peers: Vec<Peer>,
+        #[serde(default = "base_feature_version")]
+        feature_version: u32,
+        data: Vec<u8>,
     },
+    /// `transfer` is set when the current leader asked the candidate to stand, so voters that
+    /// still hear from that leader vote anyway.
     RequestVote {
         term: u32,
-        candidate_id: Peer,
+        candidate_id: NodeId,
         last_log_index: u32,
         last_log_term: u32,
+        #[serde(default)]
+        transfer: bool,
     },
     RequestVoteResponse {
+        from: NodeId,
         term: u32,
         vote_granted: bool,
     },
+    /// Sent by a leader handing over to a follower that has its whole log, telling it to stand
+    /// for election now.
+    TimeoutNow {
+        term: u32,
+        leader_id: NodeId,
+    },
+    Ping {
+        from: NodeId,
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    },
+    PingReq {
+        from: NodeId,
+        target: NodeId,
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    },
+    Ack {
+        from: NodeId,
+        seq: u64,
+        updates: Vec<MemberUpdate>,
+    },
+    /// Asks for the receiver's `/status`, to be gathered into the leader's `/cluster`. Only sent
+    /// from feature version `STATUS_VERSION` on.
+    StatusRequest {
+        from: NodeId,
+        seq: u64,
+    },
+    StatusResponse {
+        seq: u64,
+        status: Box<NodeStatus>,
+    },
+}
+
+/// A message on its way out, either to every peer or to a single one.
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    pub to: Option<NodeId>,
+    pub msg: WSMessage,
+}
+
+impl Outbound {
+    pub fn broadcast(msg: WSMessage) -> Self {
+        Outbound { to: None, msg }
+    }
+
+    pub fn to(id: NodeId, msg: WSMessage) -> Self {
+        Outbound { to: Some(id), msg }
+    }
+
+    /// Whether this should go out on a connection to `peer`. Connections whose peer never
+    /// identified itself only carry broadcasts.
+    pub fn is_for(&self, peer: Option<NodeId>) -> bool {
+        self.to.is_none() || self.to == peer
+    }
 }
 
 impl From<WSMessage> for AxumMessage {
@@ -0,0 +1,140 @@
+#!/bin/bash
+# Exercises locks and elections with competing clients against a local cluster.
+#
+#   ./test_locks.sh [nodes]
+#
+# Starts ./dev_cluster.sh, then checks that:
+#   - clients incrementing a shared counter under the lock never lose an update,
+#   - fencing tokens strictly increase from one holder to the next,
+#   - a lock held on an expired lease passes to a waiting client,
+#   - a campaigning candidate takes over when the leader resigns, and observers see it.
+# Needs curl and jq.
+
+set -e
+
+NODES=${1:-3}
+CLIENTS=4
+ROUNDS=5
+TMP=$(mktemp -d)
+
+./dev_cluster.sh "$NODES" > "$TMP/cluster.log" 2>&1 &
+CLUSTER=$!
+
+# The cluster script's nodes, and anything they started, so only those are stopped.
+descendants() {
+  local child
+  for child in $(pgrep -P "$1"); do
+    descendants "$child"
+    echo "$child"
+  done
+}
+
+cleanup() {
+  kill $(descendants $CLUSTER) $CLUSTER 2>/dev/null
+  rm -rf "$TMP"
+}
+trap cleanup EXIT
+
+fail() {
+  echo "FAIL: $*" >&2
+  exit 1
+}
+
+json() {
+  curl -s -X "$1" "$URL$2" -H 'content-type: application/json' ${3:+-d "$3"}
+}
+
+# The leader is whichever node will grant a lease. Elections can churn while the nodes are
+# still connecting, so it has to be the same node twice in a row.
+find_leader() {
+  for ((i = 0; i < NODES; i++)); do
+    url="http://127.0.0.1:$((8090 + 2 * i))"
+    if curl -s -X POST "$url/leases" -H 'content-type: application/json' \
+      -d '{"ttl_ms": 1000}' | jq -e .id > /dev/null 2>&1; then
+      echo "$url"
+      return
+    fi
+  done
+}
+
+URL=
+for _ in $(seq 60); do
+  sleep 1
+  previous=$URL
+  URL=$(find_leader)
+  if [ -n "$URL" ] && [ "$URL" = "$previous" ]; then
+    break
+  fi
+done
+[ -n "$URL" ] || fail "no leader elected"
+echo "Leader at $URL"
+
+grant() {
+  json POST /leases "{\"ttl_ms\": $1}" | jq -e .id || fail "couldn't grant a lease"
+}
+
+# Each client increments the counter with a plain read and put, relying on the lock alone.
+client() {
+  local lease token value
+  lease=$(grant 10000)
+  for ((r = 0; r < ROUNDS; r++)); do
+    held=$(json POST /locks/counter "{\"lease\": $lease, \"wait_ms\": 10000}")
+    token=$(echo "$held" | jq -r .token)
+    [ "$token" != null ] || fail "client $1 couldn't take the lock: $held"
+    echo "$token" >> "$TMP/tokens"
+    value=$(json GET /kv/counter | jq -r '.value // "0"')
+    json PUT /kv/counter "{\"value\": \"$((value + 1))\"}" > /dev/null
+    json DELETE "/locks/counter?token=$token" | jq -e .token > /dev/null ||
+      fail "client $1 couldn't release token $token"
+  done
+}
+
+echo "Mutual exclusion: $CLIENTS clients x $ROUNDS increments"
+pids=()
+for ((c = 0; c < CLIENTS; c++)); do
+  client "$c" &
+  pids+=($!)
+done
+for pid in "${pids[@]}"; do
+  wait "$pid" || fail "a client failed"
+done
+
+count=$(json GET /kv/counter | jq -r .value)
+[ "$count" = $((CLIENTS * ROUNDS)) ] || fail "counter is $count, expected $((CLIENTS * ROUNDS))"
+[ "$(sort -n "$TMP/tokens" | uniq | wc -l)" = $((CLIENTS * ROUNDS)) ] ||
+  fail "fencing tokens were reused"
+echo "ok: counter is $count, every token distinct"
+
+echo "Lease expiry releases the lock"
+short=$(grant 1500)
+long=$(grant 10000)
+first=$(json POST /locks/expiring "{\"lease\": $short}" | jq -r .token)
+held=$(json POST /locks/expiring "{\"lease\": $long}")
+[ "$(echo "$held" | jq -r .holder.lease)" = "$short" ] || fail "lock wasn't reported held: $held"
+second=$(json POST /locks/expiring "{\"lease\": $long, \"wait_ms\": 5000}" | jq -r .token)
+[ "$second" != null ] || fail "waiter didn't get the lock after the lease expired"
+[ "$second" -gt "$first" ] || fail "token went from $first to $second"
+echo "ok: token $first then $second"
+
+echo "Election"
+a=$(grant 10000)
+b=$(grant 10000)
+curl -sN "$URL/elections/primary/observe" > "$TMP/observed" &
+OBSERVER=$!
+sleep 0.2
+term=$(json POST /elections/primary/campaign "{\"lease\": $a, \"value\": \"a\"}" | jq -r .token)
+json POST /elections/primary/campaign "{\"lease\": $b, \"value\": \"b\", \"wait_ms\": 5000}" \
+  > "$TMP/campaign_b" &
+CAMPAIGN=$!
+sleep 0.5
+[ "$(json GET /elections/primary | jq -r .value)" = a ] || fail "a isn't leading"
+json POST /elections/primary/resign "{\"token\": $term}" > /dev/null
+wait $CAMPAIGN
+[ "$(jq -r .value "$TMP/campaign_b")" = b ] || fail "b didn't take over: $(cat "$TMP/campaign_b")"
+sleep 0.2
+kill $OBSERVER
+leaders=$(grep '^data:' "$TMP/observed" | sed 's/^data://' | jq -rc '.value // "none"' | tr '\n' ' ')
+[ "$leaders" = "none a none b " ] || fail "observed leaders were: $leaders"
+echo "ok: observed $leaders"
+
+echo "PASS"
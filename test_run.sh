@@ -1,3 +1,3 @@
 #!/bin/bash
 
-SERVICE_NAME="test" NAMESPACE="default" SERVICE_PORT_NAME="420" POD_NAME="whitewater-256" POD_IP="127.0.0.1" cargo run
+SERVICE_NAME="test" NAMESPACE="default" SERVICE_PORT_NAME="420" POD_NAME="whitewater-0" POD_IP="127.0.0.1" cargo run